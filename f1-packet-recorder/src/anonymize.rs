@@ -0,0 +1,405 @@
+use std::collections::HashMap;
+
+use anyhow::{Error, Result};
+use log::{info, warn};
+
+use f1_telemetry::packet::participants::Driver;
+use f1_telemetry::packet::{parse_packet, Packet};
+
+use crate::db::{for_each_packet, PacketSource, StoredPacket};
+use crate::edit::Output;
+use crate::quarantine::count_quarantined;
+use crate::utils::{format_session_uid, open_database_read_only, HeaderLayout};
+
+use super::{AnonymizeArgs, AnonymizedNames};
+
+/// Size of the name of participants and lobby players, null terminated
+const NAME_SIZE: usize = 48;
+
+pub(crate) fn anonymize(args: &AnonymizeArgs) -> Result<()> {
+    info!("Anonymizing {} to {}", args.file, args.output);
+
+    let conn = open_database_read_only(&args.file)?;
+    let mut output = Output::create(&args.output)?;
+    let mut anonymizer = Anonymizer::new(args.randomize_session_uid);
+
+    // Lobby packets name the players too, but only participants packets tell their drivers
+    if args.names == AnonymizedNames::Driver {
+        for_each_packet(&conn, |p| {
+            anonymizer.learn_drivers(&p.data);
+            Ok(())
+        })?;
+    }
+
+    for_each_packet(&conn, |p| {
+        let mut data = p.data;
+        anonymizer.rewrite(&mut data)?;
+
+        output.push(StoredPacket {
+            timestamp: p.timestamp,
+            // The address of the sender identifies players as well
+            source: PacketSource {
+                address: None,
+                label: p.source.label,
+            },
+            data,
+        })
+    })?;
+
+    let quarantined = count_quarantined(&conn)?;
+    if quarantined > 0 {
+        warn!(
+            "Left out {} quarantined packets, which can't be anonymized",
+            quarantined
+        );
+    }
+    info!(
+        "Renamed {} players, with {} names rewritten",
+        anonymizer.renamed_players(),
+        anonymizer.rewritten_names
+    );
+
+    output.finish()
+}
+
+/// Offsets of a participant or lobby player entry, which depend on the packet format.
+struct EntryLayout {
+    size: usize,
+    name: usize,
+    network_id: Option<usize>,
+}
+
+impl EntryLayout {
+    fn participant(packet_format: u16) -> Option<Self> {
+        match packet_format {
+            2019 | 2020 => Some(Self {
+                size: 54,
+                name: 5,
+                network_id: None,
+            }),
+            2021 | 2022 => Some(Self {
+                size: 56,
+                name: 7,
+                network_id: Some(2),
+            }),
+            2023 => Some(Self {
+                size: 58,
+                name: 7,
+                network_id: Some(2),
+            }),
+            _ => None,
+        }
+    }
+
+    fn lobby_player(packet_format: u16) -> Option<Self> {
+        match packet_format {
+            2020 => Some(Self {
+                size: 52,
+                name: 3,
+                network_id: None,
+            }),
+            2021 | 2022 => Some(Self {
+                size: 53,
+                name: 3,
+                network_id: None,
+            }),
+            2023 => Some(Self {
+                size: 54,
+                name: 4,
+                network_id: None,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Replacements of the identifying fields of a session, so that a player keeps the same name
+/// and network ID through the whole session.
+#[derive(Default)]
+struct SessionPseudonyms {
+    names: HashMap<String, String>,
+    network_ids: HashMap<u8, u8>,
+}
+
+impl SessionPseudonyms {
+    fn name(&mut self, name: &str, driver: Option<Driver>) -> String {
+        let next = self.names.len() + 1;
+
+        self.names
+            .entry(name.to_string())
+            .or_insert_with(|| match driver {
+                Some(d) if !matches!(d, Driver::Player | Driver::Unknown) => format!("{:?}", d),
+                _ => format!("Player {}", next),
+            })
+            .clone()
+    }
+
+    fn network_id(&mut self, network_id: u8) -> u8 {
+        let next = self.network_ids.len() as u8;
+
+        *self.network_ids.entry(network_id).or_insert(next)
+    }
+}
+
+/// Rewrites the names and network IDs of the human players in raw packets, and optionally their
+/// session UIDs.
+///
+/// AI drivers keep their names, which are public.
+struct Anonymizer {
+    /// Driver of each human player by name, when naming them after it, so that the packets
+    /// naming them all agree
+    drivers: HashMap<String, Driver>,
+    sessions: HashMap<u64, SessionPseudonyms>,
+    /// Random replacements of the session UIDs, when enabled
+    session_uids: Option<HashMap<u64, u64>>,
+    rewritten_names: u64,
+}
+
+impl Anonymizer {
+    fn new(randomize_session_uid: bool) -> Self {
+        Self {
+            drivers: HashMap::new(),
+            sessions: HashMap::new(),
+            session_uids: randomize_session_uid.then(HashMap::new),
+            rewritten_names: 0,
+        }
+    }
+
+    fn renamed_players(&self) -> usize {
+        self.sessions.values().map(|s| s.names.len()).sum()
+    }
+
+    /// Remember the drivers of the human players of a raw packet, before rewriting any.
+    fn learn_drivers(&mut self, data: &[u8]) {
+        let Ok(Packet::Participants(p)) = parse_packet(data.len(), data) else {
+            return;
+        };
+
+        for participant in &p.participants {
+            if participant.ai_controlled
+                || participant.name.is_empty()
+                || matches!(participant.driver, Driver::Player | Driver::Unknown)
+            {
+                continue;
+            }
+            self.drivers
+                .entry(participant.name.clone())
+                .or_insert(participant.driver);
+        }
+    }
+
+    /// Anonymize a raw packet, and check that it still parses.
+    fn rewrite(&mut self, data: &mut [u8]) -> Result<()> {
+        let (Some(header), Ok(packet)) = (HeaderLayout::of(data), parse_packet(data.len(), data))
+        else {
+            // Saved as is by the output, which skips packets that can't be parsed
+            return Ok(());
+        };
+        let session_uid = packet.header().session_uid;
+        let packet_format = packet.header().packet_format;
+        let pseudonyms = self.sessions.entry(session_uid).or_default();
+
+        let mut expected_names = Vec::new();
+        match &packet {
+            Packet::Participants(p) => {
+                let Some(layout) = EntryLayout::participant(packet_format) else {
+                    return Ok(());
+                };
+                for (idx, participant) in p.participants.iter().enumerate() {
+                    if participant.ai_controlled || participant.name.is_empty() {
+                        continue;
+                    }
+
+                    let driver = self.drivers.get(&participant.name).copied();
+                    let name = pseudonyms.name(&participant.name, driver);
+                    let entry = header.size + 1 + idx * layout.size;
+                    write_name(&mut data[entry + layout.name..], &name);
+
+                    if let (Some(offset), Some(network_id)) =
+                        (layout.network_id, participant.network_id)
+                    {
+                        data[entry + offset] = pseudonyms.network_id(network_id);
+                    }
+                    expected_names.push((idx, name));
+                }
+            }
+            Packet::LobbyInfo(p) => {
+                let Some(layout) = EntryLayout::lobby_player(packet_format) else {
+                    return Ok(());
+                };
+                for (idx, player) in p.players.iter().enumerate() {
+                    if player.ai_controlled || player.name.is_empty() {
+                        continue;
+                    }
+
+                    let driver = self.drivers.get(&player.name).copied();
+                    let name = pseudonyms.name(&player.name, driver);
+                    let entry = header.size + 1 + idx * layout.size;
+                    write_name(&mut data[entry + layout.name..], &name);
+                    expected_names.push((idx, name));
+                }
+            }
+            _ => {}
+        }
+
+        if let Some(session_uids) = &mut self.session_uids {
+            // Packets outside of sessions keep their null UID
+            if session_uid != 0 {
+                let uid = *session_uids.entry(session_uid).or_insert_with(rand::random);
+                data[header.session_uid..header.session_uid + 8]
+                    .copy_from_slice(&uid.to_le_bytes());
+            }
+        }
+
+        self.rewritten_names += expected_names.len() as u64;
+        verify(data, &expected_names)
+    }
+}
+
+/// Replace a null-terminated name, truncated to fit.
+fn write_name(entry: &mut [u8], name: &str) {
+    let field = &mut entry[..NAME_SIZE];
+    field.fill(0);
+
+    let mut len = name.len().min(NAME_SIZE - 1);
+    while !name.is_char_boundary(len) {
+        len -= 1;
+    }
+    field[..len].copy_from_slice(&name.as_bytes()[..len]);
+}
+
+/// Check that a rewritten packet parses, with the expected names.
+fn verify(data: &[u8], expected_names: &[(usize, String)]) -> Result<()> {
+    let packet = parse_packet(data.len(), data).map_err(|e| {
+        Error::msg(format!(
+            "An anonymized packet could not be parsed anymore: {}",
+            e
+        ))
+    })?;
+
+    let names: Vec<&str> = match &packet {
+        Packet::Participants(p) => p.participants.iter().map(|p| p.name.as_str()).collect(),
+        Packet::LobbyInfo(p) => p.players.iter().map(|p| p.name.as_str()).collect(),
+        _ => Vec::new(),
+    };
+    for (idx, name) in expected_names {
+        if names.get(*idx) != Some(&name.as_str()) {
+            return Err(Error::msg(format!(
+                "Anonymized name of car {} in session {} reads back as {:?}",
+                idx,
+                format_session_uid(packet.header().session_uid),
+                names.get(*idx)
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_anonymize {
+    use f1_telemetry::packet::{parse_packet, Packet};
+
+    use super::{write_name, Anonymizer, NAME_SIZE};
+
+    /// 2021 Participants packet with a human player and an AI driver
+    fn participants_2021() -> Vec<u8> {
+        let mut packet = vec![0u8; 24 + 1 + 22 * 56];
+        packet[0..2].copy_from_slice(&2021u16.to_le_bytes());
+        packet[5] = 4;
+        packet[6..14].copy_from_slice(&0xaaaau64.to_le_bytes());
+        packet[24] = 2;
+
+        let human = 25;
+        packet[human + 2] = 42;
+        write_name(&mut packet[human + 7..], "SecretGamertag");
+
+        let ai = 25 + 56;
+        packet[ai] = 1;
+        write_name(&mut packet[ai + 7..], "Lewis HAMILTON");
+
+        packet
+    }
+
+    /// 2021 LobbyInfo packet with the human player of `participants_2021`
+    fn lobby_info_2021() -> Vec<u8> {
+        let mut packet = vec![0u8; 24 + 1 + 22 * 53];
+        packet[0..2].copy_from_slice(&2021u16.to_le_bytes());
+        packet[5] = 9;
+        packet[6..14].copy_from_slice(&0xaaaau64.to_le_bytes());
+        packet[24] = 1;
+        write_name(&mut packet[25 + 3..], "SecretGamertag");
+
+        packet
+    }
+
+    fn names(packet: &[u8]) -> Vec<(String, Option<u8>)> {
+        let Ok(Packet::Participants(p)) = parse_packet(packet.len(), packet) else {
+            panic!("not a participants packet");
+        };
+        p.participants[..2]
+            .iter()
+            .map(|p| (p.name.clone(), p.network_id))
+            .collect()
+    }
+
+    #[test]
+    fn test_anonymize_participants() {
+        let mut anonymizer = Anonymizer::new(false);
+        let mut packet = participants_2021();
+        anonymizer.rewrite(&mut packet).unwrap();
+
+        assert_eq!(
+            names(&packet),
+            vec![
+                ("Player 1".to_string(), Some(0)),
+                ("Lewis HAMILTON".to_string(), Some(0)),
+            ]
+        );
+
+        // Same pseudonym for the rest of the session
+        let mut again = participants_2021();
+        anonymizer.rewrite(&mut again).unwrap();
+        assert_eq!(again, packet);
+    }
+
+    #[test]
+    fn test_lobby_and_participants_agree() {
+        let mut participants = participants_2021();
+        let mut lobby = lobby_info_2021();
+
+        let mut anonymizer = Anonymizer::new(false);
+        anonymizer.learn_drivers(&lobby);
+        anonymizer.learn_drivers(&participants);
+        // The lobby comes first, before the game tells the driver of the player
+        anonymizer.rewrite(&mut lobby).unwrap();
+        anonymizer.rewrite(&mut participants).unwrap();
+
+        let Ok(Packet::LobbyInfo(l)) = parse_packet(lobby.len(), &lobby) else {
+            panic!("not a lobby info packet");
+        };
+        assert_eq!(l.players[0].name, "CarlosSainz");
+        assert_eq!(names(&participants)[0].0, "CarlosSainz");
+        assert_eq!(anonymizer.renamed_players(), 1);
+    }
+
+    #[test]
+    fn test_randomize_session_uid() {
+        let mut anonymizer = Anonymizer::new(true);
+        let mut packet = participants_2021();
+        anonymizer.rewrite(&mut packet).unwrap();
+
+        assert_ne!(&packet[6..14], &0xaaaau64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_write_name() {
+        let mut field = [b'x'; NAME_SIZE + 1];
+        write_name(&mut field, &"é".repeat(30));
+
+        // Truncated on a character boundary, and still null terminated
+        assert_eq!(&field[..46], "é".repeat(23).as_bytes());
+        assert!(field[46..NAME_SIZE].iter().all(|b| *b == 0));
+        assert_eq!(field[NAME_SIZE], b'x');
+    }
+}
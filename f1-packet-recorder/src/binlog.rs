@@ -0,0 +1,321 @@
+use std::collections::BTreeMap;
+use std::fs::{metadata, File};
+use std::io::{BufReader, BufWriter, Read};
+use std::path::Path;
+
+use anyhow::{Error, Result};
+use log::{info, warn};
+use rusqlite::{params, Connection};
+
+use f1_telemetry::packet::parse_packet;
+
+use crate::db::{for_each_packet, init_db};
+use crate::quarantine::{insert_quarantined, QuarantinedPacket};
+use crate::sessions::Sessions;
+use crate::utils::{format_session_uid, get_database_connection, open_database_read_only};
+
+use super::ConvertArgs;
+
+pub(crate) use format::{BinlogWriter, PacketSummary};
+
+use format::{read_index, BinlogReader, IndexBlock, Record, MAGIC};
+
+mod format;
+
+/// Number of packets inserted per transaction
+const BATCH_SIZE: usize = 256;
+
+/// Whether a file is a binary log rather than a SQLite database
+pub(crate) fn is_binlog(file: &str) -> Result<bool> {
+    let mut magic = [0; MAGIC.len()];
+    let Ok(mut f) = File::open(file) else {
+        return Ok(false);
+    };
+
+    match f.read_exact(&mut magic) {
+        Ok(()) => Ok(magic == MAGIC),
+        Err(_) => Ok(false),
+    }
+}
+
+/// Load a binary log into a temporary in-memory database, to read it like any recording.
+pub(crate) fn open_in_memory(file: &str) -> Result<Connection> {
+    info!("Loading binary log {}", file);
+
+    let conn = Connection::open_in_memory()?;
+    init_db(&conn)?;
+    import(file, &conn)?;
+
+    Ok(conn)
+}
+
+/// Index of a binary log, `None` if the log was not closed properly
+pub(crate) fn index(file: &str) -> Result<Option<Vec<IndexBlock>>> {
+    read_index(&mut BufReader::new(File::open(file)?))
+}
+
+/// Call `f` with the packets of a binary log from `from` onwards, until it returns false.
+///
+/// Packets are numbered from 1 in the order of the log, like the `pkt_id`s of a converted log.
+/// Closed logs are read from the index block holding `from`.
+pub(crate) fn for_each_packet_from<F>(file: &str, from: i64, mut f: F) -> Result<()>
+where
+    F: FnMut(i64, f64, PacketSummary, Vec<u8>) -> Result<bool>,
+{
+    let mut reader = BinlogReader::new(BufReader::new(File::open(file)?))?;
+    let mut pkt_id = 0;
+
+    if from > 1 {
+        if let Some(blocks) = index(file)? {
+            let Some((offset, skipped)) = find_block(&blocks, from) else {
+                return Ok(());
+            };
+            reader.seek(offset)?;
+            pkt_id = skipped;
+        }
+    }
+
+    while let Some(record) = reader.next_record()? {
+        let Record::Packet {
+            timestamp,
+            summary,
+            data,
+        } = record
+        else {
+            continue;
+        };
+
+        pkt_id += 1;
+        if pkt_id >= from && !f(pkt_id, timestamp, summary, data)? {
+            return Ok(());
+        }
+    }
+
+    if reader.truncated {
+        warn!("{} ends with an incomplete record, which was ignored", file);
+    }
+
+    Ok(())
+}
+
+/// Offset of the index block holding packet `pkt_id`, and the number of packets before it
+fn find_block(blocks: &[IndexBlock], pkt_id: i64) -> Option<(u64, i64)> {
+    let mut skipped = 0;
+    for block in blocks {
+        if pkt_id <= skipped + block.packets as i64 {
+            return Some((block.first_offset, skipped));
+        }
+        skipped += block.packets as i64;
+    }
+
+    None
+}
+
+/// Number of datagrams that could not be parsed when they were recorded, by parse error
+pub(crate) fn count_unparsed_by_error(file: &str) -> Result<BTreeMap<String, u64>> {
+    let mut reader = BinlogReader::new(BufReader::new(File::open(file)?))?;
+    let mut errors = BTreeMap::new();
+
+    while let Some(record) = reader.next_record()? {
+        if let Record::Unparsed { data, .. } = record {
+            *errors.entry(unparsed_error(&data)).or_default() += 1;
+        }
+    }
+
+    Ok(errors)
+}
+
+/// Parse error of an unparsed record, which is not stored in the log
+fn unparsed_error(data: &[u8]) -> String {
+    match parse_packet(data.len(), data) {
+        Ok(_) => "Unparseable when recorded".to_string(),
+        Err(e) => e.to_string(),
+    }
+}
+
+/// Convert a binary log to a SQLite database, or the other way around.
+pub(crate) fn convert(args: &ConvertArgs) -> Result<()> {
+    if Path::new(&args.output).exists() {
+        return Err(Error::msg(format!("{} already exists", args.output)));
+    }
+
+    if is_binlog(&args.file)? {
+        info!("Converting binary log {} to {}", args.file, args.output);
+        let conn = get_database_connection(&args.output)?;
+        import(&args.file, &conn)
+    } else {
+        info!("Converting {} to binary log {}", args.file, args.output);
+        let conn = open_database_read_only(&args.file)?;
+        export(&conn, &args.output)
+    }
+}
+
+/// Insert the records of a binary log into a database.
+///
+/// Packets are stored with the header summary of the log, even if they can't be parsed anymore.
+fn import(file: &str, conn: &Connection) -> Result<()> {
+    let mut reader = BinlogReader::new(BufReader::new(File::open(file)?))?;
+
+    let mut sessions = Sessions::default();
+    let mut packets = Vec::with_capacity(BATCH_SIZE);
+    let mut quarantined = Vec::new();
+    let mut imported = 0;
+
+    while let Some(record) = reader.next_record()? {
+        match record {
+            Record::Packet {
+                timestamp,
+                summary,
+                data,
+            } => {
+                if let Ok(packet) = parse_packet(data.len(), &data) {
+                    sessions.update(timestamp, &packet, None);
+                }
+                packets.push((timestamp, summary, data));
+            }
+            Record::Unparsed { timestamp, data } => {
+                quarantined.push(QuarantinedPacket {
+                    timestamp,
+                    source: Default::default(),
+                    error: unparsed_error(&data),
+                    data,
+                });
+            }
+        }
+
+        if packets.len() >= BATCH_SIZE {
+            imported += packets.len();
+            insert_summarized_packets(conn, &packets)?;
+            packets.clear();
+        }
+    }
+
+    imported += packets.len();
+    insert_summarized_packets(conn, &packets)?;
+    insert_quarantined(conn, &quarantined)?;
+    sessions.save_all(conn)?;
+
+    if reader.truncated {
+        warn!("{} ends with an incomplete record, which was ignored", file);
+    }
+    info!(
+        "Loaded {} packets and {} unparseable ones",
+        imported,
+        quarantined.len()
+    );
+
+    Ok(())
+}
+
+fn insert_summarized_packets(
+    conn: &Connection,
+    packets: &[(f64, PacketSummary, Vec<u8>)],
+) -> Result<()> {
+    let mut stmt = conn.prepare_cached(
+        "
+        INSERT INTO packets(
+            timestamp, packetFormat, gameMajorVersion, gameMinorVersion, packetVersion, packetID,
+            sessionID, sessionTime, frameIdentifier, playerCarIndex, packet
+        ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);
+    ",
+    )?;
+
+    conn.execute("BEGIN;", ())?;
+    for (timestamp, summary, data) in packets {
+        stmt.execute(params![
+            timestamp,
+            summary.packet_format,
+            summary.game_major_version,
+            summary.game_minor_version,
+            summary.packet_version,
+            summary.packet_id,
+            format_session_uid(summary.session_uid),
+            summary.session_time,
+            summary.frame_identifier,
+            summary.player_car_index,
+            data,
+        ])?;
+    }
+    conn.execute("COMMIT;", ())?;
+
+    Ok(())
+}
+
+/// Write the packets of a database, then its quarantined packets, to a new binary log.
+///
+/// Packet sources are not part of the binary log format, and are lost.
+fn export(conn: &Connection, output: &str) -> Result<()> {
+    let mut writer = BinlogWriter::new(BufWriter::new(File::create(output)?))?;
+
+    let mut exported = 0;
+    for_each_packet(conn, |p| {
+        let packet = parse_packet(p.data.len(), &p.data)
+            .map_err(|e| Error::msg(format!("Unable to parse a stored packet: {}", e)))?;
+        writer.write_packet(p.timestamp, &PacketSummary::new(packet.header()), &p.data)?;
+        exported += 1;
+
+        Ok(())
+    })?;
+
+    let mut stmt = conn.prepare("SELECT timestamp, packet FROM quarantine ORDER BY timestamp;")?;
+    let mut rows = stmt.query([])?;
+    let mut quarantined = 0;
+    while let Some(row) = rows.next()? {
+        let data: Vec<u8> = row.get(1)?;
+        writer.write_unparsed(row.get(0)?, &data)?;
+        quarantined += 1;
+    }
+
+    writer.finish()?;
+
+    info!(
+        "Exported {} packets and {} unparseable ones",
+        exported, quarantined
+    );
+
+    Ok(())
+}
+
+/// Writes the packets of a recording to a binary log instead of a database
+pub(crate) type BinlogFile = BinlogWriter<BufWriter<File>>;
+
+/// Open a binary log for recording, appending to it if it exists.
+pub(crate) fn open_for_recording(file: &str) -> Result<BinlogFile> {
+    let empty = metadata(file).map_or(true, |m| m.len() == 0);
+    if !empty && !is_binlog(file)? {
+        return Err(Error::msg(format!("{} is not a binary log", file)));
+    }
+
+    let f = File::options()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(file)?;
+
+    // Records are buffered once the existing ones were scanned
+    Ok(BinlogWriter::append(f)?.map_writer(BufWriter::new))
+}
+
+#[cfg(test)]
+mod test_find_block {
+    use super::{find_block, IndexBlock};
+
+    #[test]
+    fn test_find_block() {
+        let blocks: Vec<IndexBlock> = [(10, 1024), (5000, 1024), (9000, 10)]
+            .into_iter()
+            .map(|(first_offset, packets)| {
+                let mut block = IndexBlock::default();
+                block.first_offset = first_offset;
+                block.packets = packets;
+                block
+            })
+            .collect();
+
+        assert_eq!(find_block(&blocks, 1), Some((10, 0)));
+        assert_eq!(find_block(&blocks, 1024), Some((10, 0)));
+        assert_eq!(find_block(&blocks, 1025), Some((5000, 1024)));
+        assert_eq!(find_block(&blocks, 2058), Some((9000, 2048)));
+        assert_eq!(find_block(&blocks, 2059), None);
+    }
+}
@@ -0,0 +1,666 @@
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+
+use anyhow::{Error, Result};
+use log::warn;
+
+use f1_telemetry::packet::header::PacketHeader;
+
+/// Start of every binary log
+pub(crate) const MAGIC: [u8; 8] = *b"F1BINLOG";
+const VERSION: u16 = 1;
+const FILE_HEADER_SIZE: u64 = 10;
+
+const RECORD_PACKET: u8 = 1;
+const RECORD_UNPARSED: u8 = 2;
+const RECORD_INDEX: u8 = 3;
+const RECORD_END: u8 = 4;
+
+/// Kind and length of a record
+const RECORD_HEADER_SIZE: u64 = 5;
+const SUMMARY_SIZE: usize = 23;
+const INDEX_SIZE: usize = 36;
+/// Size of the end record, the last one of a closed file
+const END_RECORD_SIZE: u64 = RECORD_HEADER_SIZE + 8;
+
+/// Number of packets between two index records
+const INDEX_INTERVAL: u32 = 1024;
+
+/// Header fields of a packet, stored next to it so that the log can be read without parsing
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct PacketSummary {
+    pub(crate) packet_format: u16,
+    pub(crate) game_major_version: u8,
+    pub(crate) game_minor_version: u8,
+    pub(crate) packet_version: u8,
+    pub(crate) packet_id: u8,
+    pub(crate) session_uid: u64,
+    /// Session time, in milliseconds
+    pub(crate) session_time: u32,
+    pub(crate) frame_identifier: u32,
+    pub(crate) player_car_index: u8,
+}
+
+impl PacketSummary {
+    pub(crate) fn new(header: &PacketHeader) -> Self {
+        Self {
+            packet_format: header.packet_format,
+            game_major_version: header.game_major_version,
+            game_minor_version: header.game_minor_version,
+            packet_version: header.packet_version,
+            packet_id: header.packet_type as u8,
+            session_uid: header.session_uid,
+            session_time: header.session_time,
+            frame_identifier: header.frame_identifier,
+            player_car_index: header.player_car_index,
+        }
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.packet_format.to_le_bytes());
+        buf.push(self.game_major_version);
+        buf.push(self.game_minor_version);
+        buf.push(self.packet_version);
+        buf.push(self.packet_id);
+        buf.extend_from_slice(&self.session_uid.to_le_bytes());
+        buf.extend_from_slice(&self.session_time.to_le_bytes());
+        buf.extend_from_slice(&self.frame_identifier.to_le_bytes());
+        buf.push(self.player_car_index);
+    }
+
+    fn decode(b: &[u8]) -> Self {
+        Self {
+            packet_format: u16::from_le_bytes([b[0], b[1]]),
+            game_major_version: b[2],
+            game_minor_version: b[3],
+            packet_version: b[4],
+            packet_id: b[5],
+            session_uid: u64::from_le_bytes(b[6..14].try_into().unwrap()),
+            session_time: u32::from_le_bytes(b[14..18].try_into().unwrap()),
+            frame_identifier: u32::from_le_bytes(b[18..22].try_into().unwrap()),
+            player_car_index: b[22],
+        }
+    }
+}
+
+/// A record of a binary log
+#[derive(Debug, PartialEq)]
+pub(crate) enum Record {
+    Packet {
+        timestamp: f64,
+        summary: PacketSummary,
+        data: Vec<u8>,
+    },
+    /// A datagram that could not be parsed when it was received
+    Unparsed { timestamp: f64, data: Vec<u8> },
+}
+
+/// Summary of a block of packets, written after them
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct IndexBlock {
+    /// Offset of the previous index record, 0 for the first one
+    previous: u64,
+    /// Offset of the first record of the block
+    pub(crate) first_offset: u64,
+    pub(crate) packets: u32,
+    pub(crate) first_timestamp: f64,
+    pub(crate) last_timestamp: f64,
+}
+
+impl IndexBlock {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.previous.to_le_bytes());
+        buf.extend_from_slice(&self.first_offset.to_le_bytes());
+        buf.extend_from_slice(&self.packets.to_le_bytes());
+        buf.extend_from_slice(&self.first_timestamp.to_le_bytes());
+        buf.extend_from_slice(&self.last_timestamp.to_le_bytes());
+    }
+
+    fn decode(b: &[u8]) -> Self {
+        Self {
+            previous: u64::from_le_bytes(b[0..8].try_into().unwrap()),
+            first_offset: u64::from_le_bytes(b[8..16].try_into().unwrap()),
+            packets: u32::from_le_bytes(b[16..20].try_into().unwrap()),
+            first_timestamp: f64::from_le_bytes(b[20..28].try_into().unwrap()),
+            last_timestamp: f64::from_le_bytes(b[28..36].try_into().unwrap()),
+        }
+    }
+}
+
+/// Appends packets to a binary log: a file header, then length-prefixed records.
+///
+/// Every [`INDEX_INTERVAL`] packets, an index record summarizes the previous block and points to
+/// the index before it. Closing the log writes an end record pointing to the last index, so that
+/// the index can be read from the end of the file.
+pub(crate) struct BinlogWriter<W: Write> {
+    writer: W,
+    /// Offset of the next record
+    offset: u64,
+    /// Offset of the last index record written
+    last_index: u64,
+    block: IndexBlock,
+}
+
+impl<W: Write> BinlogWriter<W> {
+    /// Start a new log.
+    pub(crate) fn new(mut writer: W) -> Result<Self> {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+
+        Ok(Self {
+            writer,
+            offset: FILE_HEADER_SIZE,
+            last_index: 0,
+            block: IndexBlock {
+                first_offset: FILE_HEADER_SIZE,
+                ..Default::default()
+            },
+        })
+    }
+
+    pub(crate) fn write_packet(
+        &mut self,
+        timestamp: f64,
+        summary: &PacketSummary,
+        data: &[u8],
+    ) -> Result<()> {
+        let mut body = Vec::with_capacity(8 + SUMMARY_SIZE + data.len());
+        body.extend_from_slice(&timestamp.to_le_bytes());
+        summary.encode(&mut body);
+        body.extend_from_slice(data);
+        self.write_record(RECORD_PACKET, &body)?;
+
+        if self.block.packets == 0 {
+            self.block.first_timestamp = timestamp;
+        }
+        self.block.packets += 1;
+        self.block.last_timestamp = timestamp;
+
+        if self.block.packets >= INDEX_INTERVAL {
+            self.write_index()?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn write_unparsed(&mut self, timestamp: f64, data: &[u8]) -> Result<()> {
+        let mut body = Vec::with_capacity(8 + data.len());
+        body.extend_from_slice(&timestamp.to_le_bytes());
+        body.extend_from_slice(data);
+
+        self.write_record(RECORD_UNPARSED, &body)
+    }
+
+    /// Replace the underlying writer, keeping the position in the log.
+    pub(crate) fn map_writer<V: Write>(self, f: impl FnOnce(W) -> V) -> BinlogWriter<V> {
+        BinlogWriter {
+            writer: f(self.writer),
+            offset: self.offset,
+            last_index: self.last_index,
+            block: self.block,
+        }
+    }
+
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        Ok(self.writer.flush()?)
+    }
+
+    /// Index the last packets and close the log.
+    pub(crate) fn finish(&mut self) -> Result<()> {
+        if self.block.packets > 0 {
+            self.write_index()?;
+        }
+        self.write_record(RECORD_END, &self.last_index.to_le_bytes())?;
+
+        self.flush()
+    }
+
+    fn write_index(&mut self) -> Result<()> {
+        let index_offset = self.offset;
+
+        self.block.previous = self.last_index;
+        let mut body = Vec::with_capacity(INDEX_SIZE);
+        self.block.encode(&mut body);
+        self.write_record(RECORD_INDEX, &body)?;
+
+        self.last_index = index_offset;
+        self.block = IndexBlock {
+            first_offset: self.offset,
+            ..Default::default()
+        };
+
+        Ok(())
+    }
+
+    fn write_record(&mut self, kind: u8, body: &[u8]) -> Result<()> {
+        self.writer.write_all(&[kind])?;
+        self.writer.write_all(&(body.len() as u32).to_le_bytes())?;
+        self.writer.write_all(body)?;
+        self.offset += RECORD_HEADER_SIZE + body.len() as u64;
+
+        Ok(())
+    }
+}
+
+/// Files a log can be appended to, which are cut after their last complete record
+pub(crate) trait Truncate {
+    fn truncate(&mut self, size: u64) -> Result<()>;
+}
+
+impl Truncate for File {
+    fn truncate(&mut self, size: u64) -> Result<()> {
+        Ok(self.set_len(size)?)
+    }
+}
+
+#[cfg(test)]
+impl Truncate for std::io::Cursor<Vec<u8>> {
+    fn truncate(&mut self, size: u64) -> Result<()> {
+        self.get_mut().truncate(size as usize);
+        Ok(())
+    }
+}
+
+impl<W: Read + Write + Seek + Truncate> BinlogWriter<W> {
+    /// Continue an existing log, or start a new one if the file is empty.
+    ///
+    /// The records are scanned to drop an incomplete record left by a crash, and to continue the
+    /// index chain and the block of packets of a log that was not closed.
+    pub(crate) fn append(mut file: W) -> Result<Self> {
+        let size = file.seek(SeekFrom::End(0))?;
+        if size == 0 {
+            return Self::new(file);
+        }
+
+        file.seek(SeekFrom::Start(0))?;
+        let (offset, last_index, block) = scan_records(&mut BufReader::new(&mut file), size)?;
+        if offset < size {
+            warn!(
+                "Dropping an incomplete record of {} bytes at the end of the log",
+                size - offset
+            );
+            file.truncate(offset)?;
+        }
+        file.seek(SeekFrom::Start(offset))?;
+
+        Ok(Self {
+            writer: file,
+            offset,
+            last_index,
+            block,
+        })
+    }
+}
+
+/// Offset after the last complete record, offset of the last index record, and the block of
+/// packets written since
+fn scan_records<R: Read + Seek>(
+    reader: &mut BufReader<R>,
+    size: u64,
+) -> Result<(u64, u64, IndexBlock)> {
+    check_file_header(reader)?;
+
+    let mut offset = FILE_HEADER_SIZE;
+    let mut last_index = 0;
+    let mut block = IndexBlock {
+        first_offset: offset,
+        ..Default::default()
+    };
+
+    loop {
+        let mut header = [0; RECORD_HEADER_SIZE as usize];
+        if !matches!(
+            read_exact_or_eof(reader, &mut header)?,
+            ReadResult::Complete
+        ) {
+            break;
+        }
+        let kind = header[0];
+        let length = u32::from_le_bytes(header[1..5].try_into().unwrap()) as u64;
+        let next = offset + RECORD_HEADER_SIZE + length;
+        if next > size {
+            break;
+        }
+
+        // Only the start of the body is needed
+        let mut start = [0; INDEX_SIZE];
+        let read = match kind {
+            RECORD_PACKET => 8,
+            RECORD_INDEX => INDEX_SIZE,
+            RECORD_UNPARSED | RECORD_END => 0,
+            kind => return Err(Error::msg(format!("Unknown record kind {}", kind))),
+        };
+        if (length as usize) < read {
+            return Err(Error::msg(format!("Invalid record of {} bytes", length)));
+        }
+        reader.read_exact(&mut start[..read])?;
+        reader.seek_relative((length as usize - read) as i64)?;
+
+        match kind {
+            RECORD_PACKET => {
+                let timestamp = f64::from_le_bytes(start[0..8].try_into().unwrap());
+                if block.packets == 0 {
+                    block.first_timestamp = timestamp;
+                }
+                block.packets += 1;
+                block.last_timestamp = timestamp;
+            }
+            RECORD_INDEX | RECORD_END => {
+                if kind == RECORD_INDEX {
+                    last_index = offset;
+                }
+                block = IndexBlock {
+                    first_offset: next,
+                    ..Default::default()
+                };
+            }
+            _ => {}
+        }
+
+        offset = next;
+    }
+
+    Ok((offset, last_index, block))
+}
+
+fn check_file_header<R: Read>(reader: &mut R) -> Result<()> {
+    let mut header = [0; FILE_HEADER_SIZE as usize];
+    reader.read_exact(&mut header)?;
+
+    if header[..8] != MAGIC {
+        return Err(Error::msg("Not a binary log"));
+    }
+    let version = u16::from_le_bytes([header[8], header[9]]);
+    if version != VERSION {
+        return Err(Error::msg(format!(
+            "Unsupported binary log version {}",
+            version
+        )));
+    }
+
+    Ok(())
+}
+
+/// Offset of the last index record, if the log was closed properly
+fn read_end_record<R: Read + Seek>(reader: &mut R, size: u64) -> Result<Option<u64>> {
+    if size < FILE_HEADER_SIZE + END_RECORD_SIZE {
+        return Ok(None);
+    }
+
+    let mut record = [0; END_RECORD_SIZE as usize];
+    reader.seek(SeekFrom::Start(size - END_RECORD_SIZE))?;
+    reader.read_exact(&mut record)?;
+
+    if record[0] != RECORD_END || u32::from_le_bytes(record[1..5].try_into().unwrap()) != 8 {
+        return Ok(None);
+    }
+
+    Ok(Some(u64::from_le_bytes(record[5..13].try_into().unwrap())))
+}
+
+/// Reads the records of a binary log in order.
+pub(crate) struct BinlogReader<R: Read> {
+    reader: R,
+    /// The log ends with an incomplete record, usually after a crash
+    pub(crate) truncated: bool,
+}
+
+impl<R: Read> BinlogReader<R> {
+    pub(crate) fn new(mut reader: R) -> Result<Self> {
+        check_file_header(&mut reader)?;
+
+        Ok(Self {
+            reader,
+            truncated: false,
+        })
+    }
+
+    pub(crate) fn next_record(&mut self) -> Result<Option<Record>> {
+        loop {
+            let mut header = [0; RECORD_HEADER_SIZE as usize];
+            let mut body = Vec::new();
+            match read_exact_or_eof(&mut self.reader, &mut header)? {
+                ReadResult::Complete => {
+                    let length = u32::from_le_bytes(header[1..5].try_into().unwrap());
+                    body.resize(length as usize, 0);
+                }
+                ReadResult::Eof => return Ok(None),
+                ReadResult::Truncated => {
+                    self.truncated = true;
+                    return Ok(None);
+                }
+            }
+            if !matches!(
+                read_exact_or_eof(&mut self.reader, &mut body)?,
+                ReadResult::Complete
+            ) {
+                self.truncated = true;
+                return Ok(None);
+            }
+
+            let kind = header[0];
+            let too_short = match kind {
+                RECORD_PACKET => body.len() < 8 + SUMMARY_SIZE,
+                RECORD_UNPARSED => body.len() < 8,
+                _ => false,
+            };
+            if too_short {
+                return Err(Error::msg(format!(
+                    "Invalid record of {} bytes",
+                    body.len()
+                )));
+            }
+
+            let timestamp = || f64::from_le_bytes(body[0..8].try_into().unwrap());
+            match kind {
+                RECORD_PACKET => {
+                    return Ok(Some(Record::Packet {
+                        timestamp: timestamp(),
+                        summary: PacketSummary::decode(&body[8..8 + SUMMARY_SIZE]),
+                        data: body[8 + SUMMARY_SIZE..].to_vec(),
+                    }))
+                }
+                RECORD_UNPARSED => {
+                    return Ok(Some(Record::Unparsed {
+                        timestamp: timestamp(),
+                        data: body[8..].to_vec(),
+                    }))
+                }
+                // Only needed when reading from the end
+                RECORD_INDEX | RECORD_END => continue,
+                kind => return Err(Error::msg(format!("Unknown record kind {}", kind))),
+            }
+        }
+    }
+}
+
+impl<R: Read + Seek> BinlogReader<R> {
+    /// Continue reading from the record at `offset`, like the first one of an index block.
+    pub(crate) fn seek(&mut self, offset: u64) -> Result<()> {
+        self.reader.seek(SeekFrom::Start(offset))?;
+        self.truncated = false;
+
+        Ok(())
+    }
+}
+
+/// Index blocks of a closed log, in order. `None` if the log was not closed properly.
+pub(crate) fn read_index<R: Read + Seek>(reader: &mut R) -> Result<Option<Vec<IndexBlock>>> {
+    let size = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+    check_file_header(reader)?;
+
+    let Some(mut offset) = read_end_record(reader, size)? else {
+        return Ok(None);
+    };
+
+    let mut blocks = Vec::new();
+    while offset != 0 {
+        let mut record = [0; RECORD_HEADER_SIZE as usize + INDEX_SIZE];
+        reader.seek(SeekFrom::Start(offset))?;
+        reader.read_exact(&mut record)?;
+
+        if record[0] != RECORD_INDEX {
+            return Err(Error::msg(format!("No index record at offset {}", offset)));
+        }
+
+        let block = IndexBlock::decode(&record[RECORD_HEADER_SIZE as usize..]);
+        offset = block.previous;
+        blocks.push(block);
+    }
+    blocks.reverse();
+
+    Ok(Some(blocks))
+}
+
+enum ReadResult {
+    Complete,
+    Eof,
+    Truncated,
+}
+
+fn read_exact_or_eof<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<ReadResult> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) if read == 0 => return Ok(ReadResult::Eof),
+            Ok(0) => return Ok(ReadResult::Truncated),
+            Ok(n) => read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(ReadResult::Complete)
+}
+
+#[cfg(test)]
+mod test_binlog {
+    use std::io::Cursor;
+
+    use super::{read_index, BinlogReader, BinlogWriter, PacketSummary, Record, INDEX_INTERVAL};
+
+    fn summary(i: u32) -> PacketSummary {
+        PacketSummary {
+            packet_format: 2021,
+            packet_id: (i % 12) as u8,
+            session_uid: 0xaaaa,
+            session_time: i * 16,
+            frame_identifier: i,
+            ..Default::default()
+        }
+    }
+
+    fn write_log(packets: u32) -> Vec<u8> {
+        let mut writer = BinlogWriter::new(Vec::new()).unwrap();
+        for i in 0..packets {
+            writer
+                .write_packet(i as f64, &summary(i), &[i as u8; 40])
+                .unwrap();
+        }
+        writer.write_unparsed(1e6, &[0xff; 10]).unwrap();
+        writer.finish().unwrap();
+        writer.writer
+    }
+
+    #[test]
+    fn test_write_and_read_back() {
+        let log = write_log(INDEX_INTERVAL + 10);
+        let mut reader = BinlogReader::new(Cursor::new(&log)).unwrap();
+
+        for i in 0..INDEX_INTERVAL + 10 {
+            assert_eq!(
+                reader.next_record().unwrap(),
+                Some(Record::Packet {
+                    timestamp: i as f64,
+                    summary: summary(i),
+                    data: vec![i as u8; 40],
+                })
+            );
+        }
+        assert_eq!(
+            reader.next_record().unwrap(),
+            Some(Record::Unparsed {
+                timestamp: 1e6,
+                data: vec![0xff; 10],
+            })
+        );
+        assert_eq!(reader.next_record().unwrap(), None);
+        assert!(!reader.truncated);
+
+        let index = read_index(&mut Cursor::new(&log)).unwrap().unwrap();
+        assert_eq!(index.len(), 2);
+        assert_eq!(index[0].packets, INDEX_INTERVAL);
+        assert_eq!(index[1].packets, 10);
+        assert_eq!(index[1].first_timestamp, INDEX_INTERVAL as f64);
+    }
+
+    #[test]
+    fn test_truncated_log() {
+        let log = write_log(3);
+        // Cut in the middle of the third packet
+        let truncated = &log[..10 + 2 * 76 + 20];
+
+        let mut reader = BinlogReader::new(Cursor::new(truncated)).unwrap();
+        assert!(reader.next_record().unwrap().is_some());
+        assert!(reader.next_record().unwrap().is_some());
+        assert_eq!(reader.next_record().unwrap(), None);
+        assert!(reader.truncated);
+
+        assert_eq!(read_index(&mut Cursor::new(truncated)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_append_to_unclosed_log() {
+        let mut writer = BinlogWriter::new(Vec::new()).unwrap();
+        for i in 0..INDEX_INTERVAL + 10 {
+            writer
+                .write_packet(i as f64, &summary(i), &[i as u8; 40])
+                .unwrap();
+        }
+        // Crash in the middle of a record
+        let mut log = writer.writer;
+        let complete = log.len();
+        log.extend_from_slice(&[1, 76, 0, 0, 0, 0, 0]);
+
+        let mut writer = BinlogWriter::append(Cursor::new(log)).unwrap();
+        assert_eq!(writer.offset, complete as u64);
+        writer.write_packet(1e5, &summary(0), &[1; 40]).unwrap();
+        writer.finish().unwrap();
+        let log = writer.writer.into_inner();
+
+        let mut reader = BinlogReader::new(Cursor::new(&log)).unwrap();
+        let mut records = 0;
+        while reader.next_record().unwrap().is_some() {
+            records += 1;
+        }
+        assert_eq!(records, INDEX_INTERVAL + 11);
+        assert!(!reader.truncated);
+
+        // The packets written before the crash are indexed along with the new ones
+        let index = read_index(&mut Cursor::new(&log)).unwrap().unwrap();
+        let packets: Vec<u32> = index.iter().map(|b| b.packets).collect();
+        assert_eq!(packets, vec![INDEX_INTERVAL, 11]);
+        assert_eq!(index[1].first_timestamp, INDEX_INTERVAL as f64);
+        assert_eq!(index[1].last_timestamp, 1e5);
+    }
+
+    #[test]
+    fn test_append() {
+        let mut writer = BinlogWriter::append(Cursor::new(write_log(5))).unwrap();
+        writer.write_packet(10.0, &summary(10), &[1; 40]).unwrap();
+        writer.finish().unwrap();
+        let log = writer.writer.into_inner();
+
+        let mut reader = BinlogReader::new(Cursor::new(&log)).unwrap();
+        let mut records = 0;
+        while reader.next_record().unwrap().is_some() {
+            records += 1;
+        }
+        assert_eq!(records, 7);
+
+        let index = read_index(&mut Cursor::new(&log)).unwrap().unwrap();
+        let packets: Vec<u32> = index.iter().map(|b| b.packets).collect();
+        assert_eq!(packets, vec![5, 1]);
+    }
+}
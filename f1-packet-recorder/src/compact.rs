@@ -0,0 +1,125 @@
+use std::fs::metadata;
+
+use anyhow::Result;
+use log::info;
+use rusqlite::{params, Connection};
+
+use crate::compression::{
+    CompressionStats, PacketCompressor, PacketDecompressor, TRAINING_SAMPLES,
+};
+use crate::utils::get_database_connection;
+
+use super::CompactArgs;
+
+/// Number of packets rewritten per query
+const CHUNK_SIZE: i64 = 4096;
+
+pub(crate) fn compact(args: &CompactArgs) -> Result<()> {
+    info!(
+        "Compacting {} ({})",
+        args.file,
+        if args.decompress {
+            String::from("decompress")
+        } else {
+            format!("zstd level {}", args.level)
+        }
+    );
+
+    let size_before = metadata(&args.file)?.len();
+    let conn = get_database_connection(&args.file)?;
+
+    let mut decompressor = PacketDecompressor::new(&conn)?;
+    let mut compressor = if args.decompress {
+        None
+    } else {
+        let mut compressor = PacketCompressor::new(args.level)?;
+        compressor.train(&training_samples(&conn, &mut decompressor)?)?;
+        Some(compressor)
+    };
+
+    let mut stats = CompressionStats::default();
+
+    conn.execute("BEGIN;", ())?;
+
+    if let Some(compressor) = &compressor {
+        compressor.save_dictionary(&conn)?;
+    }
+
+    let mut last_pkt_id = -1;
+    loop {
+        let mut packets = Vec::new();
+        {
+            let mut stmt = conn.prepare_cached(
+                "SELECT pkt_id, packet FROM packets WHERE pkt_id > ? ORDER BY pkt_id LIMIT ?;",
+            )?;
+            let mut rows = stmt.query(params![last_pkt_id, CHUNK_SIZE])?;
+            while let Some(row) = rows.next()? {
+                packets.push((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?));
+            }
+        }
+
+        let Some((pkt_id, _)) = packets.last() else {
+            break;
+        };
+        last_pkt_id = *pkt_id;
+
+        let mut stmt = conn.prepare_cached("UPDATE packets SET packet = ? WHERE pkt_id = ?;")?;
+        for (pkt_id, data) in packets {
+            let raw = decompressor.decompress(data)?;
+            let stored = match &mut compressor {
+                Some(c) => c.compress(&raw)?,
+                None => raw.clone(),
+            };
+
+            stats.add(raw.len(), stored.len());
+            stmt.execute(params![stored, pkt_id])?;
+        }
+    }
+
+    // Dictionaries of the previous compression are not used anymore
+    let dict_id = compressor.as_ref().and_then(|c| c.dictionary_id());
+    conn.execute(
+        "DELETE FROM dictionaries WHERE ? IS NULL OR dictID != ?1;",
+        params![dict_id],
+    )?;
+
+    conn.execute("COMMIT;", ())?;
+    conn.execute("VACUUM;", ())?;
+    drop(conn);
+
+    let size_after = metadata(&args.file)?.len();
+
+    info!(
+        "Packets: {} bytes raw, {} bytes stored (ratio {:.2})",
+        stats.raw,
+        stats.stored,
+        stats.ratio()
+    );
+    info!(
+        "File: {} bytes before, {} bytes after (ratio {:.2})",
+        size_before,
+        size_after,
+        size_before as f64 / size_after.max(1) as f64
+    );
+
+    Ok(())
+}
+
+/// Packets spread over the whole recording, to train the compression dictionary on.
+fn training_samples(
+    conn: &Connection,
+    decompressor: &mut PacketDecompressor,
+) -> Result<Vec<Vec<u8>>> {
+    let count: i64 = conn.query_row("SELECT COUNT(*) FROM packets;", (), |r| r.get(0))?;
+    let step = (count / TRAINING_SAMPLES as i64).max(1);
+
+    let mut stmt = conn.prepare("SELECT packet FROM packets WHERE pkt_id % ? = 0 LIMIT ?;")?;
+    let mut rows = stmt.query(params![step, TRAINING_SAMPLES])?;
+
+    let mut samples = Vec::with_capacity(TRAINING_SAMPLES);
+    while let Some(row) = rows.next()? {
+        samples.push(decompressor.decompress(row.get(0)?)?);
+    }
+
+    Ok(samples)
+}
@@ -0,0 +1,253 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+
+use anyhow::{Error, Result};
+use log::{info, warn};
+use rusqlite::{params, Connection};
+use zstd::bulk::{Compressor, Decompressor};
+use zstd::zstd_safe::{get_dict_id_from_frame, get_frame_content_size};
+
+use crate::db::table_exists;
+
+/// Magic number at the start of every zstd frame. Raw packets start with their packet format
+/// instead, so compressed and uncompressed packets can be told apart.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Number of packets the dictionary is trained on
+pub(crate) const TRAINING_SAMPLES: usize = 2048;
+
+/// Maximum size of a trained dictionary
+const DICTIONARY_SIZE: usize = 64 * 1024;
+
+/// Upper bound of the size of a packet, for frames without a content size
+const MAX_PACKET_SIZE: usize = 2048;
+
+pub(crate) const DEFAULT_LEVEL: i32 = 3;
+
+pub(crate) fn init_dictionaries_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "
+        CREATE TABLE IF NOT EXISTS dictionaries (
+            dictID     INTEGER PRIMARY KEY,
+            dictionary BLOB    NOT NULL
+        );
+        ",
+        (),
+    )?;
+
+    Ok(())
+}
+
+pub(crate) fn is_compressed(data: &[u8]) -> bool {
+    data.starts_with(&ZSTD_MAGIC)
+}
+
+/// Bytes before and after compression
+#[derive(Debug, Default, Copy, Clone)]
+pub(crate) struct CompressionStats {
+    pub(crate) raw: u64,
+    pub(crate) stored: u64,
+}
+
+impl CompressionStats {
+    pub(crate) fn add(&mut self, raw: usize, stored: usize) {
+        self.raw += raw as u64;
+        self.stored += stored as u64;
+    }
+
+    pub(crate) fn ratio(&self) -> f64 {
+        if self.stored == 0 {
+            1.0
+        } else {
+            self.raw as f64 / self.stored as f64
+        }
+    }
+}
+
+/// Compresses packets one by one with zstd, so that they can still be read and seeked
+/// individually.
+///
+/// A single packet is too small to compress well on its own, so a dictionary is trained on the
+/// first packets and used for all the following ones.
+pub(crate) struct PacketCompressor {
+    level: i32,
+    compressor: Compressor<'static>,
+    /// Packets collected to train the dictionary, until there are enough of them
+    samples: Option<Vec<Vec<u8>>>,
+    dictionary: Option<(u32, Vec<u8>)>,
+    pub(crate) stats: CompressionStats,
+}
+
+impl PacketCompressor {
+    pub(crate) fn new(level: i32) -> Result<Self> {
+        Ok(Self {
+            level,
+            compressor: Compressor::new(level)?,
+            samples: Some(Vec::with_capacity(TRAINING_SAMPLES)),
+            dictionary: None,
+            stats: CompressionStats::default(),
+        })
+    }
+
+    /// Train the dictionary on the given packets.
+    ///
+    /// Without enough data for a dictionary, packets are compressed without one.
+    pub(crate) fn train(&mut self, samples: &[Vec<u8>]) -> Result<()> {
+        self.samples = None;
+
+        let dictionary = match zstd::dict::from_samples(samples, DICTIONARY_SIZE) {
+            Ok(d) => d,
+            Err(e) => {
+                warn!("Unable to train a compression dictionary: {}", e);
+                return Ok(());
+            }
+        };
+        let Some(dict_id) = zstd::zstd_safe::get_dict_id(&dictionary) else {
+            warn!("Trained compression dictionary has no ID, ignoring it");
+            return Ok(());
+        };
+
+        info!(
+            "Trained a {} bytes compression dictionary on {} packets",
+            dictionary.len(),
+            samples.len()
+        );
+
+        self.compressor = Compressor::with_dictionary(self.level, &dictionary)?;
+        self.dictionary = Some((dict_id.get(), dictionary));
+
+        Ok(())
+    }
+
+    pub(crate) fn compress(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        if let Some(samples) = &mut self.samples {
+            samples.push(data.to_vec());
+
+            if samples.len() >= TRAINING_SAMPLES {
+                let samples = std::mem::take(samples);
+                self.train(&samples)?;
+            }
+        }
+
+        let compressed = self.compressor.compress(data)?;
+        self.stats.add(data.len(), compressed.len());
+
+        Ok(compressed)
+    }
+
+    /// Store the dictionary in a database, before any packet compressed with it.
+    pub(crate) fn save_dictionary(&self, conn: &Connection) -> Result<()> {
+        if let Some((dict_id, dictionary)) = &self.dictionary {
+            conn.execute(
+                "INSERT OR IGNORE INTO dictionaries(dictID, dictionary) VALUES (?, ?);",
+                params![dict_id, dictionary],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn dictionary_id(&self) -> Option<u32> {
+        self.dictionary.as_ref().map(|(id, _)| *id)
+    }
+}
+
+/// Restores the packets stored by a [`PacketCompressor`], and returns uncompressed ones as is.
+pub(crate) struct PacketDecompressor {
+    /// Decompressors by dictionary ID, 0 being no dictionary
+    decompressors: HashMap<u32, Decompressor<'static>>,
+    dictionaries: HashMap<u32, Vec<u8>>,
+}
+
+impl PacketDecompressor {
+    pub(crate) fn new(conn: &Connection) -> Result<Self> {
+        let mut dictionaries = HashMap::new();
+
+        if table_exists(conn, "dictionaries")? {
+            let mut stmt = conn.prepare("SELECT dictID, dictionary FROM dictionaries;")?;
+            let mut rows = stmt.query(())?;
+            while let Some(row) = rows.next()? {
+                dictionaries.insert(row.get(0)?, row.get(1)?);
+            }
+        }
+
+        Ok(Self {
+            decompressors: HashMap::new(),
+            dictionaries,
+        })
+    }
+
+    pub(crate) fn decompress(&mut self, data: Vec<u8>) -> Result<Vec<u8>> {
+        if !is_compressed(&data) {
+            return Ok(data);
+        }
+
+        let dict_id = get_dict_id_from_frame(&data).map_or(0, |id| id.get());
+        let decompressor = match self.decompressors.entry(dict_id) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let decompressor = match dict_id {
+                    0 => Decompressor::new()?,
+                    id => match self.dictionaries.get(&id) {
+                        Some(dictionary) => Decompressor::with_dictionary(dictionary)?,
+                        None => {
+                            return Err(Error::msg(format!(
+                                "Missing compression dictionary {}",
+                                id
+                            )))
+                        }
+                    },
+                };
+                e.insert(decompressor)
+            }
+        };
+
+        let capacity = match get_frame_content_size(&data) {
+            Ok(Some(size)) => size as usize,
+            _ => MAX_PACKET_SIZE,
+        };
+
+        Ok(decompressor.decompress(&data, capacity)?)
+    }
+}
+
+#[cfg(test)]
+mod test_compression {
+    use rusqlite::Connection;
+
+    use super::{init_dictionaries_table, is_compressed, PacketCompressor, PacketDecompressor};
+
+    fn packet(i: usize) -> Vec<u8> {
+        let mut packet = vec![0xe5, 0x07, 1, 18, 1, (i % 12) as u8];
+        packet.extend((0..1000).map(|j| ((i * 7 + j) % 17) as u8));
+        packet
+    }
+
+    #[test]
+    fn test_round_trip_with_dictionary() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_dictionaries_table(&conn).unwrap();
+
+        let mut compressor = PacketCompressor::new(3).unwrap();
+        let samples: Vec<Vec<u8>> = (0..500).map(packet).collect();
+        compressor.train(&samples).unwrap();
+        compressor.save_dictionary(&conn).unwrap();
+        assert!(compressor.dictionary_id().is_some());
+
+        let compressed = compressor.compress(&packet(1000)).unwrap();
+        assert!(is_compressed(&compressed));
+        assert!(compressor.stats.ratio() > 1.0);
+
+        let mut decompressor = PacketDecompressor::new(&conn).unwrap();
+        assert_eq!(decompressor.decompress(compressed).unwrap(), packet(1000));
+    }
+
+    #[test]
+    fn test_raw_packets_are_returned_as_is() {
+        let conn = Connection::open_in_memory().unwrap();
+        let mut decompressor = PacketDecompressor::new(&conn).unwrap();
+
+        assert!(!is_compressed(&packet(0)));
+        assert_eq!(decompressor.decompress(packet(0)).unwrap(), packet(0));
+    }
+}
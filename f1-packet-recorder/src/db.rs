@@ -0,0 +1,331 @@
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Error, Result};
+use log::info;
+use rusqlite::{params, Connection};
+
+use crate::compression::{init_dictionaries_table, PacketDecompressor};
+use crate::quarantine::init_quarantine_table;
+use crate::sessions::{add_session_sources, init_sessions_table};
+
+use super::MigrateArgs;
+
+type Migration = fn(&Connection) -> Result<()>;
+
+/// Changes to the schema, in order. A database at version `n` had the first `n` ones applied.
+///
+/// Migrations must keep working on files created before versioning, which may already have
+/// some of their tables.
+const MIGRATIONS: [Migration; 7] = [
+    create_packets_table,
+    init_sessions_table,
+    create_indices,
+    init_dictionaries_table,
+    init_quarantine_table,
+    add_packet_sources,
+    add_session_sources,
+];
+
+pub(crate) const SCHEMA_VERSION: usize = MIGRATIONS.len();
+
+/// Create the tables of a new database, or bring an existing one to the current schema.
+pub(crate) fn init_db(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "
+        CREATE TABLE IF NOT EXISTS schema_version (
+            version   INTEGER PRIMARY KEY,
+            appliedAt REAL    NOT NULL
+        );
+        ",
+        (),
+    )?;
+
+    let version = schema_version(conn)?;
+    if version > SCHEMA_VERSION {
+        return Err(newer_schema_error(version));
+    }
+
+    if version < SCHEMA_VERSION && table_exists(conn, "packets")? {
+        info!(
+            "Migrating database from schema version {} to {}",
+            version, SCHEMA_VERSION
+        );
+    }
+
+    for (idx, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let version = idx + 1;
+
+        let applied_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Somehow we went back in time")
+            .as_secs_f64();
+
+        // Rolled back when dropped, if the migration fails
+        let tx = conn.unchecked_transaction()?;
+        migration(&tx)?;
+        tx.execute(
+            "INSERT INTO schema_version(version, appliedAt) VALUES (?, ?);",
+            params![version, applied_at],
+        )?;
+        tx.commit()?;
+    }
+
+    Ok(())
+}
+
+/// Check that a database opened without migrations can be read.
+///
+/// Older schemas only lack tables and columns, which are shadowed by empty temporary ones so that
+/// the database reads like a current one. Temporary objects can be created on read-only
+/// connections, and take precedence over the tables of the file.
+pub(crate) fn check_schema(conn: &Connection, file: &str) -> Result<()> {
+    let version = schema_version(conn)?;
+    if version > SCHEMA_VERSION {
+        return Err(newer_schema_error(version));
+    }
+    if version == SCHEMA_VERSION {
+        return Ok(());
+    }
+
+    if !table_exists(conn, "packets")? {
+        return Err(Error::msg(format!("{} is not a recording", file)));
+    }
+    info!(
+        "{} has the older schema version {}, run `f1-packet-recorder migrate {}` to upgrade it",
+        file, version, file
+    );
+
+    if !column_exists(conn, "packets", "source")? {
+        conn.execute(
+            "CREATE TEMP VIEW packets AS \
+            SELECT *, NULL AS source, NULL AS sourceLabel FROM main.packets;",
+            (),
+        )?;
+    }
+    if !table_exists(conn, "quarantine")? {
+        conn.execute(
+            "
+            CREATE TEMP TABLE quarantine (
+                quarantine_id INTEGER PRIMARY KEY,
+                timestamp     REAL    NOT NULL,
+                error         TEXT    NOT NULL,
+                packet        BLOB    NOT NULL,
+                source        TEXT,
+                sourceLabel   TEXT
+            );
+            ",
+            (),
+        )?;
+    } else if !column_exists(conn, "quarantine", "source")? {
+        conn.execute(
+            "CREATE TEMP VIEW quarantine AS \
+            SELECT *, NULL AS source, NULL AS sourceLabel FROM main.quarantine;",
+            (),
+        )?;
+    }
+
+    Ok(())
+}
+
+fn column_exists(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    let exists = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info(?) WHERE name = ?;",
+        [table, column],
+        |r| r.get(0),
+    )?;
+
+    Ok(exists)
+}
+
+fn newer_schema_error(version: usize) -> Error {
+    Error::msg(format!(
+        "Database schema version {} is newer than the supported version {}",
+        version, SCHEMA_VERSION
+    ))
+}
+
+pub(crate) fn migrate(args: &MigrateArgs) -> Result<()> {
+    if !Path::new(&args.file).exists() {
+        return Err(Error::msg(format!("{} not found", args.file)));
+    }
+
+    let conn = Connection::open(&args.file)?;
+    let version = match table_exists(&conn, "schema_version")? {
+        true => schema_version(&conn)?,
+        false => 0,
+    };
+    init_db(&conn)?;
+
+    if version == SCHEMA_VERSION {
+        info!(
+            "{} is already at schema version {}",
+            args.file, SCHEMA_VERSION
+        );
+    }
+
+    Ok(())
+}
+
+/// Version of the schema, 0 for files created before versioning
+pub(crate) fn schema_version(conn: &Connection) -> Result<usize> {
+    if !table_exists(conn, "schema_version")? {
+        return Ok(0);
+    }
+
+    let version: Option<usize> =
+        conn.query_row("SELECT MAX(version) FROM schema_version;", (), |r| r.get(0))?;
+
+    Ok(version.unwrap_or_default())
+}
+
+pub(crate) fn table_exists(conn: &Connection, table: &str) -> Result<bool> {
+    let exists = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = ?;",
+        [table],
+        |r| r.get(0),
+    )?;
+
+    Ok(exists)
+}
+
+fn create_packets_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "
+        CREATE TABLE IF NOT EXISTS packets (
+            pkt_id           INTEGER  PRIMARY KEY,
+            timestamp        REAL     NOT NULL,
+            packetFormat     INTEGER  NOT NULL,
+            gameMajorVersion INTEGER  NOT NULL,
+            gameMinorVersion INTEGER  NOT NULL,
+            packetVersion    INTEGER  NOT NULL,
+            packetID         INTEGER  NOT NULL,
+            sessionID        CHAR(16) NOT NULL,
+            sessionTime      REAL     NOT NULL,
+            frameIdentifier  INTEGER  NOT NULL,
+            playerCarIndex   INTEGER  NOT NULL,
+            packet           BLOB     NOT NULL
+        );
+        ",
+        (),
+    )?;
+
+    Ok(())
+}
+
+/// Where a packet was received from, when recording from several games at once
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct PacketSource {
+    /// Address and port of the sender
+    pub(crate) address: Option<String>,
+    /// Label of the listener that received the packet
+    pub(crate) label: Option<String>,
+}
+
+/// A packet as stored in the database, before parsing.
+pub(crate) struct StoredPacket {
+    pub(crate) timestamp: f64,
+    pub(crate) source: PacketSource,
+    pub(crate) data: Vec<u8>,
+}
+
+/// Iterate over every stored packet, in recording order, decompressing them if needed.
+pub(crate) fn for_each_packet<F>(conn: &Connection, mut f: F) -> Result<()>
+where
+    F: FnMut(StoredPacket) -> Result<()>,
+{
+    let mut decompressor = PacketDecompressor::new(conn)?;
+    let mut stmt = conn
+        .prepare("SELECT timestamp, source, sourceLabel, packet FROM packets ORDER BY pkt_id;")?;
+    let mut rows = stmt.query([])?;
+
+    while let Some(row) = rows.next()? {
+        f(StoredPacket {
+            timestamp: row.get(0)?,
+            source: PacketSource {
+                address: row.get(1)?,
+                label: row.get(2)?,
+            },
+            data: decompressor.decompress(row.get(3)?)?,
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Create the indices used to seek in a recording.
+fn create_indices(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "
+        CREATE INDEX IF NOT EXISTS packets_session_time ON packets(sessionID, sessionTime);
+        CREATE INDEX IF NOT EXISTS packets_packet_id ON packets(packetID);
+        ",
+    )?;
+
+    Ok(())
+}
+
+/// Record where packets come from, to store several games in the same database.
+fn add_packet_sources(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "
+        ALTER TABLE packets ADD COLUMN source TEXT;
+        ALTER TABLE packets ADD COLUMN sourceLabel TEXT;
+        ALTER TABLE quarantine ADD COLUMN source TEXT;
+        ALTER TABLE quarantine ADD COLUMN sourceLabel TEXT;
+        CREATE INDEX IF NOT EXISTS packets_source_label ON packets(sourceLabel);
+        ",
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_db {
+    use rusqlite::Connection;
+
+    use super::{check_schema, create_packets_table, init_db};
+    use crate::recording::Recording;
+    use crate::selection::Selection;
+
+    #[test]
+    fn test_read_baseline_schema() {
+        // A recording from before schema versioning, sources and quarantine
+        let conn = Connection::open_in_memory().unwrap();
+        create_packets_table(&conn).unwrap();
+        conn.execute(
+            "INSERT INTO packets VALUES (1, 10.0, 2021, 1, 0, 1, 6, '000000000000aaaa', 1.5, 7, 0, x'00');",
+            (),
+        )
+        .unwrap();
+
+        check_schema(&conn, "baseline.db").unwrap();
+
+        let recording = Recording::Database(conn);
+        let mut packets = vec![];
+        recording
+            .for_each_selected(&Selection::default(), 0, |packet| {
+                packets.push((packet.pkt_id, packet.frame, packet.source.label));
+                Ok(true)
+            })
+            .unwrap();
+        assert_eq!(packets, vec![(1, 7, None)]);
+        assert!(recording
+            .count_unparseable_by_error(None)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_check_schema() {
+        let conn = Connection::open_in_memory().unwrap();
+        assert!(check_schema(&conn, "empty.db").is_err());
+
+        init_db(&conn).unwrap();
+        check_schema(&conn, "current.db").unwrap();
+
+        conn.execute("INSERT INTO schema_version VALUES (100, 0.0);", ())
+            .unwrap();
+        assert!(check_schema(&conn, "newer.db").is_err());
+    }
+}
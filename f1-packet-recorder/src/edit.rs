@@ -0,0 +1,476 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+
+use anyhow::{Error, Result};
+use log::{info, warn};
+use rusqlite::{Connection, Rows};
+
+use f1_telemetry::packet::parse_packet;
+
+use crate::compression::PacketDecompressor;
+use crate::db::{for_each_packet, PacketSource, StoredPacket};
+use crate::quarantine::count_quarantined;
+use crate::recorder::{insert_packets, TimestampedPacket};
+use crate::recording::Recording;
+use crate::selection::Selection;
+use crate::sessions::Sessions;
+use crate::utils::{get_database_connection, open_database_read_only, HeaderLayout};
+
+use super::{MergeArgs, SliceArgs, TrimArgs};
+
+/// Number of packets inserted per transaction
+const BATCH_SIZE: usize = 256;
+
+/// Time within which a packet already merged from another input is a duplicate, in seconds
+const DUPLICATE_WINDOW: f64 = 1.0;
+
+pub(crate) fn slice(args: &SliceArgs) -> Result<()> {
+    info!("Slicing {} to {}", args.file, args.output);
+
+    let conn = open_database_read_only(&args.file)?;
+    warn_quarantined([&conn])?;
+    let recording = Recording::Database(conn);
+    let selection = Selection::new(&args.selection, &recording)?;
+    let mut output = Output::create(&args.output)?;
+
+    recording.for_each_selected(&selection, 0, |p| {
+        output.push(StoredPacket {
+            timestamp: p.timestamp,
+            source: p.source,
+            data: p.data,
+        })?;
+
+        Ok(true)
+    })?;
+
+    output.finish()
+}
+
+pub(crate) fn merge(args: &MergeArgs) -> Result<()> {
+    info!("Merging {} to {}", args.files.join(", "), args.output);
+
+    let connections = args
+        .files
+        .iter()
+        .map(|f| open_database_read_only(f))
+        .collect::<Result<Vec<_>>>()?;
+    let mut decompressors = connections
+        .iter()
+        .map(PacketDecompressor::new)
+        .collect::<Result<Vec<_>>>()?;
+    let mut statements = connections
+        .iter()
+        .map(|c| {
+            c.prepare(
+                "
+                SELECT timestamp, source, sourceLabel, packet
+                FROM packets ORDER BY timestamp, pkt_id;
+                ",
+            )
+        })
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let mut rows = statements
+        .iter_mut()
+        .map(|s| s.query(()))
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut output = Output::create(&args.output)?;
+
+    // Next packet of every input, the earliest one being written first
+    let mut heads = Vec::with_capacity(rows.len());
+    for (r, d) in rows.iter_mut().zip(decompressors.iter_mut()) {
+        heads.push(next_packet(r, d)?);
+    }
+
+    let mut recent = RecentPackets::default();
+    let mut duplicates = 0;
+
+    loop {
+        let earliest = heads
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, head)| head.as_ref().map(|p| (idx, p.timestamp)))
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(idx, _)| idx);
+        let Some(idx) = earliest else {
+            break;
+        };
+
+        let next = next_packet(&mut rows[idx], &mut decompressors[idx])?;
+        let Some(packet) = std::mem::replace(&mut heads[idx], next) else {
+            unreachable!("the earliest input has a packet");
+        };
+
+        if recent.is_duplicate(idx, &packet) {
+            duplicates += 1;
+            continue;
+        }
+
+        output.push(packet)?;
+    }
+
+    info!("Skipped {} duplicate packets", duplicates);
+
+    warn_quarantined(&connections)?;
+    output.finish()
+}
+
+/// Header fields identifying a packet of a game
+type PacketKey = (u64, u32, u8);
+
+/// Packets merged within the last `DUPLICATE_WINDOW`, to find the ones recorded in several
+/// inputs.
+#[derive(Default)]
+struct RecentPackets {
+    /// Data of the recent packets, with their input and timestamp
+    packets: HashMap<PacketKey, Vec<(usize, f64, Vec<u8>)>>,
+    /// Keys of the recent packets, oldest first
+    order: VecDeque<(f64, PacketKey)>,
+}
+
+impl RecentPackets {
+    /// Whether the same packet was merged from another input. Packets repeated in a single input
+    /// were received twice, and are kept.
+    fn is_duplicate(&mut self, input: usize, packet: &StoredPacket) -> bool {
+        self.forget_before(packet.timestamp - DUPLICATE_WINDOW);
+
+        let Some(layout) = HeaderLayout::of(&packet.data) else {
+            return false;
+        };
+        let data = &packet.data;
+        let key = (
+            u64::from_le_bytes(
+                data[layout.session_uid..layout.session_uid + 8]
+                    .try_into()
+                    .unwrap(),
+            ),
+            u32::from_le_bytes(
+                data[layout.frame_identifier..layout.frame_identifier + 4]
+                    .try_into()
+                    .unwrap(),
+            ),
+            data[layout.packet_id],
+        );
+
+        let recent = self.packets.entry(key).or_default();
+        if recent.iter().any(|(i, _, d)| *i != input && d == data) {
+            return true;
+        }
+
+        recent.push((input, packet.timestamp, data.clone()));
+        self.order.push_back((packet.timestamp, key));
+        false
+    }
+
+    fn forget_before(&mut self, timestamp: f64) {
+        while let Some((_, key)) = self.order.front().filter(|(t, _)| *t < timestamp) {
+            let key = *key;
+            self.order.pop_front();
+
+            if let Some(recent) = self.packets.get_mut(&key) {
+                recent.retain(|(_, t, _)| *t >= timestamp);
+                if recent.is_empty() {
+                    self.packets.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+/// Warn about the quarantined packets of the inputs, which are not copied.
+fn warn_quarantined<'a>(inputs: impl IntoIterator<Item = &'a Connection>) -> Result<()> {
+    let mut quarantined = 0;
+    for conn in inputs {
+        quarantined += count_quarantined(conn)?;
+    }
+
+    if quarantined > 0 {
+        warn!(
+            "Left out {} quarantined packets, use reparse on the input first to keep them",
+            quarantined
+        );
+    }
+
+    Ok(())
+}
+
+fn next_packet(
+    rows: &mut Rows,
+    decompressor: &mut PacketDecompressor,
+) -> Result<Option<StoredPacket>> {
+    match rows.next()? {
+        Some(row) => Ok(Some(StoredPacket {
+            timestamp: row.get(0)?,
+            source: PacketSource {
+                address: row.get(1)?,
+                label: row.get(2)?,
+            },
+            data: decompressor.decompress(row.get(3)?)?,
+        })),
+        None => Ok(None),
+    }
+}
+
+pub(crate) fn trim(args: &TrimArgs) -> Result<()> {
+    info!(
+        "Trimming idle periods longer than {:.1}s from {} to {}",
+        args.idle, args.file, args.output
+    );
+
+    let conn = open_database_read_only(&args.file)?;
+    let mut output = Output::create(&args.output)?;
+
+    // Last change of the session time of every session, with its timestamp
+    let mut last_change: HashMap<u64, (u32, f64)> = HashMap::new();
+    let mut previous_timestamp: Option<f64> = None;
+    let mut offset = 0.0;
+    let mut dropped = 0;
+
+    for_each_packet(&conn, |p| {
+        let header = match parse_packet(p.data.len(), &p.data) {
+            Ok(packet) => packet.header().clone(),
+            Err(_) => return Ok(()),
+        };
+
+        let (session_time, changed_at) = last_change
+            .entry(header.session_uid)
+            .or_insert((header.session_time, p.timestamp));
+        if *session_time != header.session_time {
+            *session_time = header.session_time;
+            *changed_at = p.timestamp;
+        } else if p.timestamp - *changed_at > args.idle {
+            // Paused game or menus
+            dropped += 1;
+            return Ok(());
+        }
+
+        // Remove the gaps left by idle periods
+        if let Some(previous) = previous_timestamp {
+            let gap = p.timestamp - previous;
+            if gap > args.idle {
+                offset += gap;
+            }
+        }
+        previous_timestamp = Some(p.timestamp);
+
+        output.push(StoredPacket {
+            timestamp: p.timestamp - offset,
+            ..p
+        })
+    })?;
+
+    info!(
+        "Dropped {} idle packets, removed {:.1}s of gaps",
+        dropped, offset
+    );
+
+    warn_quarantined([&conn])?;
+    output.finish()
+}
+
+/// New recording written by the editing commands
+pub(crate) struct Output {
+    conn: Connection,
+    sessions: Sessions,
+    packets: Vec<TimestampedPacket>,
+    count: u64,
+    errors: u64,
+}
+
+impl Output {
+    pub(crate) fn create(file: &str) -> Result<Self> {
+        if Path::new(file).exists() {
+            return Err(Error::msg(format!("{} already exists", file)));
+        }
+
+        let conn = get_database_connection(file)?;
+
+        Ok(Self {
+            conn,
+            sessions: Sessions::default(),
+            packets: Vec::with_capacity(BATCH_SIZE),
+            count: 0,
+            errors: 0,
+        })
+    }
+
+    pub(crate) fn push(&mut self, stored: StoredPacket) -> Result<()> {
+        let StoredPacket {
+            timestamp,
+            source,
+            data,
+        } = stored;
+
+        let packet = match parse_packet(data.len(), &data) {
+            Ok(p) => p,
+            Err(_) => {
+                self.errors += 1;
+                return Ok(());
+            }
+        };
+
+        self.sessions
+            .update(timestamp, &packet, source.label.as_deref());
+        self.packets.push(TimestampedPacket {
+            timestamp,
+            source,
+            packet,
+            data,
+        });
+
+        if self.packets.len() >= BATCH_SIZE {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        insert_packets(&self.conn, &self.packets.iter().collect::<Vec<_>>())?;
+        self.count += self.packets.len() as u64;
+        self.packets.clear();
+
+        Ok(())
+    }
+
+    pub(crate) fn finish(mut self) -> Result<()> {
+        self.flush()?;
+        self.sessions.save_all(&self.conn)?;
+
+        if self.errors > 0 {
+            warn!("Skipped {} packets that could not be parsed", self.errors);
+        }
+        info!("Wrote {} packets", self.count);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_edit {
+    use std::fs::remove_file;
+
+    use rusqlite::Connection;
+
+    use crate::db::{PacketSource, StoredPacket};
+    use crate::fixtures::{file_recording, lap_data_2021, laps, temp_file};
+    use crate::{MergeArgs, SelectionArgs, SliceArgs, TrimArgs};
+
+    use super::{merge, slice, trim, RecentPackets};
+
+    /// Packet count, and first and last timestamps of a recording
+    fn bounds(file: &str) -> (u64, f64, f64) {
+        let conn = Connection::open(file).unwrap();
+        conn.query_row(
+            "SELECT COUNT(*), MIN(timestamp), MAX(timestamp) FROM packets;",
+            (),
+            |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+        )
+        .unwrap()
+    }
+
+    /// 2021 header of a Lap Data packet
+    fn packet(timestamp: f64, frame: u32, payload: u8) -> StoredPacket {
+        let mut data = vec![0u8; 25];
+        data[0..2].copy_from_slice(&2021u16.to_le_bytes());
+        data[5] = 2;
+        data[6..14].copy_from_slice(&0xaaaau64.to_le_bytes());
+        data[18..22].copy_from_slice(&frame.to_le_bytes());
+        data[24] = payload;
+
+        StoredPacket {
+            timestamp,
+            source: PacketSource::default(),
+            data,
+        }
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let mut recent = RecentPackets::default();
+
+        assert!(!recent.is_duplicate(0, &packet(1.0, 60, 0)));
+        // Same packet in another input
+        assert!(recent.is_duplicate(1, &packet(1.01, 60, 0)));
+        // Same frame with other data
+        assert!(!recent.is_duplicate(1, &packet(1.01, 60, 1)));
+        // Received twice by the same recording
+        assert!(!recent.is_duplicate(0, &packet(1.02, 60, 0)));
+        // Too late to be the same packet
+        assert!(!recent.is_duplicate(1, &packet(2.5, 60, 0)));
+        assert_eq!(recent.order.len(), 1);
+    }
+
+    #[test]
+    fn test_slice() {
+        let file = file_recording("slice", &laps(1..=3));
+        let output = temp_file("slice-output");
+
+        slice(&SliceArgs {
+            file: file.clone(),
+            output: output.clone(),
+            selection: SelectionArgs {
+                session: None,
+                from_lap: Some(2),
+                to_lap: Some(2),
+                from_session_time: None,
+                to_session_time: None,
+                packet_types: vec![],
+                source: None,
+            },
+        })
+        .unwrap();
+        assert_eq!(bounds(&output), (10, 1010.0, 1019.0));
+
+        remove_file(file).unwrap();
+        remove_file(output).unwrap();
+    }
+
+    #[test]
+    fn test_merge() {
+        // Both recordings received lap 2
+        let first = file_recording("merge-first", &laps(1..=2));
+        let second = file_recording("merge-second", &laps(2..=3));
+        let output = temp_file("merge-output");
+
+        merge(&MergeArgs {
+            files: vec![first.clone(), second.clone()],
+            output: output.clone(),
+        })
+        .unwrap();
+        assert_eq!(bounds(&output), (30, 1000.0, 1029.0));
+
+        for file in [first, second, output] {
+            remove_file(file).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_trim() {
+        let mut packets = laps(1..=1);
+        // Paused on the last second of the lap for 10 seconds
+        for second in 1..=10 {
+            let data = lap_data_2021(0xaaaa, 9.0, 540, 1);
+            packets.push((1009.0 + second as f64, data));
+        }
+        packets.extend(
+            laps(2..=2)
+                .into_iter()
+                .map(|(timestamp, data)| (timestamp + 10.0, data)),
+        );
+        let file = file_recording("trim", &packets);
+        let output = temp_file("trim-output");
+
+        trim(&TrimArgs {
+            file: file.clone(),
+            output: output.clone(),
+            idle: 5.0,
+        })
+        .unwrap();
+        // The last 5 seconds of the pause are dropped, and the gap they leave removed
+        assert_eq!(bounds(&output), (25, 1000.0, 1023.0));
+
+        remove_file(file).unwrap();
+        remove_file(output).unwrap();
+    }
+}
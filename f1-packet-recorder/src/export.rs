@@ -0,0 +1,370 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{create_dir_all, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use anyhow::Result;
+use log::{info, warn};
+use rusqlite::Connection;
+use serde_json::{Map, Value};
+
+use f1_telemetry::packet::{parse_packet, Packet, PacketType};
+
+use crate::db::{for_each_packet, PacketSource};
+use crate::utils::{format_session_uid, open_recording, parse_session_uid};
+
+use super::{ExportArgs, ExportFormat};
+
+mod columnar;
+mod motec;
+
+/// A flattened packet, ready to be written as a CSV row or a JSON object
+type Row = Map<String, Value>;
+
+pub(crate) fn export(args: &ExportArgs) -> Result<()> {
+    info!(
+        "Exporting {} to {} ({:?})",
+        args.file, args.output_dir, args.format
+    );
+
+    let conn = open_recording(&args.file)?;
+    let filter = ExportFilter::new(args)?;
+
+    create_dir_all(&args.output_dir)?;
+    let output_dir = Path::new(&args.output_dir);
+
+    match args.format {
+        ExportFormat::Csv => export_csv(&conn, &filter, output_dir),
+        ExportFormat::JsonLines => export_json_lines(&conn, &filter, output_dir),
+        ExportFormat::Parquet => {
+            columnar::export_parquet(&conn, &filter, output_dir, args.compression)
+        }
+        ExportFormat::Motec => motec::export_motec(&conn, &filter, output_dir, args.frequency),
+    }
+}
+
+pub(crate) struct ExportFilter {
+    session_uid: Option<u64>,
+    cars: Vec<u8>,
+    from: Option<f64>,
+    to: Option<f64>,
+    source: Option<String>,
+}
+
+impl ExportFilter {
+    fn new(args: &ExportArgs) -> Result<Self> {
+        let session_uid = match &args.session {
+            Some(s) => Some(parse_session_uid(s)?),
+            None => None,
+        };
+
+        Ok(Self {
+            session_uid,
+            cars: args.cars.clone(),
+            from: args.from,
+            to: args.to,
+            source: args.source.clone(),
+        })
+    }
+
+    fn accepts_source(&self, source: &PacketSource) -> bool {
+        self.source.is_none() || self.source == source.label
+    }
+
+    fn accepts_packet(&self, packet: &Packet) -> bool {
+        let header = packet.header();
+        let session_time = header.session_time as f64 / 1000.0;
+
+        self.session_uid.is_none_or(|uid| uid == header.session_uid)
+            && self.from.is_none_or(|from| session_time >= from)
+            && self.to.is_none_or(|to| session_time <= to)
+    }
+
+    fn accepts_car(&self, car_index: Option<u8>) -> bool {
+        match car_index {
+            Some(idx) => self.cars.is_empty() || self.cars.contains(&idx),
+            None => true,
+        }
+    }
+}
+
+/// Parse every packet of the database and call `f` with each of the rows matching the filter.
+pub(crate) fn for_each_row<F>(conn: &Connection, filter: &ExportFilter, mut f: F) -> Result<()>
+where
+    F: FnMut(PacketType, Row) -> Result<()>,
+{
+    let mut errors = 0;
+
+    for_each_packet(conn, |p| {
+        if !filter.accepts_source(&p.source) {
+            return Ok(());
+        }
+
+        let packet = match parse_packet(p.data.len(), &p.data) {
+            Ok(packet) => packet,
+            Err(_) => {
+                errors += 1;
+                return Ok(());
+            }
+        };
+
+        if !filter.accepts_packet(&packet) {
+            return Ok(());
+        }
+
+        let packet_type = packet.header().packet_type;
+        for row in packet_rows(p.timestamp, &packet, filter)? {
+            f(packet_type, row)?;
+        }
+
+        Ok(())
+    })?;
+
+    if errors > 0 {
+        warn!("Skipped {} packets that could not be parsed", errors);
+    }
+
+    Ok(())
+}
+
+fn export_csv(conn: &Connection, filter: &ExportFilter, output_dir: &Path) -> Result<()> {
+    // Columns can vary between packets of the same type (events for example), so we need a
+    // first pass to know all of them before writing the headers.
+    let mut columns: HashMap<u8, Vec<String>> = HashMap::new();
+    let mut known_columns: HashMap<u8, HashSet<String>> = HashMap::new();
+
+    for_each_row(conn, filter, |packet_type, row| {
+        let id = u8::from(packet_type);
+        let cols = columns.entry(id).or_default();
+        let known = known_columns.entry(id).or_default();
+
+        for key in row.keys() {
+            if known.insert(key.clone()) {
+                cols.push(key.clone());
+            }
+        }
+
+        Ok(())
+    })?;
+
+    let mut writers: HashMap<u8, csv::Writer<File>> = HashMap::new();
+
+    for_each_row(conn, filter, |packet_type, row| {
+        let id = u8::from(packet_type);
+        let cols = &columns[&id];
+
+        let writer = match writers.get_mut(&id) {
+            Some(w) => w,
+            None => {
+                let path = output_dir.join(format!("{}.csv", packet_type.name()));
+                info!("Writing {}", path.display());
+
+                let mut w = csv::Writer::from_path(path)?;
+                w.write_record(cols)?;
+                writers.entry(id).or_insert(w)
+            }
+        };
+
+        writer.write_record(cols.iter().map(|c| csv_value(row.get(c))))?;
+
+        Ok(())
+    })?;
+
+    for w in writers.values_mut() {
+        w.flush()?;
+    }
+
+    Ok(())
+}
+
+fn export_json_lines(conn: &Connection, filter: &ExportFilter, output_dir: &Path) -> Result<()> {
+    let mut writers: HashMap<u8, BufWriter<File>> = HashMap::new();
+
+    for_each_row(conn, filter, |packet_type, row| {
+        let id = u8::from(packet_type);
+
+        let writer = match writers.get_mut(&id) {
+            Some(w) => w,
+            None => {
+                let path = output_dir.join(format!("{}.jsonl", packet_type.name()));
+                info!("Writing {}", path.display());
+
+                writers
+                    .entry(id)
+                    .or_insert(BufWriter::new(File::create(path)?))
+            }
+        };
+
+        serde_json::to_writer(&mut *writer, &row)?;
+        writer.write_all(b"\n")?;
+
+        Ok(())
+    })?;
+
+    for w in writers.values_mut() {
+        w.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Name of the field holding one entry per car, for packets that have one.
+fn car_array_field(packet: &Packet) -> Option<&'static str> {
+    match packet {
+        Packet::Motion(_) => Some("motion_data"),
+        Packet::LapData(_) => Some("lap_data"),
+        Packet::Participants(_) => Some("participants"),
+        Packet::CarSetups(_) => Some("car_setups"),
+        Packet::CarTelemetry(_) => Some("car_telemetry_data"),
+        Packet::CarStatus(_) => Some("car_status_data"),
+        Packet::FinalClassification(_) => Some("final_classifications"),
+        Packet::LobbyInfo(_) => Some("players"),
+        Packet::CarDamage(_) => Some("car_damage_data"),
+        _ => None,
+    }
+}
+
+/// Index of the car a packet is about, for packets without a per-car array.
+fn packet_car_index(packet: &Packet) -> Option<u8> {
+    match packet {
+        Packet::Event(e) => e.event.vehicle_idx(),
+        Packet::SessionHistory(h) => Some(h.car_index),
+        Packet::TyreSets(t) => Some(t.car_index),
+        Packet::MotionEx(m) => Some(m.header.player_car_index),
+        _ => None,
+    }
+}
+
+/// Flatten a packet into rows: one per car for packets holding data for every car, a single one
+/// otherwise.
+pub(crate) fn packet_rows(
+    timestamp: f64,
+    packet: &Packet,
+    filter: &ExportFilter,
+) -> Result<Vec<Row>> {
+    let Value::Object(fields) = serde_json::to_value(packet)? else {
+        unreachable!("packets are always serialized as objects")
+    };
+
+    let car_array = car_array_field(packet);
+    let mut cars = None;
+
+    let mut base = Row::new();
+    base.insert("timestamp".to_string(), timestamp.into());
+
+    for (key, value) in fields {
+        if key == "packet_type" {
+            // Already part of the header
+            continue;
+        } else if Some(key.as_str()) == car_array {
+            cars = Some(value);
+        } else if key == "header" {
+            flatten("", value, &mut base);
+            // Session UIDs don't always fit in a signed 64-bit integer, use the same
+            // representation as the database instead.
+            let session_uid = format_session_uid(packet.header().session_uid);
+            base.insert("session_uid".to_string(), session_uid.into());
+        } else {
+            flatten(&key, value, &mut base);
+        }
+    }
+
+    let rows = match cars {
+        Some(Value::Array(cars)) => cars
+            .into_iter()
+            .enumerate()
+            .filter(|(idx, _)| filter.accepts_car(Some(*idx as u8)))
+            .map(|(idx, car)| {
+                let mut row = base.clone();
+                row.insert("car_index".to_string(), idx.into());
+                flatten("", car, &mut row);
+                row
+            })
+            .collect(),
+        _ if filter.accepts_car(packet_car_index(packet)) => vec![base],
+        _ => vec![],
+    };
+
+    Ok(rows)
+}
+
+const WHEELS: [(&str, &str); 4] = [
+    ("front_left", "fl"),
+    ("front_right", "fr"),
+    ("rear_left", "rl"),
+    ("rear_right", "rr"),
+];
+
+fn is_wheel_data(fields: &Map<String, Value>) -> bool {
+    fields.len() == WHEELS.len() && WHEELS.iter().all(|(w, _)| fields.contains_key(*w))
+}
+
+fn flatten(prefix: &str, value: Value, row: &mut Row) {
+    let column = |key: &str| {
+        if prefix.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}_{}", prefix, key)
+        }
+    };
+
+    match value {
+        Value::Object(mut fields) if is_wheel_data(&fields) => {
+            for (wheel, suffix) in WHEELS {
+                row.insert(column(suffix), fields.remove(wheel).unwrap_or_default());
+            }
+        }
+        Value::Object(fields) => {
+            for (key, value) in fields {
+                flatten(&column(&key), value, row);
+            }
+        }
+        value => {
+            row.insert(prefix.to_string(), value);
+        }
+    }
+}
+
+fn csv_value(value: Option<&Value>) -> String {
+    match value {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(s)) => s.clone(),
+        Some(v) => v.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test_flatten {
+    use serde_json::{json, Value};
+
+    use super::{flatten, Row};
+
+    #[test]
+    fn test_nested_objects_are_prefixed() {
+        let mut row = Row::new();
+
+        flatten("", json!({"a": 1, "b": {"c": true, "d": "x"}}), &mut row);
+
+        assert_eq!(Value::from(row), json!({"a": 1, "b_c": true, "b_d": "x"}));
+    }
+
+    #[test]
+    fn test_wheel_data_is_expanded() {
+        let mut row = Row::new();
+        let wheels = json!({"rear_left": 1, "rear_right": 2, "front_left": 3, "front_right": 4});
+
+        flatten("tyres_wear", wheels, &mut row);
+
+        assert_eq!(
+            row.keys().collect::<Vec<_>>(),
+            vec![
+                "tyres_wear_fl",
+                "tyres_wear_fr",
+                "tyres_wear_rl",
+                "tyres_wear_rr"
+            ]
+        );
+        assert_eq!(row["tyres_wear_fl"], json!(3));
+        assert_eq!(row["tyres_wear_rr"], json!(2));
+    }
+}
@@ -0,0 +1,366 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Result;
+use arrow_array::builder::{
+    BooleanBuilder, Float32Builder, Int64Builder, StringBuilder, StringDictionaryBuilder,
+    TimestampMicrosecondBuilder,
+};
+use arrow_array::types::Int32Type;
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use log::info;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use rusqlite::Connection;
+use serde_json::Value;
+
+use crate::ParquetCompression;
+
+use super::{for_each_row, ExportFilter, Row};
+
+/// Number of rows buffered before being written to the file
+const BATCH_SIZE: usize = 8192;
+
+pub(super) fn export_parquet(
+    conn: &Connection,
+    filter: &ExportFilter,
+    output_dir: &Path,
+    compression: ParquetCompression,
+) -> Result<()> {
+    // The schema of every packet type is inferred from the values in a first pass.
+    let mut schemas: HashMap<u8, Vec<(String, ColumnType)>> = HashMap::new();
+    let mut column_indices: HashMap<u8, HashMap<String, usize>> = HashMap::new();
+
+    for_each_row(conn, filter, |packet_type, row| {
+        let id = u8::from(packet_type);
+        let schema = schemas.entry(id).or_default();
+        let indices = column_indices.entry(id).or_default();
+
+        for (key, value) in row.iter() {
+            let column_type = ColumnType::of(key, value);
+
+            match indices.get(key) {
+                Some(&idx) => schema[idx].1 = schema[idx].1.merge(column_type),
+                None => {
+                    indices.insert(key.clone(), schema.len());
+                    schema.push((key.clone(), column_type));
+                }
+            }
+        }
+
+        Ok(())
+    })?;
+
+    let properties = WriterProperties::builder()
+        .set_compression(compression.into())
+        .build();
+    let mut writers: HashMap<u8, ParquetWriter> = HashMap::new();
+
+    for_each_row(conn, filter, |packet_type, row| {
+        let id = u8::from(packet_type);
+
+        let writer = match writers.get_mut(&id) {
+            Some(w) => w,
+            None => {
+                let path = output_dir.join(format!("{}.parquet", packet_type.name()));
+                info!("Writing {}", path.display());
+
+                let w = ParquetWriter::new(&path, &schemas[&id], properties.clone())?;
+                writers.entry(id).or_insert(w)
+            }
+        };
+
+        writer.append(&row)
+    })?;
+
+    for (_, w) in writers.drain() {
+        w.close()?;
+    }
+
+    Ok(())
+}
+
+impl From<ParquetCompression> for Compression {
+    fn from(value: ParquetCompression) -> Self {
+        match value {
+            ParquetCompression::None => Compression::UNCOMPRESSED,
+            ParquetCompression::Snappy => Compression::SNAPPY,
+            ParquetCompression::Zstd => Compression::ZSTD(ZstdLevel::default()),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum ColumnType {
+    /// Only null values seen so far
+    Null,
+    Timestamp,
+    Boolean,
+    Integer,
+    Float,
+    /// Enums and other strings, stored as dictionaries
+    String,
+    /// Nested arrays, stored as JSON strings
+    Json,
+}
+
+impl ColumnType {
+    fn of(column: &str, value: &Value) -> Self {
+        match value {
+            _ if column == "timestamp" => ColumnType::Timestamp,
+            Value::Null => ColumnType::Null,
+            Value::Bool(_) => ColumnType::Boolean,
+            Value::Number(n) if n.is_f64() => ColumnType::Float,
+            Value::Number(_) => ColumnType::Integer,
+            Value::String(_) => ColumnType::String,
+            Value::Array(_) | Value::Object(_) => ColumnType::Json,
+        }
+    }
+
+    fn merge(self, other: Self) -> Self {
+        match (self, other) {
+            (a, b) if a == b => a,
+            (ColumnType::Null, t) | (t, ColumnType::Null) => t,
+            (ColumnType::Integer, ColumnType::Float) | (ColumnType::Float, ColumnType::Integer) => {
+                ColumnType::Float
+            }
+            _ => ColumnType::Json,
+        }
+    }
+
+    fn data_type(self) -> DataType {
+        match self {
+            ColumnType::Timestamp => DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())),
+            ColumnType::Boolean => DataType::Boolean,
+            ColumnType::Integer => DataType::Int64,
+            // All floating point values in the packets are single precision
+            ColumnType::Float => DataType::Float32,
+            ColumnType::String => {
+                DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8))
+            }
+            ColumnType::Null | ColumnType::Json => DataType::Utf8,
+        }
+    }
+
+    fn builder(self) -> ColumnBuilder {
+        match self {
+            ColumnType::Timestamp => ColumnBuilder::Timestamp(TimestampMicrosecondBuilder::new()),
+            ColumnType::Boolean => ColumnBuilder::Boolean(BooleanBuilder::new()),
+            ColumnType::Integer => ColumnBuilder::Integer(Int64Builder::new()),
+            ColumnType::Float => ColumnBuilder::Float(Float32Builder::new()),
+            ColumnType::String => ColumnBuilder::Dictionary(StringDictionaryBuilder::new()),
+            ColumnType::Null | ColumnType::Json => ColumnBuilder::String(StringBuilder::new()),
+        }
+    }
+}
+
+enum ColumnBuilder {
+    Timestamp(TimestampMicrosecondBuilder),
+    Boolean(BooleanBuilder),
+    Integer(Int64Builder),
+    Float(Float32Builder),
+    Dictionary(StringDictionaryBuilder<Int32Type>),
+    String(StringBuilder),
+}
+
+impl ColumnBuilder {
+    fn append(&mut self, value: Option<&Value>) {
+        let value = value.filter(|v| !v.is_null());
+
+        match self {
+            ColumnBuilder::Timestamp(b) => {
+                b.append_option(value.and_then(Value::as_f64).map(|t| (t * 1e6) as i64))
+            }
+            ColumnBuilder::Boolean(b) => b.append_option(value.and_then(Value::as_bool)),
+            ColumnBuilder::Integer(b) => b.append_option(value.and_then(Value::as_i64)),
+            ColumnBuilder::Float(b) => {
+                b.append_option(value.and_then(Value::as_f64).map(|f| f as f32))
+            }
+            ColumnBuilder::Dictionary(b) => b.append_option(value.and_then(Value::as_str)),
+            ColumnBuilder::String(b) => b.append_option(value.map(|v| match v {
+                Value::String(s) => s.clone(),
+                v => v.to_string(),
+            })),
+        }
+    }
+
+    fn finish(&mut self) -> ArrayRef {
+        match self {
+            ColumnBuilder::Timestamp(b) => Arc::new(b.finish().with_timezone("UTC")),
+            ColumnBuilder::Boolean(b) => Arc::new(b.finish()),
+            ColumnBuilder::Integer(b) => Arc::new(b.finish()),
+            ColumnBuilder::Float(b) => Arc::new(b.finish()),
+            ColumnBuilder::Dictionary(b) => Arc::new(b.finish()),
+            ColumnBuilder::String(b) => Arc::new(b.finish()),
+        }
+    }
+}
+
+/// Writes the rows of a single packet type to a Parquet file, with one row group per session.
+struct ParquetWriter {
+    schema: SchemaRef,
+    columns: Vec<String>,
+    builders: Vec<ColumnBuilder>,
+    writer: ArrowWriter<File>,
+    buffered_rows: usize,
+    session_uid: Option<Value>,
+}
+
+impl ParquetWriter {
+    fn new(
+        path: &Path,
+        columns: &[(String, ColumnType)],
+        properties: WriterProperties,
+    ) -> Result<Self> {
+        let fields: Vec<Field> = columns
+            .iter()
+            .map(|(name, t)| Field::new(name, t.data_type(), true))
+            .collect();
+        let schema = Arc::new(Schema::new(fields));
+
+        let writer = ArrowWriter::try_new(File::create(path)?, schema.clone(), Some(properties))?;
+
+        Ok(Self {
+            schema,
+            columns: columns.iter().map(|(name, _)| name.clone()).collect(),
+            builders: columns.iter().map(|(_, t)| t.builder()).collect(),
+            writer,
+            buffered_rows: 0,
+            session_uid: None,
+        })
+    }
+
+    fn append(&mut self, row: &Row) -> Result<()> {
+        let session_uid = row.get("session_uid");
+        if self.session_uid.is_some() && self.session_uid.as_ref() != session_uid {
+            self.write_batch()?;
+            // Start a new row group for the new session
+            self.writer.flush()?;
+        }
+        self.session_uid = session_uid.cloned();
+
+        for (column, builder) in self.columns.iter().zip(self.builders.iter_mut()) {
+            builder.append(row.get(column));
+        }
+
+        self.buffered_rows += 1;
+        if self.buffered_rows >= BATCH_SIZE {
+            self.write_batch()?;
+        }
+
+        Ok(())
+    }
+
+    fn write_batch(&mut self) -> Result<()> {
+        if self.buffered_rows == 0 {
+            return Ok(());
+        }
+
+        let arrays: Vec<ArrayRef> = self.builders.iter_mut().map(|b| b.finish()).collect();
+        let batch = RecordBatch::try_new(self.schema.clone(), arrays)?;
+
+        self.writer.write(&batch)?;
+        self.buffered_rows = 0;
+
+        Ok(())
+    }
+
+    fn close(mut self) -> Result<()> {
+        self.write_batch()?;
+        self.writer.close()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_column_type {
+    use serde_json::json;
+
+    use super::ColumnType;
+
+    #[test]
+    fn test_column_type_of_values() {
+        assert_eq!(
+            ColumnType::of("timestamp", &json!(1.5)),
+            ColumnType::Timestamp
+        );
+        assert_eq!(ColumnType::of("speed", &json!(300)), ColumnType::Integer);
+        assert_eq!(ColumnType::of("steer", &json!(0.5)), ColumnType::Float);
+        assert_eq!(ColumnType::of("drs", &json!(true)), ColumnType::Boolean);
+        assert_eq!(
+            ColumnType::of("weather", &json!("Clear")),
+            ColumnType::String
+        );
+        assert_eq!(
+            ColumnType::of("marshal_zones", &json!([])),
+            ColumnType::Json
+        );
+        assert_eq!(
+            ColumnType::of("ai_difficulty", &json!(null)),
+            ColumnType::Null
+        );
+    }
+
+    #[test]
+    fn test_merge_column_types() {
+        assert_eq!(
+            ColumnType::Null.merge(ColumnType::Integer),
+            ColumnType::Integer
+        );
+        assert_eq!(
+            ColumnType::Integer.merge(ColumnType::Float),
+            ColumnType::Float
+        );
+        assert_eq!(
+            ColumnType::String.merge(ColumnType::Integer),
+            ColumnType::Json
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_parquet_writer {
+    use std::fs::File;
+
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use serde_json::json;
+
+    use super::{ColumnType, ParquetWriter};
+
+    #[test]
+    fn test_one_row_group_per_session() {
+        // Unique to this run, as other test runs may write to the same directory in parallel
+        let path = std::env::temp_dir().join(format!(
+            "f1-packet-recorder-test-row-groups-{}-{:016x}.parquet",
+            std::process::id(),
+            rand::random::<u64>()
+        ));
+        let columns = vec![
+            ("timestamp".to_string(), ColumnType::Timestamp),
+            ("session_uid".to_string(), ColumnType::String),
+            ("speed".to_string(), ColumnType::Integer),
+        ];
+
+        let mut writer = ParquetWriter::new(&path, &columns, WriterProperties::default()).unwrap();
+        for (session_uid, speed) in [("a", 100), ("a", 110), ("b", 120)] {
+            let row = json!({"timestamp": 1.0, "session_uid": session_uid, "speed": speed});
+            writer.append(row.as_object().unwrap()).unwrap();
+        }
+        writer.close().unwrap();
+
+        let reader = SerializedFileReader::new(File::open(&path).unwrap()).unwrap();
+        let metadata = reader.metadata();
+
+        assert_eq!(metadata.num_row_groups(), 2);
+        assert_eq!(metadata.row_group(0).num_rows(), 2);
+        assert_eq!(metadata.row_group(1).num_rows(), 1);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,555 @@
+//! Export of a single car's telemetry to the MoTeC i2 log format.
+//!
+//! Each session is written to a `.ld` file holding the channels, resampled at a fixed frequency,
+//! and a `.ldx` file holding the lap beacons.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use anyhow::Result;
+use log::{info, warn};
+use rusqlite::Connection;
+use time::macros::format_description;
+use time::OffsetDateTime;
+
+use f1_telemetry::packet::generic::WheelData;
+use f1_telemetry::packet::{parse_packet, Packet};
+
+use crate::db::for_each_packet;
+use crate::utils::format_session_uid;
+
+use super::ExportFilter;
+
+const HEADER_SIZE: u32 = 1762;
+const EVENT_SIZE: u32 = 1154;
+const VENUE_SIZE: u32 = 1100;
+const VEHICLE_SIZE: u32 = 260;
+const CHANNEL_HEADER_SIZE: u32 = 124;
+
+pub(super) fn export_motec(
+    conn: &Connection,
+    filter: &ExportFilter,
+    output_dir: &Path,
+    frequency: u16,
+) -> Result<()> {
+    let car_index = filter.cars.first().copied();
+    if filter.cars.len() > 1 {
+        warn!(
+            "MoTeC logs hold a single car, only exporting car {:?}",
+            car_index
+        );
+    }
+
+    let mut sessions: BTreeMap<u64, MotecSession> = BTreeMap::new();
+
+    for_each_packet(conn, |p| {
+        if !filter.accepts_source(&p.source) {
+            return Ok(());
+        }
+
+        let packet = match parse_packet(p.data.len(), &p.data) {
+            Ok(packet) => packet,
+            Err(_) => return Ok(()),
+        };
+
+        if !filter.accepts_packet(&packet) {
+            return Ok(());
+        }
+
+        let header = packet.header();
+        sessions
+            .entry(header.session_uid)
+            .or_insert_with(|| MotecSession::new(p.timestamp))
+            .update(&packet, car_index);
+
+        Ok(())
+    })?;
+
+    for (session_uid, session) in sessions {
+        let name = format_session_uid(session_uid).trim().to_string();
+        let path = output_dir.join(format!("{}.ld", name));
+
+        info!("Writing {}", path.display());
+        session.write_ld(&path, frequency)?;
+        session.write_ldx(&path.with_extension("ldx"))?;
+    }
+
+    Ok(())
+}
+
+struct Channel {
+    name: String,
+    short_name: String,
+    unit: &'static str,
+    /// Samples as (session time in seconds, value)
+    samples: Vec<(f64, f32)>,
+}
+
+struct MotecSession {
+    start_timestamp: f64,
+    driver: String,
+    vehicle: String,
+    venue: String,
+    session_type: String,
+    channels: Vec<Channel>,
+    channel_indices: HashMap<String, usize>,
+    /// Session time at which each lap started
+    lap_starts: Vec<(f64, u8)>,
+}
+
+impl MotecSession {
+    fn new(start_timestamp: f64) -> Self {
+        Self {
+            start_timestamp,
+            driver: String::new(),
+            vehicle: String::new(),
+            venue: String::new(),
+            session_type: String::new(),
+            channels: Vec::new(),
+            channel_indices: HashMap::new(),
+            lap_starts: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, time: f64, name: &str, short_name: &str, unit: &'static str, value: f32) {
+        let idx = match self.channel_indices.get(name) {
+            Some(&idx) => idx,
+            None => {
+                self.channels.push(Channel {
+                    name: name.to_string(),
+                    short_name: short_name.to_string(),
+                    unit,
+                    samples: Vec::new(),
+                });
+                self.channel_indices
+                    .insert(name.to_string(), self.channels.len() - 1);
+                self.channels.len() - 1
+            }
+        };
+
+        let samples = &mut self.channels[idx].samples;
+        // After a flashback, the session time goes back: drop the samples that were undone.
+        while samples.last().is_some_and(|(t, _)| *t >= time) {
+            samples.pop();
+        }
+        samples.push((time, value));
+    }
+
+    fn push_wheels<T>(
+        &mut self,
+        time: f64,
+        name: &str,
+        short_name: &str,
+        unit: &'static str,
+        wheels: WheelData<T>,
+        convert: fn(T) -> f32,
+    ) where
+        T: Copy,
+    {
+        let values = [
+            ("FL", wheels.front_left),
+            ("FR", wheels.front_right),
+            ("RL", wheels.rear_left),
+            ("RR", wheels.rear_right),
+        ];
+
+        for (suffix, value) in values {
+            self.push(
+                time,
+                &format!("{} {}", name, suffix),
+                &format!("{}{}", short_name, suffix),
+                unit,
+                convert(value),
+            );
+        }
+    }
+
+    fn update(&mut self, packet: &Packet, car_index: Option<u8>) {
+        let header = packet.header();
+        let car = car_index.unwrap_or(header.player_car_index) as usize;
+        let is_player_car = car == header.player_car_index as usize;
+        let t = header.session_time as f64 / 1000.0;
+
+        match packet {
+            Packet::Session(s) => {
+                self.venue = s.track.name().to_string();
+                self.session_type = s.session_type.name().to_string();
+            }
+            Packet::Participants(p) => {
+                if let Some(participant) = p.participants.get(car) {
+                    self.driver = participant.name.clone();
+                    self.vehicle = format!("{:?}", participant.team);
+                }
+            }
+            Packet::CarTelemetry(p) => {
+                let Some(c) = p.car_telemetry_data.get(car) else {
+                    return;
+                };
+
+                self.push(t, "Ground Speed", "Speed", "km/h", c.speed as f32);
+                self.push(t, "Throttle Pos", "Thr", "%", c.throttle * 100.0);
+                self.push(t, "Brake Pos", "Brk", "%", c.brake * 100.0);
+                self.push(t, "Steering", "Steer", "", c.steer);
+                self.push(t, "Clutch Pos", "Clu", "%", c.clutch as f32);
+                self.push(t, "Gear", "Gear", "", c.gear as f32);
+                self.push(t, "Engine RPM", "RPM", "rpm", c.engine_rpm as f32);
+                self.push(t, "DRS", "DRS", "", c.drs as u8 as f32);
+                self.push(t, "Engine Temp", "EngT", "C", c.engine_temperature as f32);
+                self.push_wheels(
+                    t,
+                    "Brake Temp",
+                    "BrkT",
+                    "C",
+                    c.brakes_temperature,
+                    f32::from,
+                );
+                self.push_wheels(
+                    t,
+                    "Tyre Surface Temp",
+                    "TyrS",
+                    "C",
+                    c.tyres_surface_temperature,
+                    f32::from,
+                );
+                self.push_wheels(
+                    t,
+                    "Tyre Inner Temp",
+                    "TyrI",
+                    "C",
+                    c.tyres_inner_temperature,
+                    f32::from,
+                );
+                self.push_wheels(t, "Tyre Pressure", "TyrP", "psi", c.tyre_pressures, |v| v);
+            }
+            Packet::Motion(p) => {
+                if let Some(m) = p.motion_data.get(car) {
+                    self.push(t, "Pos X", "PosX", "m", m.world_position_x);
+                    self.push(t, "Pos Y", "PosY", "m", m.world_position_y);
+                    self.push(t, "Pos Z", "PosZ", "m", m.world_position_z);
+                    self.push(t, "Vel X", "VelX", "m/s", m.world_velocity_x);
+                    self.push(t, "Vel Y", "VelY", "m/s", m.world_velocity_y);
+                    self.push(t, "Vel Z", "VelZ", "m/s", m.world_velocity_z);
+                    self.push(t, "G Force Lat", "GLat", "G", m.g_force_lateral);
+                    self.push(t, "G Force Long", "GLong", "G", m.g_force_longitudinal);
+                    self.push(t, "G Force Vert", "GVert", "G", m.g_force_vertical);
+                    self.push(t, "Yaw", "Yaw", "rad", m.yaw);
+                    self.push(t, "Pitch", "Pitch", "rad", m.pitch);
+                    self.push(t, "Roll", "Roll", "rad", m.roll);
+                }
+
+                // Before F1 23, the extended motion data of the player is part of this packet
+                if let (Some(p), true) = (&p.player_car_data, is_player_car) {
+                    self.push_wheels(t, "Susp Pos", "SusP", "mm", p.suspension_position, |v| v);
+                    self.push_wheels(t, "Susp Vel", "SusV", "mm/s", p.suspension_velocity, |v| v);
+                    self.push_wheels(t, "Wheel Speed", "WSpd", "m/s", p.wheel_speed, |v| v);
+                    self.push_wheels(t, "Wheel Slip", "WSlp", "", p.wheel_slip, |v| v);
+                    self.push(t, "Front Wheels Angle", "FWA", "rad", p.front_wheels_angle);
+                }
+            }
+            Packet::MotionEx(p) if is_player_car => {
+                self.push_wheels(t, "Susp Pos", "SusP", "mm", p.suspension_position, |v| v);
+                self.push_wheels(t, "Susp Vel", "SusV", "mm/s", p.suspension_velocity, |v| v);
+                self.push_wheels(t, "Wheel Speed", "WSpd", "m/s", p.wheel_speed, |v| v);
+                self.push_wheels(t, "Wheel Slip", "WSlp", "", p.wheel_slip_ratio, |v| v);
+                self.push_wheels(
+                    t,
+                    "Wheel Slip Angle",
+                    "WSlA",
+                    "rad",
+                    p.wheel_slip_angle,
+                    |v| v,
+                );
+                self.push_wheels(t, "Wheel Lat Force", "WLat", "N", p.wheel_lat_force, |v| v);
+                self.push_wheels(
+                    t,
+                    "Wheel Long Force",
+                    "WLng",
+                    "N",
+                    p.wheel_long_force,
+                    |v| v,
+                );
+                self.push(t, "Front Wheels Angle", "FWA", "rad", p.front_wheels_angle);
+                self.push(t, "CoG Height", "CoGH", "m", p.height_of_center_of_gravity);
+            }
+            Packet::LapData(p) => {
+                let Some(l) = p.lap_data.get(car) else {
+                    return;
+                };
+
+                self.push(t, "Lap Distance", "LapD", "m", l.lap_distance);
+                self.push(t, "Lap Number", "Lap", "", l.current_lap_num as f32);
+                self.push(
+                    t,
+                    "Lap Time",
+                    "LapT",
+                    "s",
+                    l.current_lap_time as f32 / 1000.0,
+                );
+                self.push(t, "Position", "Pos", "", l.car_position as f32);
+
+                while self.lap_starts.last().is_some_and(|(start, _)| *start > t) {
+                    self.lap_starts.pop();
+                }
+                let last_lap = self.lap_starts.last().map(|(_, lap)| *lap);
+                if last_lap != Some(l.current_lap_num) {
+                    let lap_start = t - l.current_lap_time as f64 / 1000.0;
+                    self.lap_starts
+                        .push((lap_start.max(0.0), l.current_lap_num));
+                }
+            }
+            Packet::CarStatus(p) => {
+                let Some(s) = p.car_status_data.get(car) else {
+                    return;
+                };
+
+                self.push(t, "Fuel Level", "Fuel", "kg", s.fuel_in_tank);
+                self.push(t, "ERS Store", "ERS", "MJ", s.ers_store_energy / 1e6);
+                self.push(t, "Brake Bias", "Bias", "%", s.front_brake_bias as f32);
+                if let Some(wear) = s.tyres_wear {
+                    self.push_wheels(t, "Tyre Wear", "TyrW", "%", wear, f32::from);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Time span of the session, in session time
+    fn time_range(&self) -> Option<(f64, f64)> {
+        let samples = self.channels.iter().flat_map(|c| c.samples.iter());
+
+        samples.fold(None, |range, (t, _)| match range {
+            None => Some((*t, *t)),
+            Some((start, end)) => Some((start.min(*t), end.max(*t))),
+        })
+    }
+
+    fn write_ld(&self, path: &Path, frequency: u16) -> Result<()> {
+        let (start, end) = self.time_range().unwrap_or_default();
+        let num_samples = ((end - start) * frequency as f64).floor() as u32 + 1;
+
+        let event_ptr = HEADER_SIZE;
+        let venue_ptr = event_ptr + EVENT_SIZE;
+        let vehicle_ptr = venue_ptr + VENUE_SIZE;
+        let meta_ptr = vehicle_ptr + VEHICLE_SIZE;
+        let data_ptr = meta_ptr + CHANNEL_HEADER_SIZE * self.channels.len() as u32;
+
+        let datetime = OffsetDateTime::from_unix_timestamp(self.start_timestamp as i64)?;
+        let date = datetime.format(format_description!("[day]/[month]/[year]"))?;
+        let time = datetime.format(format_description!("[hour]:[minute]:[second]"))?;
+
+        let mut buf = LdBuffer::default();
+
+        // Header
+        buf.u32(0x40);
+        buf.zeros(4);
+        buf.u32(meta_ptr);
+        buf.u32(data_ptr);
+        buf.zeros(20);
+        buf.u32(event_ptr);
+        buf.zeros(24);
+        buf.u16(1);
+        buf.u16(0x4240);
+        buf.u16(0xf);
+        buf.u32(0x1f44);
+        buf.str("ADL", 8);
+        buf.u16(420);
+        buf.u16(0xadb0);
+        buf.u32(self.channels.len() as u32);
+        buf.zeros(4);
+        buf.str(&date, 16);
+        buf.zeros(16);
+        buf.str(&time, 16);
+        buf.zeros(16);
+        buf.str(&self.driver, 64);
+        buf.str(&self.vehicle, 64);
+        buf.zeros(64);
+        buf.str(&self.venue, 64);
+        buf.zeros(64);
+        buf.zeros(1024);
+        buf.u32(0xc81a4);
+        buf.zeros(66);
+        buf.str(&self.session_type, 64);
+        buf.zeros(126);
+
+        // Event
+        buf.str(&self.session_type, 64);
+        buf.str(&self.session_type, 64);
+        buf.str("", 1024);
+        buf.u16(venue_ptr as u16);
+
+        // Venue
+        buf.str(&self.venue, 64);
+        buf.zeros(1034);
+        buf.u16(vehicle_ptr as u16);
+
+        // Vehicle
+        buf.str(&self.vehicle, 64);
+        buf.zeros(128);
+        buf.u32(0);
+        buf.str("", 32);
+        buf.str("", 32);
+
+        // Channel headers, as a linked list
+        for (idx, channel) in self.channels.iter().enumerate() {
+            let idx = idx as u32;
+            let this_ptr = meta_ptr + idx * CHANNEL_HEADER_SIZE;
+            let prev_ptr = if idx == 0 {
+                0
+            } else {
+                this_ptr - CHANNEL_HEADER_SIZE
+            };
+            let next_ptr = if idx as usize == self.channels.len() - 1 {
+                0
+            } else {
+                this_ptr + CHANNEL_HEADER_SIZE
+            };
+
+            buf.u32(prev_ptr);
+            buf.u32(next_ptr);
+            buf.u32(data_ptr + idx * num_samples * 4);
+            buf.u32(num_samples);
+            buf.u16(0x2ee1);
+            // 32-bit floats
+            buf.u16(0x07);
+            buf.u16(4);
+            buf.u16(frequency);
+            // shift, multiplier, scale and decimal places
+            buf.u16(0);
+            buf.u16(1);
+            buf.u16(1);
+            buf.u16(0);
+            buf.str(&channel.name, 32);
+            buf.str(&channel.short_name, 8);
+            buf.str(channel.unit, 12);
+            buf.zeros(40);
+        }
+
+        // Channel data
+        for channel in &self.channels {
+            for value in resample(&channel.samples, start, frequency, num_samples) {
+                buf.f32(value);
+            }
+        }
+
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&buf.0)?;
+        file.flush()?;
+
+        Ok(())
+    }
+
+    fn write_ldx(&self, path: &Path) -> Result<()> {
+        let (start, _) = self.time_range().unwrap_or_default();
+        let mut file = BufWriter::new(File::create(path)?);
+
+        writeln!(file, "<?xml version=\"1.0\"?>")?;
+        writeln!(
+            file,
+            "<LDXFile Locale=\"English_United States.1252\" DefaultLocale=\"C\" Version=\"1.6\">"
+        )?;
+        writeln!(file, " <Layers>")?;
+        writeln!(file, "  <Layer>")?;
+        writeln!(file, "   <MarkerBlock>")?;
+        writeln!(file, "    <MarkerGroup Name=\"Beacons\" Index=\"3\">")?;
+        // The first lap starts with the log, only the following ones need a beacon
+        for (idx, (lap_start, _)) in self.lap_starts.iter().skip(1).enumerate() {
+            writeln!(
+                file,
+                "     <Marker Version=\"100\" ClassName=\"BCN\" Name=\"Manual.{}\" Flags=\"77\" Time=\"{:.6}\"/>",
+                idx + 1,
+                (lap_start - start).max(0.0) * 1e6
+            )?;
+        }
+        writeln!(file, "    </MarkerGroup>")?;
+        writeln!(file, "   </MarkerBlock>")?;
+        writeln!(file, "   <RangeBlock/>")?;
+        writeln!(file, "  </Layer>")?;
+        writeln!(file, "  <Details>")?;
+        writeln!(
+            file,
+            "   <String Id=\"Total Laps\" Value=\"{}\"/>",
+            self.lap_starts.len()
+        )?;
+        writeln!(file, "  </Details>")?;
+        writeln!(file, " </Layers>")?;
+        writeln!(file, "</LDXFile>")?;
+
+        file.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Resample a channel at a fixed frequency, holding the last known value between samples.
+fn resample(samples: &[(f64, f32)], start: f64, frequency: u16, num_samples: u32) -> Vec<f32> {
+    let mut values = Vec::with_capacity(num_samples as usize);
+    let mut idx = 0;
+
+    for i in 0..num_samples {
+        let t = start + i as f64 / frequency as f64;
+        while idx + 1 < samples.len() && samples[idx + 1].0 <= t {
+            idx += 1;
+        }
+
+        values.push(samples.get(idx).map(|(_, v)| *v).unwrap_or_default());
+    }
+
+    values
+}
+
+/// Little-endian buffer for the `.ld` binary format
+#[derive(Default)]
+struct LdBuffer(Vec<u8>);
+
+impl LdBuffer {
+    fn u16(&mut self, v: u16) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn f32(&mut self, v: f32) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn zeros(&mut self, len: usize) {
+        self.0.resize(self.0.len() + len, 0);
+    }
+
+    /// Fixed length string, truncated or padded with zeros
+    fn str(&mut self, s: &str, len: usize) {
+        let bytes = s.as_bytes();
+        let n = bytes.len().min(len);
+
+        self.0.extend_from_slice(&bytes[..n]);
+        self.zeros(len - n);
+    }
+}
+
+#[cfg(test)]
+mod test_motec {
+    use super::{resample, LdBuffer};
+
+    #[test]
+    fn test_resample_holds_last_value() {
+        let samples = vec![(0.0, 1.0), (0.5, 2.0), (1.0, 3.0)];
+
+        let actual = resample(&samples, 0.0, 4, 5);
+
+        assert_eq!(actual, vec![1.0, 1.0, 2.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn test_fixed_length_strings_are_padded_and_truncated() {
+        let mut buf = LdBuffer::default();
+
+        buf.str("abc", 5);
+        buf.str("abcdef", 4);
+
+        assert_eq!(buf.0, b"abc\0\0abcd");
+    }
+}
@@ -0,0 +1,89 @@
+//! Packets and recordings shared by the tests.
+
+use std::path::PathBuf;
+
+use rusqlite::Connection;
+
+use f1_telemetry::packet::parse_packet;
+
+use crate::db::{init_db, PacketSource, StoredPacket};
+use crate::edit::Output;
+use crate::recorder::{insert_packets, TimestampedPacket};
+
+/// 2021 LapData packet, with the player on `lap`
+pub(crate) fn lap_data_2021(session_uid: u64, session_time: f32, frame: u32, lap: u8) -> Vec<u8> {
+    let mut packet = vec![0u8; 970];
+    packet[0..2].copy_from_slice(&2021u16.to_le_bytes());
+    packet[4] = 1;
+    packet[5] = 2;
+    packet[6..14].copy_from_slice(&session_uid.to_le_bytes());
+    packet[14..18].copy_from_slice(&session_time.to_le_bytes());
+    packet[18..22].copy_from_slice(&frame.to_le_bytes());
+    // Current lap of the first car, the player's
+    packet[24 + 25] = lap;
+
+    packet
+}
+
+/// A lap of session 0xaaaa per 10 seconds of session time, one packet per second from
+/// timestamp 1000, with the given laps
+pub(crate) fn laps(laps: std::ops::RangeInclusive<u8>) -> Vec<(f64, Vec<u8>)> {
+    laps.flat_map(|lap| {
+        (0..10).map(move |second| {
+            let time = (lap as u32 - 1) * 10 + second;
+            let data = lap_data_2021(0xaaaa, time as f32, time * 60, lap);
+            (1000.0 + time as f64, data)
+        })
+    })
+    .collect()
+}
+
+/// In-memory recording of timestamped packets
+pub(crate) fn memory_recording(packets: &[(f64, Vec<u8>)]) -> Connection {
+    let conn = Connection::open_in_memory().unwrap();
+    init_db(&conn).unwrap();
+
+    let packets: Vec<TimestampedPacket> = packets
+        .iter()
+        .map(|(timestamp, data)| TimestampedPacket {
+            timestamp: *timestamp,
+            source: PacketSource::default(),
+            packet: parse_packet(data.len(), data).unwrap(),
+            data: data.clone(),
+        })
+        .collect();
+    insert_packets(&conn, &packets.iter().collect::<Vec<_>>()).unwrap();
+
+    conn
+}
+
+/// Recording of timestamped packets written to a new file, as the editing commands do
+pub(crate) fn file_recording(name: &str, packets: &[(f64, Vec<u8>)]) -> String {
+    let file = temp_file(name);
+    let mut output = Output::create(&file).unwrap();
+    for (timestamp, data) in packets {
+        output
+            .push(StoredPacket {
+                timestamp: *timestamp,
+                source: PacketSource::default(),
+                data: data.clone(),
+            })
+            .unwrap();
+    }
+    output.finish().unwrap();
+
+    file
+}
+
+/// Path of a database file unique to this run, as other test runs may use the same directory in
+/// parallel
+pub(crate) fn temp_file(name: &str) -> String {
+    let path: PathBuf = std::env::temp_dir().join(format!(
+        "f1-packet-recorder-test-{}-{}-{:016x}.db",
+        name,
+        std::process::id(),
+        rand::random::<u64>()
+    ));
+
+    path.to_string_lossy().into_owned()
+}
@@ -0,0 +1,426 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use f1_telemetry::packet::{parse_packet, Packet};
+
+use crate::binlog::{self, is_binlog};
+use crate::recording::Recording;
+use crate::selection::Selection;
+use crate::utils::format_session_uid;
+
+use super::InfoArgs;
+
+pub(crate) fn info(args: &InfoArgs) -> Result<()> {
+    let info = RecordingInfo::from_file(&args.file, args.source.as_deref())?;
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&info)?);
+    } else {
+        info.print();
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Default, Serialize)]
+struct PacketTypeStats {
+    count: u64,
+    /// Average number of packets per second over the whole recording
+    rate: f64,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct SessionInfo {
+    session_uid: String,
+    /// Label of the source the packets were recorded from
+    source_label: Option<String>,
+    track: Option<String>,
+    session_type: Option<String>,
+    formula: Option<String>,
+    start_time: f64,
+    end_time: f64,
+    duration: f64,
+    /// Number of laps started by the player
+    laps: u8,
+    participants: Vec<String>,
+    packet_count: u64,
+    /// Number of times the frame identifier skipped ahead by more than one frame
+    frame_gaps: u64,
+    largest_frame_gap: u32,
+
+    #[serde(skip)]
+    last_frame: Option<u32>,
+}
+
+impl SessionInfo {
+    fn new(session_uid: u64, source_label: Option<String>, timestamp: f64) -> Self {
+        Self {
+            session_uid: format_session_uid(session_uid),
+            source_label,
+            start_time: timestamp,
+            end_time: timestamp,
+            ..Default::default()
+        }
+    }
+
+    fn update(&mut self, timestamp: f64, packet: &Packet) {
+        let header = packet.header();
+
+        self.packet_count += 1;
+        self.start_time = self.start_time.min(timestamp);
+        self.end_time = self.end_time.max(timestamp);
+        self.duration = self.end_time - self.start_time;
+
+        let frame = header.frame_identifier;
+        if let Some(last_frame) = self.last_frame {
+            if frame > last_frame + 1 {
+                self.frame_gaps += 1;
+                self.largest_frame_gap = self.largest_frame_gap.max(frame - last_frame - 1);
+            }
+        }
+        self.last_frame = Some(frame);
+
+        match packet {
+            Packet::Session(s) => {
+                self.track = Some(format!("{:?}", s.track));
+                self.session_type = Some(format!("{:?}", s.session_type));
+                self.formula = Some(format!("{:?}", s.formula));
+            }
+            Packet::Participants(p) => {
+                self.participants = p
+                    .participants
+                    .iter()
+                    .take(p.num_active_cars as usize)
+                    .map(|p| p.name.clone())
+                    .collect();
+            }
+            Packet::LapData(l) => {
+                if let Some(lap) = l.lap_data.get(header.player_car_index as usize) {
+                    self.laps = self.laps.max(lap.current_lap_num);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Index of a binary log
+#[derive(Debug, Default, Serialize)]
+struct BinlogInfo {
+    /// The log was closed properly, so its index covers every packet
+    closed: bool,
+    index_blocks: usize,
+    indexed_packets: u64,
+}
+
+impl BinlogInfo {
+    fn from_file(file: &str) -> Result<Self> {
+        let Some(blocks) = binlog::index(file)? else {
+            return Ok(Self::default());
+        };
+
+        Ok(Self {
+            closed: true,
+            index_blocks: blocks.len(),
+            indexed_packets: blocks.iter().map(|b| b.packets as u64).sum(),
+        })
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+struct RecordingInfo {
+    file: String,
+    /// Set for binary logs
+    binlog: Option<BinlogInfo>,
+    game_versions: BTreeSet<String>,
+    start_time: Option<f64>,
+    end_time: Option<f64>,
+    duration: f64,
+    packet_count: u64,
+    packet_types: BTreeMap<String, PacketTypeStats>,
+    sessions: Vec<SessionInfo>,
+    unparseable_packets: u64,
+    /// Parse errors, with the number of packets affected by each
+    parse_errors: BTreeMap<String, u64>,
+}
+
+impl RecordingInfo {
+    /// Summarize a recording, or only the packets recorded from the given source label.
+    fn from_file(file: &str, source: Option<&str>) -> Result<Self> {
+        let recording = Recording::open(file)?;
+
+        let mut info = Self {
+            file: file.to_string(),
+            binlog: match is_binlog(file)? {
+                true => Some(BinlogInfo::from_file(file)?),
+                false => None,
+            },
+            ..Default::default()
+        };
+        // Each source of a session has its own frame identifiers and player
+        let mut sessions: BTreeMap<(u64, Option<String>), SessionInfo> = BTreeMap::new();
+
+        for (error, count) in recording.count_unparseable_by_error(source)? {
+            info.unparseable_packets += count;
+            *info.parse_errors.entry(error).or_default() += count;
+        }
+
+        let selection = Selection {
+            source: source.map(str::to_string),
+            ..Default::default()
+        };
+        recording.for_each_selected(&selection, 0, |p| {
+            info.packet_count += 1;
+            info.start_time = Some(info.start_time.map_or(p.timestamp, |t| t.min(p.timestamp)));
+            info.end_time = Some(info.end_time.map_or(p.timestamp, |t| t.max(p.timestamp)));
+
+            let packet = match parse_packet(p.data.len(), &p.data) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    info.unparseable_packets += 1;
+                    *info.parse_errors.entry(e.0).or_default() += 1;
+                    return Ok(true);
+                }
+            };
+
+            let header = packet.header();
+            info.game_versions.insert(format!(
+                "{} v{}.{:02}",
+                header.packet_format, header.game_major_version, header.game_minor_version
+            ));
+            info.packet_types
+                .entry(format!("{:?}", header.packet_type))
+                .or_default()
+                .count += 1;
+
+            sessions
+                .entry((header.session_uid, p.source.label.clone()))
+                .or_insert_with(|| {
+                    SessionInfo::new(header.session_uid, p.source.label.clone(), p.timestamp)
+                })
+                .update(p.timestamp, &packet);
+
+            Ok(true)
+        })?;
+
+        if let (Some(start), Some(end)) = (info.start_time, info.end_time) {
+            info.duration = end - start;
+        }
+        for stats in info.packet_types.values_mut() {
+            if info.duration > 0.0 {
+                stats.rate = stats.count as f64 / info.duration;
+            }
+        }
+
+        info.sessions = sessions.into_values().collect();
+        info.sessions
+            .sort_by(|a, b| a.start_time.total_cmp(&b.start_time));
+
+        Ok(info)
+    }
+
+    fn print(&self) {
+        println!("File:              {}", self.file);
+        match &self.binlog {
+            Some(binlog) if binlog.closed => println!(
+                "Format:            binary log ({} packets in {} index blocks)",
+                binlog.indexed_packets, binlog.index_blocks
+            ),
+            Some(_) => println!("Format:            binary log (not closed, no index)"),
+            None => println!("Format:            database"),
+        }
+        println!(
+            "Game versions:     {}",
+            self.game_versions
+                .iter()
+                .cloned()
+                .collect::<Vec<String>>()
+                .join(", ")
+        );
+        println!("Duration:          {}", format_duration(self.duration));
+        println!("Packets:           {}", self.packet_count);
+        println!("Unparseable:       {}", self.unparseable_packets);
+        for (error, count) in &self.parse_errors {
+            println!("    {:>8}  {}", count, error);
+        }
+
+        println!();
+        println!("Packet types:");
+        for (packet_type, stats) in &self.packet_types {
+            println!(
+                "    {:<20} {:>10} {:>8.2}/s",
+                packet_type, stats.count, stats.rate
+            );
+        }
+
+        for session in &self.sessions {
+            println!();
+            match &session.source_label {
+                Some(label) => println!("Session {} from {}:", session.session_uid, label),
+                None => println!("Session {}:", session.session_uid),
+            }
+            println!(
+                "    Track:         {}",
+                session.track.as_deref().unwrap_or("-")
+            );
+            println!(
+                "    Session type:  {}",
+                session.session_type.as_deref().unwrap_or("-")
+            );
+            println!(
+                "    Formula:       {}",
+                session.formula.as_deref().unwrap_or("-")
+            );
+            println!("    Duration:      {}", format_duration(session.duration));
+            println!("    Laps:          {}", session.laps);
+            println!("    Packets:       {}", session.packet_count);
+            println!(
+                "    Frame gaps:    {} (largest: {} frames)",
+                session.frame_gaps, session.largest_frame_gap
+            );
+            println!("    Participants:  {}", session.participants.join(", "));
+        }
+    }
+}
+
+fn format_duration(seconds: f64) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+#[cfg(test)]
+mod test_recording_info {
+    use std::fs::remove_file;
+
+    use rusqlite::Connection;
+
+    use super::RecordingInfo;
+    use crate::db::PacketSource;
+    use crate::fixtures::{file_recording, lap_data_2021};
+    use crate::quarantine::{insert_quarantined, QuarantinedPacket};
+    use crate::utils::format_session_uid;
+
+    /// Two sessions, the first one missing frames 3 and 4, and two unparseable packets
+    fn recording() -> String {
+        let mut packets = vec![];
+        for (idx, frame) in [0, 1, 2, 5, 6].into_iter().enumerate() {
+            let data = lap_data_2021(0xaaaa, frame as f32 / 60.0, frame, 2);
+            packets.push((1000.0 + idx as f64, data));
+        }
+        for frame in [0, 1] {
+            let data = lap_data_2021(0xbbbb, frame as f32 / 60.0, frame, 1);
+            packets.push((2000.0 + frame as f64, data));
+        }
+        let file = file_recording("info", &packets);
+
+        let quarantined: Vec<QuarantinedPacket> = (0..2)
+            .map(|i| QuarantinedPacket {
+                timestamp: 1500.0 + i as f64,
+                source: PacketSource::default(),
+                error: "Invalid packet".to_string(),
+                data: vec![0; 10],
+            })
+            .collect();
+        insert_quarantined(&Connection::open(&file).unwrap(), &quarantined).unwrap();
+
+        file
+    }
+
+    #[test]
+    fn test_recording_info() {
+        let file = recording();
+        let info = RecordingInfo::from_file(&file, None).unwrap();
+        remove_file(&file).unwrap();
+
+        assert_eq!(info.packet_count, 7);
+        assert_eq!(info.duration, 1001.0);
+        assert_eq!(info.packet_types["LapData"].count, 7);
+        assert_eq!(info.unparseable_packets, 2);
+        assert_eq!(info.parse_errors["Invalid packet"], 2);
+
+        let sessions: Vec<_> = info
+            .sessions
+            .iter()
+            .map(|s| {
+                (
+                    s.session_uid.clone(),
+                    s.packet_count,
+                    s.laps,
+                    s.frame_gaps,
+                    s.largest_frame_gap,
+                )
+            })
+            .collect();
+        assert_eq!(
+            sessions,
+            vec![
+                (format_session_uid(0xaaaa), 5, 2, 1, 2),
+                (format_session_uid(0xbbbb), 2, 1, 0, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_json() {
+        let file = recording();
+        let info = RecordingInfo::from_file(&file, None).unwrap();
+        remove_file(&file).unwrap();
+
+        let json = serde_json::to_value(&info).unwrap();
+        let keys: Vec<&str> = json
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(|k| k.as_str())
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                "file",
+                "binlog",
+                "game_versions",
+                "start_time",
+                "end_time",
+                "duration",
+                "packet_count",
+                "packet_types",
+                "sessions",
+                "unparseable_packets",
+                "parse_errors",
+            ]
+        );
+        assert_eq!(json["binlog"], serde_json::Value::Null);
+        assert_eq!(json["game_versions"], serde_json::json!(["2021 v0.00"]));
+        assert_eq!(json["packet_types"]["LapData"]["count"], 7);
+        assert_eq!(json["parse_errors"]["Invalid packet"], 2);
+
+        let session = &json["sessions"][0];
+        assert_eq!(session["session_uid"], format_session_uid(0xaaaa));
+        assert_eq!(session["source_label"], serde_json::Value::Null);
+        assert_eq!(session["frame_gaps"], 1);
+        assert_eq!(session["largest_frame_gap"], 2);
+        // Only used while reading
+        assert!(session.get("last_frame").is_none());
+    }
+}
+
+#[cfg(test)]
+mod test_format_duration {
+    use super::format_duration;
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(0.0), "00:00:00.000");
+        assert_eq!(format_duration(61.5), "00:01:01.500");
+        assert_eq!(format_duration(3723.0014), "01:02:03.001");
+    }
+}
@@ -1,13 +1,35 @@
+use std::net::{Ipv4Addr, SocketAddr};
+
 use anyhow::Result;
-use clap::{Args, Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use log::LevelFilter;
 use simplelog::{ColorChoice, TerminalMode};
 
+use f1_telemetry::packet::PacketType;
 use f1_telemetry_common::logging::LogBuilder;
 
+use crate::utils::{parse_packet_type, parse_seconds};
+
+mod anonymize;
+mod binlog;
+mod compact;
+mod compression;
+mod db;
+mod edit;
+mod export;
+#[cfg(test)]
+mod fixtures;
+mod info;
+mod pcap;
 mod player;
+mod quarantine;
 mod recorder;
+mod recording;
+mod relay;
+mod selection;
+mod sessions;
 mod utils;
+mod validate;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None, propagate_version = true)]
@@ -23,6 +45,48 @@ enum Commands {
 
     /// Record an incoming session
     Record(RecordArgs),
+
+    /// Summarize the content of a recording
+    Info(InfoArgs),
+
+    /// Export the packets of a recording, one file per packet type
+    Export(ExportArgs),
+
+    /// Compress the packets of an existing recording, in place
+    Compact(CompactArgs),
+
+    /// Import the F1 packets of a pcap or pcapng capture into a recording
+    ImportPcap(ImportPcapArgs),
+
+    /// Export the packets of a recording to a pcap capture
+    ExportPcap(ExportPcapArgs),
+
+    /// Copy a range of a recording (session, laps, session time) to a new recording
+    Slice(SliceArgs),
+
+    /// Merge several recordings into a new one, ordered by timestamp and without duplicates
+    Merge(MergeArgs),
+
+    /// Copy a recording without its idle periods (menus, paused games)
+    Trim(TrimArgs),
+
+    /// Check that every stored packet can be parsed and matches its header columns
+    Validate(ValidateArgs),
+
+    /// Move quarantined packets that can now be parsed into the recording
+    Reparse(ReparseArgs),
+
+    /// Upgrade a database to the current schema, which commands writing to it do first
+    Migrate(MigrateArgs),
+
+    /// Forward incoming packets to several destinations
+    Relay(RelayArgs),
+
+    /// Convert a binary log to a database, or a database to a binary log
+    Convert(ConvertArgs),
+
+    /// Copy a recording with the names and network IDs of the human players replaced, to share it
+    Anonymize(AnonymizeArgs),
 }
 
 #[derive(Debug, Args)]
@@ -43,6 +107,14 @@ struct PlayArgs {
     #[clap(long = "loop")]
     loop_play: bool,
 
+    /// Give the sessions a new random UID on every loop, so that consumers see new sessions
+    #[clap(long, requires = "loop_play")]
+    loop_new_session: bool,
+
+    /// Offset the session time and frame identifiers on every loop, so that they keep increasing
+    #[clap(long, requires = "loop_play")]
+    loop_continue_time: bool,
+
     /// Play the packets in realtime or not.
     #[clap(long)]
     realtime: bool,
@@ -54,14 +126,376 @@ struct PlayArgs {
     /// Number of packets to skip at the start of the file
     #[clap(long, default_value = "0")]
     skip: u64,
+
+    #[clap(flatten)]
+    selection: SelectionArgs,
+
+    /// Replay from the perspective of another car, by index or participant name.
+    ///
+    /// Rewrites the player car index of every packet. Motion Ex packets, which only describe the
+    /// recording player, are not replayed.
+    #[clap(long, default_value = None, value_parser = player::FocusTarget::parse)]
+    focus_car: Option<player::FocusTarget>,
+
+    /// Control the playback with commands read from stdin (pause, step, speed, jumps)
+    #[clap(long, short)]
+    interactive: bool,
+
+    #[clap(flatten)]
+    impairment: ImpairmentArgs,
+}
+
+/// Simulated network issues during a replay
+#[derive(Debug, Args)]
+struct ImpairmentArgs {
+    /// Percentage of packets to drop at random
+    #[clap(long, default_value = "0", value_parser = player::parse_percentage)]
+    drop: f64,
+
+    /// Percentage of packets to drop for some packet types, instead of --drop (PACKET_TYPE=PERCENT,
+    /// comma separated, ex. motion=50,lap_data=10)
+    #[clap(long, value_delimiter = ',')]
+    drop_type: Vec<player::PacketLoss>,
+
+    /// Percentage of packets to send twice
+    #[clap(long, default_value = "0", value_parser = player::parse_percentage)]
+    duplicate: f64,
+
+    /// Maximum random delay added to each packet, in milliseconds
+    #[clap(long, default_value = "0", value_parser = player::parse_jitter)]
+    jitter: f64,
+
+    /// Shuffle packets within a window of this many packets
+    #[clap(long, default_value = "1")]
+    reorder_window: usize,
+
+    /// Seed of the random simulation, to reproduce a test run. Random by default, and logged.
+    #[clap(long, default_value = None)]
+    seed: Option<u64>,
+}
+
+/// Range of packets of a recording
+#[derive(Debug, Args)]
+struct SelectionArgs {
+    /// Only select packets from this session (UID in hexadecimal)
+    #[clap(long, default_value = None)]
+    session: Option<String>,
+
+    /// Start at the beginning of this lap of the player
+    #[clap(long, default_value = None)]
+    from_lap: Option<u8>,
+
+    /// Stop at the end of this lap of the player
+    #[clap(long, default_value = None)]
+    to_lap: Option<u8>,
+
+    /// Start at this session time, in seconds
+    #[clap(long, default_value = None)]
+    from_session_time: Option<f64>,
+
+    /// Stop at this session time, in seconds
+    #[clap(long, default_value = None)]
+    to_session_time: Option<f64>,
+
+    /// Only select these packet types (comma separated, ex. motion,lap_data)
+    #[clap(long, value_delimiter = ',', value_parser = parse_packet_type)]
+    packet_types: Vec<PacketType>,
+
+    /// Only select packets recorded from this source label
+    #[clap(long, default_value = None)]
+    source: Option<String>,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+enum RecordFormat {
+    /// SQLite database
+    Sqlite,
+    /// Append-only binary log, cheaper to write. Can be converted to a database later.
+    Binlog,
 }
 
 #[derive(Debug, Args)]
 struct RecordArgs {
     /// Database file to record to
-    #[clap(short, long, default_value = None)]
+    #[clap(short, long, required_unless_present = "auto")]
+    file: Option<String>,
+
+    /// Format of the recording. Binary logs don't support automatic mode, split sessions,
+    /// compression or source labels.
+    #[clap(long, value_enum, default_value = "sqlite")]
+    format: RecordFormat,
+
+    /// Host to bind on for the UDP packet listener
+    #[clap(long, default_value = "0.0.0.0")]
+    host: String,
+
+    /// Port to bind on for the UDP packet listener
+    #[clap(long, default_value = "20777")]
+    port: u16,
+
+    /// Write each session to its own database file, suffixed with the session UID
+    #[clap(long)]
+    split_sessions: bool,
+
+    /// Wait for sessions to start and record each of them to its own file in --output-dir, named
+    /// from its track, session type and start time (UTC)
+    #[clap(long, conflicts_with_all = ["file", "split_sessions"])]
+    auto: bool,
+
+    /// Directory of the recordings in automatic mode
+    #[clap(long, default_value = ".")]
+    output_dir: String,
+
+    /// Close a session when no packet was received for this long, in seconds (automatic mode)
+    #[clap(long, default_value_t = 60.0)]
+    idle_timeout: f64,
+
+    /// Delete the oldest recordings when they take more than this, in MB (automatic mode)
+    #[clap(long, default_value = None)]
+    max_disk_usage: Option<u64>,
+
+    /// Number of packets waiting to be written above which new packets are dropped
+    #[clap(long, default_value_t = 8192)]
+    queue_size: usize,
+
+    /// Maximum time a packet waits before being written to disk, in seconds
+    #[clap(long, default_value_t = 1.0, value_parser = parse_seconds)]
+    flush_interval: f64,
+
+    /// Compress the stored packets with zstd
+    #[clap(long)]
+    compress: bool,
+
+    /// Compression level, from 1 (fastest) to 22 (smallest)
+    #[clap(long, default_value_t = compression::DEFAULT_LEVEL)]
+    compression_level: i32,
+
+    /// Listen on several ports at once, labelling the packets of each one (LABEL=PORT, comma
+    /// separated). Replaces --port.
+    #[clap(long, value_delimiter = ',')]
+    listen: Vec<recorder::Listener>,
+
+    /// Also forward every received packet to these addresses (IP:port, comma separated)
+    #[clap(long, value_delimiter = ',')]
+    relay: Vec<SocketAddr>,
+}
+
+#[derive(Debug, Args)]
+struct InfoArgs {
+    /// Database file to inspect
+    file: String,
+
+    /// Output the summary as JSON
+    #[clap(long)]
+    json: bool,
+
+    /// Only summarize packets recorded from this source label
+    #[clap(long, default_value = None)]
+    source: Option<String>,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+enum ExportFormat {
+    Csv,
+    JsonLines,
+    Parquet,
+    /// MoTeC i2 log of a single car, one file per session
+    Motec,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+enum ParquetCompression {
+    None,
+    Snappy,
+    Zstd,
+}
+
+#[derive(Debug, Args)]
+struct ExportArgs {
+    /// Database file to export
+    file: String,
+
+    /// Directory to write the exported files to
+    #[clap(short, long, default_value = ".")]
+    output_dir: String,
+
+    /// Output format
+    #[clap(long, value_enum, default_value = "csv")]
+    format: ExportFormat,
+
+    /// Only export packets from this session (UID in hexadecimal)
+    #[clap(long, default_value = None)]
+    session: Option<String>,
+
+    /// Only export data for this car index. Can be repeated.
+    #[clap(long = "car")]
+    cars: Vec<u8>,
+
+    /// Only export packets from this session time onwards, in seconds
+    #[clap(long, default_value = None)]
+    from: Option<f64>,
+
+    /// Only export packets up to this session time, in seconds
+    #[clap(long, default_value = None)]
+    to: Option<f64>,
+
+    /// Only export packets recorded from this source label
+    #[clap(long, default_value = None)]
+    source: Option<String>,
+
+    /// Compression of the Parquet files
+    #[clap(long, value_enum, default_value = "zstd")]
+    compression: ParquetCompression,
+
+    /// Sample rate of the MoTeC channels, in Hz
+    #[clap(long, default_value = "60")]
+    frequency: u16,
+}
+
+#[derive(Debug, Args)]
+struct CompactArgs {
+    /// Database file to compact
+    file: String,
+
+    /// Compression level, from 1 (fastest) to 22 (smallest)
+    #[clap(long, default_value_t = compression::DEFAULT_LEVEL)]
+    level: i32,
+
+    /// Store the packets uncompressed instead
+    #[clap(long)]
+    decompress: bool,
+}
+
+#[derive(Debug, Args)]
+struct ImportPcapArgs {
+    /// Capture file to import (pcap or pcapng)
+    capture: String,
+
+    /// Database file to import to
+    #[clap(short, long)]
+    file: String,
+
+    /// Destination port of the F1 packets in the capture
+    #[clap(long, default_value = "20777")]
+    port: u16,
+}
+
+#[derive(Debug, Args)]
+struct ExportPcapArgs {
+    /// Database file to export
+    file: String,
+
+    /// Capture file to write
+    #[clap(short, long)]
+    output: String,
+
+    /// Source IP address of the generated packets
+    #[clap(long, default_value = "127.0.0.1")]
+    source: Ipv4Addr,
+
+    /// Destination IP address of the generated packets
+    #[clap(long, default_value = "127.0.0.1")]
+    destination: Ipv4Addr,
+
+    /// Source and destination port of the generated packets
+    #[clap(long, default_value = "20777")]
+    port: u16,
+}
+
+#[derive(Debug, Args)]
+struct SliceArgs {
+    /// Database file to slice
     file: String,
 
+    /// Database file to create
+    #[clap(short, long)]
+    output: String,
+
+    #[clap(flatten)]
+    selection: SelectionArgs,
+}
+
+#[derive(Debug, Args)]
+struct MergeArgs {
+    /// Database files to merge
+    #[clap(required = true, num_args = 2..)]
+    files: Vec<String>,
+
+    /// Database file to create
+    #[clap(short, long)]
+    output: String,
+}
+
+#[derive(Debug, Args)]
+struct TrimArgs {
+    /// Database file to trim
+    file: String,
+
+    /// Database file to create
+    #[clap(short, long)]
+    output: String,
+
+    /// Minimum duration of the removed idle periods, in seconds
+    #[clap(long, default_value = "5.0")]
+    idle: f64,
+}
+
+#[derive(Debug, Args)]
+struct ValidateArgs {
+    /// Database file to validate
+    file: String,
+}
+
+#[derive(Debug, Args)]
+struct MigrateArgs {
+    /// Database file to upgrade
+    file: String,
+}
+
+#[derive(Debug, Args)]
+struct ReparseArgs {
+    /// Database file with quarantined packets
+    file: String,
+}
+
+#[derive(Debug, Args)]
+struct ConvertArgs {
+    /// Binary log or database file to convert
+    file: String,
+
+    /// File to create, a database for a binary log and a binary log for a database
+    #[clap(short, long)]
+    output: String,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+enum AnonymizedNames {
+    /// Player 1, Player 2... in order of appearance in each session
+    Pseudonym,
+    /// Name of the driver the player races as, when the game sets one
+    Driver,
+}
+
+#[derive(Debug, Args)]
+struct AnonymizeArgs {
+    /// Database file to anonymize
+    file: String,
+
+    /// Database file to create
+    #[clap(short, long)]
+    output: String,
+
+    /// Names given to the human players
+    #[clap(long, value_enum, default_value = "pseudonym")]
+    names: AnonymizedNames,
+
+    /// Also replace the session UIDs with random ones
+    #[clap(long)]
+    randomize_session_uid: bool,
+}
+
+#[derive(Debug, Args)]
+struct RelayArgs {
     /// Host to bind on for the UDP packet listener
     #[clap(long, default_value = "0.0.0.0")]
     host: String,
@@ -69,6 +503,11 @@ struct RecordArgs {
     /// Port to bind on for the UDP packet listener
     #[clap(long, default_value = "20777")]
     port: u16,
+
+    /// Addresses to forward packets to (IP:port, comma separated). Broadcast and multicast
+    /// addresses are supported.
+    #[clap(short, long, value_delimiter = ',', required = true)]
+    destinations: Vec<SocketAddr>,
 }
 
 fn main() -> Result<()> {
@@ -82,5 +521,19 @@ fn main() -> Result<()> {
     match &cli.command {
         Commands::Play(args) => player::play(args),
         Commands::Record(args) => recorder::record(args),
+        Commands::Info(args) => info::info(args),
+        Commands::Export(args) => export::export(args),
+        Commands::Compact(args) => compact::compact(args),
+        Commands::ImportPcap(args) => pcap::import_pcap(args),
+        Commands::ExportPcap(args) => pcap::export_pcap(args),
+        Commands::Slice(args) => edit::slice(args),
+        Commands::Merge(args) => edit::merge(args),
+        Commands::Trim(args) => edit::trim(args),
+        Commands::Validate(args) => validate::validate(args),
+        Commands::Reparse(args) => quarantine::reparse(args),
+        Commands::Migrate(args) => db::migrate(args),
+        Commands::Relay(args) => relay::relay(args),
+        Commands::Convert(args) => binlog::convert(args),
+        Commands::Anonymize(args) => anonymize::anonymize(args),
     }
 }
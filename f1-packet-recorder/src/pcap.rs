@@ -0,0 +1,111 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+
+use anyhow::Result;
+use log::{info, warn};
+
+use f1_telemetry::packet::parse_packet;
+
+use crate::db::{for_each_packet, PacketSource};
+use crate::quarantine::{insert_quarantined, QuarantinedPacket};
+use crate::recorder::{insert_packets, TimestampedPacket};
+use crate::sessions::Sessions;
+use crate::utils::{get_database_connection, open_recording};
+
+use super::{ExportPcapArgs, ImportPcapArgs};
+
+use capture::{udp_payload, CaptureReader, CaptureWriter};
+
+mod capture;
+
+/// Number of packets inserted per transaction
+const BATCH_SIZE: usize = 256;
+
+pub(crate) fn import_pcap(args: &ImportPcapArgs) -> Result<()> {
+    info!(
+        "Importing UDP packets to port {} from {} into {}",
+        args.port, args.capture, args.file
+    );
+
+    let mut reader = CaptureReader::new(BufReader::new(File::open(&args.capture)?))?;
+    let conn = get_database_connection(&args.file)?;
+
+    let mut sessions = Sessions::default();
+    let mut packets = Vec::with_capacity(BATCH_SIZE);
+    let mut quarantined = Vec::new();
+    let mut frames = 0;
+    let mut imported = 0;
+
+    while let Some(frame) = reader.next_frame()? {
+        frames += 1;
+
+        let Some(payload) = udp_payload(&frame, args.port) else {
+            continue;
+        };
+
+        match parse_packet(payload.len(), payload) {
+            Ok(packet) => {
+                sessions.update(frame.timestamp, &packet, None);
+                packets.push(TimestampedPacket {
+                    timestamp: frame.timestamp,
+                    source: PacketSource::default(),
+                    packet,
+                    data: payload.to_vec(),
+                });
+            }
+            Err(e) => quarantined.push(QuarantinedPacket {
+                timestamp: frame.timestamp,
+                source: PacketSource::default(),
+                error: e.to_string(),
+                data: payload.to_vec(),
+            }),
+        }
+
+        if packets.len() >= BATCH_SIZE {
+            imported += packets.len();
+            insert_packets(&conn, &packets.iter().collect::<Vec<_>>())?;
+            packets.clear();
+        }
+    }
+
+    imported += packets.len();
+    insert_packets(&conn, &packets.iter().collect::<Vec<_>>())?;
+    insert_quarantined(&conn, &quarantined)?;
+    sessions.save_all(&conn)?;
+
+    if !quarantined.is_empty() {
+        warn!(
+            "Quarantined {} UDP payloads that could not be parsed",
+            quarantined.len()
+        );
+    }
+    info!("Imported {} packets from {} frames", imported, frames);
+
+    Ok(())
+}
+
+pub(crate) fn export_pcap(args: &ExportPcapArgs) -> Result<()> {
+    info!(
+        "Exporting {} to {} ({} -> {}:{})",
+        args.file, args.output, args.source, args.destination, args.port
+    );
+
+    let conn = open_recording(&args.file)?;
+    let mut writer = CaptureWriter::new(
+        BufWriter::new(File::create(&args.output)?),
+        args.source.octets(),
+        args.destination.octets(),
+        args.port,
+    )?;
+
+    let mut exported = 0;
+    for_each_packet(&conn, |p| {
+        exported += 1;
+        writer.write(p.timestamp, &p.data)
+    })?;
+    writer.finish()?;
+
+    info!("Exported {} packets", exported);
+
+    Ok(())
+}
@@ -0,0 +1,600 @@
+use std::io::{ErrorKind, Read, Write};
+
+use anyhow::{Error, Result};
+
+const PCAP_MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const PCAP_MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+
+const PCAPNG_SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 1;
+const PCAPNG_ENHANCED_PACKET: u32 = 6;
+/// Option of an interface description holding the timestamp resolution
+const PCAPNG_IF_TSRESOL: u16 = 9;
+/// Largest block read, as in Wireshark, so that a corrupted length doesn't exhaust the memory
+const PCAPNG_MAX_BLOCK_LENGTH: usize = 16 * 1024 * 1024;
+
+/// Largest snapshot length of libpcap, for the captures that don't set one
+const MAX_SNAPLEN: usize = 262_144;
+
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LOOP: u32 = 108;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: [u16; 2] = [0x8100, 0x88a8];
+
+const IP_PROTOCOL_UDP: u8 = 17;
+
+/// A frame read from a capture file
+pub(super) struct Frame {
+    /// Capture time, in seconds since the epoch
+    pub(super) timestamp: f64,
+    pub(super) link_type: u32,
+    pub(super) data: Vec<u8>,
+}
+
+#[derive(Copy, Clone)]
+enum Endianness {
+    Little,
+    Big,
+}
+
+impl Endianness {
+    fn u16(self, b: &[u8]) -> u16 {
+        let b = [b[0], b[1]];
+        match self {
+            Endianness::Little => u16::from_le_bytes(b),
+            Endianness::Big => u16::from_be_bytes(b),
+        }
+    }
+
+    fn u32(self, b: &[u8]) -> u32 {
+        let b = [b[0], b[1], b[2], b[3]];
+        match self {
+            Endianness::Little => u32::from_le_bytes(b),
+            Endianness::Big => u32::from_be_bytes(b),
+        }
+    }
+}
+
+enum Format {
+    Pcap {
+        link_type: u32,
+        /// Fraction of a second of the timestamps
+        resolution: f64,
+        snaplen: usize,
+    },
+    PcapNg {
+        interfaces: Vec<Interface>,
+    },
+}
+
+/// Interface of a pcapng section
+struct Interface {
+    link_type: u32,
+    /// Fraction of a second of the timestamps
+    resolution: f64,
+    snaplen: usize,
+}
+
+/// Reads the frames of a pcap or pcapng file.
+pub(super) struct CaptureReader<R: Read> {
+    reader: R,
+    endianness: Endianness,
+    format: Format,
+}
+
+impl<R: Read> CaptureReader<R> {
+    pub(super) fn new(mut reader: R) -> Result<Self> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+
+        if u32::from_le_bytes(magic) == PCAPNG_SECTION_HEADER {
+            let mut capture = Self {
+                reader,
+                endianness: Endianness::Little,
+                format: Format::PcapNg { interfaces: vec![] },
+            };
+            capture.read_section_header()?;
+            return Ok(capture);
+        }
+
+        let (endianness, resolution) = match (u32::from_le_bytes(magic), u32::from_be_bytes(magic))
+        {
+            (PCAP_MAGIC_MICROS, _) => (Endianness::Little, 1e-6),
+            (PCAP_MAGIC_NANOS, _) => (Endianness::Little, 1e-9),
+            (_, PCAP_MAGIC_MICROS) => (Endianness::Big, 1e-6),
+            (_, PCAP_MAGIC_NANOS) => (Endianness::Big, 1e-9),
+            _ => return Err(Error::msg("Not a pcap or pcapng file")),
+        };
+
+        let mut header = [0; 20];
+        reader.read_exact(&mut header)?;
+
+        Ok(Self {
+            reader,
+            endianness,
+            format: Format::Pcap {
+                link_type: endianness.u32(&header[16..20]),
+                resolution,
+                snaplen: snaplen(endianness.u32(&header[12..16])),
+            },
+        })
+    }
+
+    pub(super) fn next_frame(&mut self) -> Result<Option<Frame>> {
+        match self.format {
+            Format::Pcap { .. } => self.next_pcap_frame(),
+            Format::PcapNg { .. } => self.next_pcapng_frame(),
+        }
+    }
+
+    fn next_pcap_frame(&mut self) -> Result<Option<Frame>> {
+        let Format::Pcap {
+            link_type,
+            resolution,
+            snaplen,
+        } = self.format
+        else {
+            unreachable!()
+        };
+
+        let mut header = [0; 16];
+        if !read_or_eof(&mut self.reader, &mut header)? {
+            return Ok(None);
+        }
+
+        let e = self.endianness;
+        let seconds = e.u32(&header[0..4]) as f64;
+        let fraction = e.u32(&header[4..8]) as f64 * resolution;
+        let length = e.u32(&header[8..12]) as usize;
+        if length > snaplen {
+            return Err(Error::msg(format!(
+                "Invalid pcap frame of {} bytes, over the snapshot length {}",
+                length, snaplen
+            )));
+        }
+        let mut data = vec![0; length];
+        self.reader.read_exact(&mut data)?;
+
+        Ok(Some(Frame {
+            timestamp: seconds + fraction,
+            link_type,
+            data,
+        }))
+    }
+
+    fn next_pcapng_frame(&mut self) -> Result<Option<Frame>> {
+        loop {
+            let mut header = [0; 8];
+            if !read_or_eof(&mut self.reader, &mut header)? {
+                return Ok(None);
+            }
+
+            let block_type = self.endianness.u32(&header[0..4]);
+            if block_type == PCAPNG_SECTION_HEADER {
+                self.read_section_header_body(&header)?;
+                continue;
+            }
+
+            let length = self.endianness.u32(&header[4..8]) as usize;
+            if !(12..=PCAPNG_MAX_BLOCK_LENGTH).contains(&length) {
+                return Err(Error::msg(format!(
+                    "Invalid pcapng block of {} bytes",
+                    length
+                )));
+            }
+            // The body is followed by a copy of the length
+            let mut body = vec![0; length - 8];
+            self.reader.read_exact(&mut body)?;
+            let body = &body[..body.len() - 4];
+
+            match block_type {
+                PCAPNG_INTERFACE_DESCRIPTION => self.read_interface_description(body)?,
+                PCAPNG_ENHANCED_PACKET => return Ok(Some(self.read_enhanced_packet(body)?)),
+                // Simple packets have no timestamp, and other blocks no packets
+                _ => {}
+            }
+        }
+    }
+
+    fn read_section_header(&mut self) -> Result<()> {
+        let mut header = [0; 8];
+        header[0..4].copy_from_slice(&PCAPNG_SECTION_HEADER.to_le_bytes());
+        self.reader.read_exact(&mut header[4..8])?;
+
+        self.read_section_header_body(&header)
+    }
+
+    fn read_section_header_body(&mut self, header: &[u8; 8]) -> Result<()> {
+        let mut magic = [0; 4];
+        self.reader.read_exact(&mut magic)?;
+
+        self.endianness = match u32::from_le_bytes(magic) {
+            PCAPNG_BYTE_ORDER_MAGIC => Endianness::Little,
+            _ if u32::from_be_bytes(magic) == PCAPNG_BYTE_ORDER_MAGIC => Endianness::Big,
+            _ => return Err(Error::msg("Invalid pcapng byte order magic")),
+        };
+
+        let length = self.endianness.u32(&header[4..8]) as usize;
+        if !(16..=PCAPNG_MAX_BLOCK_LENGTH).contains(&length) {
+            return Err(Error::msg("Invalid pcapng section header"));
+        }
+        let mut rest = vec![0; length - 12];
+        self.reader.read_exact(&mut rest)?;
+
+        // Interface IDs are local to a section
+        self.format = Format::PcapNg { interfaces: vec![] };
+
+        Ok(())
+    }
+
+    fn read_interface_description(&mut self, body: &[u8]) -> Result<()> {
+        if body.len() < 8 {
+            return Err(Error::msg("Invalid pcapng interface description"));
+        }
+
+        let e = self.endianness;
+        let link_type = e.u16(&body[0..2]) as u32;
+        let snaplen = snaplen(e.u32(&body[4..8]));
+        let mut resolution = 1e-6;
+
+        let mut options = &body[8..];
+        while options.len() >= 4 {
+            let code = e.u16(&options[0..2]);
+            let length = e.u16(&options[2..4]) as usize;
+            let value = options.get(4..4 + length).unwrap_or_default();
+
+            if code == PCAPNG_IF_TSRESOL {
+                if let Some(&r) = value.first() {
+                    let exponent = (r & 0x7f) as i32;
+                    resolution = if r & 0x80 == 0 {
+                        10f64.powi(-exponent)
+                    } else {
+                        2f64.powi(-exponent)
+                    };
+                }
+            }
+
+            // Options are padded to 32 bits
+            let padded = 4 + length.div_ceil(4) * 4;
+            options = options.get(padded..).unwrap_or_default();
+        }
+
+        if let Format::PcapNg { interfaces } = &mut self.format {
+            interfaces.push(Interface {
+                link_type,
+                resolution,
+                snaplen,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn read_enhanced_packet(&self, body: &[u8]) -> Result<Frame> {
+        if body.len() < 20 {
+            return Err(Error::msg("Invalid pcapng enhanced packet"));
+        }
+
+        let e = self.endianness;
+        let interface_id = e.u32(&body[0..4]) as usize;
+        let ticks = ((e.u32(&body[4..8]) as u64) << 32) | e.u32(&body[8..12]) as u64;
+        let length = e.u32(&body[12..16]) as usize;
+
+        let Format::PcapNg { interfaces } = &self.format else {
+            unreachable!()
+        };
+        let Some(interface) = interfaces.get(interface_id) else {
+            return Err(Error::msg(format!(
+                "Unknown pcapng interface {}",
+                interface_id
+            )));
+        };
+
+        if length > interface.snaplen {
+            return Err(Error::msg(format!(
+                "Invalid pcapng enhanced packet of {} bytes, over the snapshot length {}",
+                length, interface.snaplen
+            )));
+        }
+        let data = body
+            .get(20..20 + length)
+            .ok_or_else(|| Error::msg("Truncated pcapng enhanced packet"))?;
+
+        Ok(Frame {
+            timestamp: ticks as f64 * interface.resolution,
+            link_type: interface.link_type,
+            data: data.to_vec(),
+        })
+    }
+}
+
+/// Largest frame of a capture, from the snapshot length of its header, 0 meaning none.
+fn snaplen(snaplen: u32) -> usize {
+    match snaplen as usize {
+        0 => MAX_SNAPLEN,
+        snaplen => snaplen.min(MAX_SNAPLEN),
+    }
+}
+
+/// Fill `buf`, or return false at the end of the file.
+fn read_or_eof<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Payload of a UDP datagram sent to `port`, if the frame holds one.
+///
+/// Fragmented IPv4 datagrams are not reassembled, F1 packets fit in a single Ethernet frame.
+pub(super) fn udp_payload(frame: &Frame, port: u16) -> Option<&[u8]> {
+    let data = frame.data.as_slice();
+    let be16 = |b: &[u8], i: usize| -> Option<u16> {
+        Some(u16::from_be_bytes([*b.get(i)?, *b.get(i + 1)?]))
+    };
+
+    let ip = match frame.link_type {
+        LINKTYPE_ETHERNET => {
+            let mut offset = 12;
+            let mut ethertype = be16(data, offset)?;
+            while ETHERTYPE_VLAN.contains(&ethertype) {
+                offset += 4;
+                ethertype = be16(data, offset)?;
+            }
+            match ethertype {
+                ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => data.get(offset + 2..)?,
+                _ => return None,
+            }
+        }
+        LINKTYPE_LINUX_SLL => data.get(16..)?,
+        LINKTYPE_LINUX_SLL2 => data.get(20..)?,
+        LINKTYPE_NULL | LINKTYPE_LOOP => data.get(4..)?,
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => data,
+        _ => return None,
+    };
+
+    let udp = match ip.first()? >> 4 {
+        4 => {
+            let header_length = (ip[0] & 0x0f) as usize * 4;
+            let fragment = be16(ip, 6)?;
+            // More fragments flag or fragment offset
+            if ip.get(9)? != &IP_PROTOCOL_UDP || fragment & 0x3fff != 0 {
+                return None;
+            }
+            let total_length = be16(ip, 2)? as usize;
+            ip.get(header_length..total_length.min(ip.len()))?
+        }
+        6 => {
+            if ip.get(6)? != &IP_PROTOCOL_UDP {
+                return None;
+            }
+            let payload_length = be16(ip, 4)? as usize;
+            ip.get(40..(40 + payload_length).min(ip.len()))?
+        }
+        _ => return None,
+    };
+
+    if be16(udp, 2)? != port {
+        return None;
+    }
+    let length = be16(udp, 4)? as usize;
+
+    udp.get(8..length.min(udp.len()))
+}
+
+/// Writes UDP datagrams to a pcap file, as Ethernet frames between two hosts.
+pub(super) struct CaptureWriter<W: Write> {
+    writer: W,
+    source: [u8; 4],
+    destination: [u8; 4],
+    port: u16,
+    identification: u16,
+}
+
+impl<W: Write> CaptureWriter<W> {
+    pub(super) fn new(
+        mut writer: W,
+        source: [u8; 4],
+        destination: [u8; 4],
+        port: u16,
+    ) -> Result<Self> {
+        writer.write_all(&PCAP_MAGIC_MICROS.to_le_bytes())?;
+        writer.write_all(&2u16.to_le_bytes())?; // Version 2.4
+        writer.write_all(&4u16.to_le_bytes())?;
+        writer.write_all(&0i32.to_le_bytes())?; // UTC
+        writer.write_all(&0u32.to_le_bytes())?; // Timestamp accuracy
+        writer.write_all(&65535u32.to_le_bytes())?; // Snapshot length
+        writer.write_all(&LINKTYPE_ETHERNET.to_le_bytes())?;
+
+        Ok(Self {
+            writer,
+            source,
+            destination,
+            port,
+            identification: 0,
+        })
+    }
+
+    pub(super) fn write(&mut self, timestamp: f64, payload: &[u8]) -> Result<()> {
+        let udp_length = 8 + payload.len();
+        let ip_length = 20 + udp_length;
+        let frame_length = 14 + ip_length;
+
+        let mut frame = Vec::with_capacity(frame_length);
+
+        // Ethernet, between locally administered addresses
+        frame.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x02]);
+        frame.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x01]);
+        frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+
+        // IPv4
+        let ip_start = frame.len();
+        frame.extend_from_slice(&[0x45, 0]);
+        frame.extend_from_slice(&(ip_length as u16).to_be_bytes());
+        frame.extend_from_slice(&self.identification.to_be_bytes());
+        frame.extend_from_slice(&[0x40, 0, 64, IP_PROTOCOL_UDP, 0, 0]); // Don't fragment
+        frame.extend_from_slice(&self.source);
+        frame.extend_from_slice(&self.destination);
+        let checksum = ipv4_checksum(&frame[ip_start..]);
+        frame[ip_start + 10..ip_start + 12].copy_from_slice(&checksum.to_be_bytes());
+        self.identification = self.identification.wrapping_add(1);
+
+        // UDP, without checksum
+        frame.extend_from_slice(&self.port.to_be_bytes());
+        frame.extend_from_slice(&self.port.to_be_bytes());
+        frame.extend_from_slice(&(udp_length as u16).to_be_bytes());
+        frame.extend_from_slice(&[0, 0]);
+        frame.extend_from_slice(payload);
+
+        let seconds = timestamp.floor();
+        let micros = ((timestamp - seconds) * 1e6).round().min(999_999.0);
+
+        self.writer.write_all(&(seconds as u32).to_le_bytes())?;
+        self.writer.write_all(&(micros as u32).to_le_bytes())?;
+        self.writer.write_all(&(frame.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&(frame.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&frame)?;
+
+        Ok(())
+    }
+
+    pub(super) fn finish(mut self) -> Result<W> {
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|c| u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod test_capture {
+    use super::{ipv4_checksum, udp_payload, CaptureReader, CaptureWriter};
+
+    #[test]
+    fn test_write_and_read_back() {
+        let writer = CaptureWriter::new(Vec::new(), [10, 0, 0, 1], [10, 0, 0, 2], 20777).unwrap();
+        let mut writer = writer;
+        writer.write(1700000000.25, b"first").unwrap();
+        writer.write(1700000000.5, b"second").unwrap();
+        let capture = writer.finish().unwrap();
+
+        let mut reader = CaptureReader::new(capture.as_slice()).unwrap();
+
+        let frame = reader.next_frame().unwrap().unwrap();
+        assert_eq!(frame.timestamp, 1700000000.25);
+        assert_eq!(udp_payload(&frame, 20777), Some(&b"first"[..]));
+        assert_eq!(udp_payload(&frame, 20778), None);
+
+        let frame = reader.next_frame().unwrap().unwrap();
+        assert_eq!(udp_payload(&frame, 20777), Some(&b"second"[..]));
+
+        assert!(reader.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_read_pcapng() {
+        let block = |block_type: u32, body: &[u8]| {
+            let length = (12 + body.len()) as u32;
+            let mut block = block_type.to_le_bytes().to_vec();
+            block.extend_from_slice(&length.to_le_bytes());
+            block.extend_from_slice(body);
+            block.extend_from_slice(&length.to_le_bytes());
+            block
+        };
+
+        // Section header, version 1.0 and unknown section length
+        let mut section = 0x1a2b3c4du32.to_le_bytes().to_vec();
+        section.extend_from_slice(&[1, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+
+        // Raw IPv4 interface with nanosecond timestamps
+        let mut interface = vec![228, 0, 0, 0, 0, 0, 0, 0];
+        interface.extend_from_slice(&[9, 0, 1, 0, 9, 0, 0, 0, 0, 0, 0, 0]);
+
+        let mut datagram = vec![0x45, 0, 0, 33, 0, 0, 0x40, 0, 64, 17, 0, 0];
+        datagram.extend_from_slice(&[127, 0, 0, 1, 127, 0, 0, 1]);
+        datagram.extend_from_slice(&[0x51, 0x29, 0x51, 0x29, 0, 13, 0, 0]);
+        datagram.extend_from_slice(b"hello");
+        datagram.extend_from_slice(&[0, 0, 0]);
+
+        let ticks: u64 = 1_700_000_000_500_000_000;
+        let mut packet = 0u32.to_le_bytes().to_vec();
+        packet.extend_from_slice(&((ticks >> 32) as u32).to_le_bytes());
+        packet.extend_from_slice(&(ticks as u32).to_le_bytes());
+        packet.extend_from_slice(&33u32.to_le_bytes());
+        packet.extend_from_slice(&33u32.to_le_bytes());
+        packet.extend_from_slice(&datagram);
+
+        let mut capture = block(0x0a0d0d0a, &section);
+        capture.extend(block(1, &interface));
+        capture.extend(block(6, &packet));
+
+        let mut reader = CaptureReader::new(capture.as_slice()).unwrap();
+        let frame = reader.next_frame().unwrap().unwrap();
+
+        assert_eq!(frame.timestamp, 1_700_000_000.5);
+        assert_eq!(udp_payload(&frame, 20777), Some(&b"hello"[..]));
+        assert!(reader.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_reject_frames_over_the_snapshot_length() {
+        let writer = CaptureWriter::new(Vec::new(), [10, 0, 0, 1], [10, 0, 0, 2], 20777).unwrap();
+        let mut capture = writer.finish().unwrap();
+        // Frame header claiming more than the 65535 bytes of the snapshot length
+        capture.extend_from_slice(&[0; 8]);
+        capture.extend_from_slice(&[0, 0, 1, 0, 0, 0, 1, 0]);
+
+        let mut reader = CaptureReader::new(capture.as_slice()).unwrap();
+        let error = reader.next_frame().err().unwrap().to_string();
+        assert!(
+            error.contains("over the snapshot length 65535"),
+            "{}",
+            error
+        );
+
+        // Section header, then a block claiming 4 GB
+        let mut capture = 0x0a0d0d0au32.to_le_bytes().to_vec();
+        capture.extend_from_slice(&28u32.to_le_bytes());
+        capture.extend_from_slice(&0x1a2b3c4du32.to_le_bytes());
+        capture.extend_from_slice(&[1, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        capture.extend_from_slice(&28u32.to_le_bytes());
+        capture.extend_from_slice(&6u32.to_le_bytes());
+        capture.extend_from_slice(&u32::MAX.to_le_bytes());
+
+        let mut reader = CaptureReader::new(capture.as_slice()).unwrap();
+        let error = reader.next_frame().err().unwrap().to_string();
+        assert_eq!(error, "Invalid pcapng block of 4294967295 bytes");
+    }
+
+    #[test]
+    fn test_ipv4_checksum() {
+        let header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8,
+            0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+
+        assert_eq!(ipv4_checksum(&header), 0xb861);
+    }
+}
@@ -1,17 +1,33 @@
 use std::net::UdpSocket;
-use std::sync::mpsc::Receiver;
-use std::thread::sleep;
-use std::time::Duration;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
 
 use anyhow::{Error, Result};
-use log::info;
-use rusqlite::Connection;
-use time::Instant;
+use log::{info, warn};
 
-use crate::utils::{ctrl_c_channel, get_database_connection};
+use f1_telemetry::packet::PacketType;
+
+use crate::recording::{RecordedPacket, Recording};
+use crate::selection::{player_lap, Selection};
+use crate::utils::ctrl_c_channel;
 
 use super::PlayArgs;
 
+use control::{command_channel, Command, HELP};
+use focus::Focus;
+use identity::LoopIdentity;
+use impairment::Impairment;
+use timing::{wait_until, TimingStats, FAST_DATA_RATE, FRAME_WINDOW, MAX_LATENESS};
+
+pub(crate) use focus::FocusTarget;
+pub(crate) use impairment::{parse_jitter, parse_percentage, PacketLoss};
+
+mod control;
+mod focus;
+mod identity;
+mod impairment;
+mod timing;
+
 pub(crate) fn play(args: &PlayArgs) -> Result<()> {
     info!(
         "Replaying {} to {}:{} (realtime: {}/{:.1}x, loop: {})",
@@ -27,13 +43,37 @@ pub(crate) fn play(args: &PlayArgs) -> Result<()> {
 
     let player = Player::new(args)?;
     let ctrl_receiver = ctrl_c_channel()?;
+    let commands = player.interactive.then(|| {
+        info!("{}", HELP);
+        command_channel()
+    });
+
+    let mut focus = match &args.focus_car {
+        Some(car) => Some(Focus::new(
+            car.clone(),
+            &player.recording,
+            player.selection.session_id.as_deref(),
+        )?),
+        None => None,
+    };
+    let mut impairment = Impairment::new(&args.impairment);
+    let mut identity = LoopIdentity::new(args.loop_new_session, args.loop_continue_time);
 
     loop {
-        player.play(&ctrl_receiver)?;
+        player.play(
+            &ctrl_receiver,
+            commands.as_ref(),
+            focus.as_mut(),
+            impairment.as_mut(),
+            identity.as_mut(),
+        )?;
 
         if !args.loop_play {
             break;
         }
+        if let Some(identity) = identity.as_mut() {
+            identity.next_loop();
+        }
     }
 
     Ok(())
@@ -53,109 +93,379 @@ fn get_socket(destination: &Option<String>, port: u16) -> Result<UdpSocket> {
     Ok(socket)
 }
 
-struct TimestampedPacket {
-    timestamp: f64,
-    data: Vec<u8>,
-}
-
 struct Player {
     socket: UdpSocket,
-    conn: Connection,
+    recording: Recording,
 
     realtime: bool,
     realtime_factor: f32,
     skip: u64,
+    selection: Selection,
+    interactive: bool,
 }
 
 impl Player {
     fn new(args: &PlayArgs) -> Result<Self> {
         let socket = get_socket(&args.destination, args.port)?;
-        let conn = get_database_connection(&args.file)?;
+        let recording = Recording::open(&args.file)?;
+
+        let selection = Selection::new(&args.selection, &recording)?;
 
         Ok(Self {
             socket,
-            conn,
+            recording,
             realtime: args.realtime,
             realtime_factor: args.realtime_factor,
             skip: args.skip,
+            selection,
+            interactive: args.interactive,
         })
     }
 
-    fn play(&self, ctrl_receiver: &Receiver<()>) -> Result<()> {
-        let playback_start = Instant::now();
-        let first_timestamp = self.get_first_timestamp()?;
-
-        let mut stmt = self
-            .conn
-            .prepare("SELECT timestamp, packet FROM packets ORDER BY pkt_id LIMIT -1 OFFSET ?;")?;
-
-        let packets = stmt.query_map([self.skip], |r| {
-            Ok(TimestampedPacket {
-                timestamp: r.get(0)?,
-                data: r.get(1)?,
-            })
-        })?;
-
-        for (idx, packet) in packets.enumerate() {
-            if ctrl_receiver.try_recv().is_ok() {
-                info!("Stopping playback");
-                return Err(Error::msg("ctrl-c received"));
+    fn play(
+        &self,
+        ctrl_receiver: &Receiver<()>,
+        commands: Option<&Receiver<Command>>,
+        mut focus: Option<&mut Focus>,
+        mut impairment: Option<&mut Impairment>,
+        mut identity: Option<&mut LoopIdentity>,
+    ) -> Result<()> {
+        let mut playback = Playback::new(self.skip, self.realtime_factor);
+        let mut sent = 0;
+
+        loop {
+            let mut jump = None;
+            let mut skip = playback.offset;
+
+            self.recording
+                .for_each_selected(&self.selection, playback.cursor, |mut packet| {
+                    if skip > 0 {
+                        skip -= 1;
+                        return Ok(true);
+                    }
+
+                    if ctrl_receiver.try_recv().is_ok() {
+                        info!("Stopping playback");
+                        return Err(Error::msg("ctrl-c received"));
+                    }
+
+                    if let Some(commands) = commands {
+                        jump = self.control(&mut playback, &packet, commands, ctrl_receiver)?;
+                        if jump.is_some() {
+                            return Ok(false);
+                        }
+                    }
+
+                    if let Some(focus) = focus.as_mut() {
+                        if !focus.apply(&mut packet.data) {
+                            return Ok(true);
+                        }
+                    }
+                    if let Some(identity) = identity.as_mut() {
+                        identity.apply(&mut packet.data);
+                    }
+
+                    let first_timestamp = *playback.first_timestamp.get_or_insert(packet.timestamp);
+                    // The packets of a frame are sent together, at the deadline of the first one
+                    if playback.stepping.is_none() && playback.is_new_frame(&packet) {
+                        self.wait_for_frame(&mut playback, first_timestamp, packet.timestamp);
+                    }
+
+                    match impairment.as_mut() {
+                        Some(impairment) => {
+                            impairment.send(&self.socket, &packet.data, packet.packet_id)?
+                        }
+                        None => {
+                            self.socket.send(&packet.data)?;
+                        }
+                    }
+                    playback.advance(&packet);
+
+                    sent += 1;
+                    if sent % 500 == 0 && !playback.paused {
+                        let since_start = playback.start.elapsed().as_secs_f64();
+                        let expected_elapsed =
+                            (packet.timestamp - first_timestamp) / playback.speed as f64;
+
+                        info!(
+                            "{} packages sent, delay: {:.3}ms",
+                            sent,
+                            (since_start - expected_elapsed) * 1000.0
+                        )
+                    }
+
+                    Ok(true)
+                })?;
+
+            match jump {
+                Some(cursor) => playback.jump(cursor),
+                None => {
+                    playback.timing.log();
+                    if let Some(impairment) = impairment {
+                        impairment.flush(&self.socket)?;
+                        impairment.log_stats();
+                    }
+                    return Ok(());
+                }
             }
+        }
+    }
 
-            let packet = packet?;
-
-            self.delay_next_packet(playback_start, first_timestamp, packet.timestamp);
-
-            self.socket.send(&packet.data)?;
+    /// Apply the pending commands before sending `packet`, waiting for them while paused.
+    ///
+    /// Returns the position to continue from after a jump.
+    fn control(
+        &self,
+        playback: &mut Playback,
+        packet: &RecordedPacket,
+        commands: &Receiver<Command>,
+        ctrl_receiver: &Receiver<()>,
+    ) -> Result<Option<i64>> {
+        if playback.stepping.is_some_and(|frame| frame != packet.frame) {
+            playback.stepping = None;
+            playback.print_status();
+        }
 
-            if (idx + 1) % 500 == 0 {
-                let since_start = playback_start.elapsed().as_seconds_f64();
-                let expected_elapsed = packet.timestamp - first_timestamp;
+        loop {
+            let command = if playback.paused && playback.stepping.is_none() {
+                if ctrl_receiver.try_recv().is_ok() {
+                    info!("Stopping playback");
+                    return Err(Error::msg("ctrl-c received"));
+                }
+
+                match commands.recv_timeout(Duration::from_millis(100)) {
+                    Ok(command) => command,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => {
+                        // Nobody is left to resume the playback
+                        playback.resume();
+                        return Ok(None);
+                    }
+                }
+            } else {
+                match commands.try_recv() {
+                    Ok(command) => command,
+                    Err(_) => return Ok(None),
+                }
+            };
+
+            match command {
+                Command::TogglePause if playback.paused => playback.resume(),
+                Command::TogglePause => playback.paused = true,
+                Command::Step if playback.paused => playback.stepping = Some(packet.frame),
+                Command::Step => playback.paused = true,
+                Command::Faster => playback.set_speed(playback.speed * 2.0),
+                Command::Slower => playback.set_speed(playback.speed / 2.0),
+                Command::SetSpeed(speed) => playback.set_speed(speed),
+                Command::Jump(seconds) => match self.find_session_time(playback, seconds)? {
+                    Some(cursor) => return Ok(Some(cursor)),
+                    None => warn!("Nothing to jump to"),
+                },
+                Command::NextLap => match self.find_next_lap(playback)? {
+                    Some(cursor) => return Ok(Some(cursor)),
+                    None => warn!("No next lap in this session"),
+                },
+                Command::Status => {}
+                Command::Help => info!("{}", HELP),
+            }
 
-                info!(
-                    "{} packages sent, delay: {:.3}ms",
-                    idx + 1,
-                    (since_start - expected_elapsed) * 1000.0
-                )
+            if command != Command::Step || !playback.paused {
+                playback.print_status();
             }
         }
-
-        Ok(())
     }
 
-    fn get_first_timestamp(&self) -> Result<f64> {
-        let mut stmt = self.conn.prepare(
-            "SELECT timestamp FROM packets WHERE pkt_id = (SELECT min(pkt_id) FROM packets);",
-        )?;
+    /// First packet of the current session at the given offset from the current session time
+    fn find_session_time(&self, playback: &Playback, seconds: f64) -> Result<Option<i64>> {
+        let Some(session_id) = &playback.session_id else {
+            return Ok(None);
+        };
+        let session_time = (playback.session_time + seconds * 1000.0).max(0.0);
+
+        let selection = Selection {
+            session_id: Some(session_id.clone()),
+            from_session_time: Some(session_time as u32),
+            ..Default::default()
+        };
+        let mut cursor = None;
+        self.recording.for_each_selected(&selection, 0, |p| {
+            cursor = Some(p.pkt_id);
+            Ok(false)
+        })?;
+
+        Ok(cursor)
+    }
 
-        let mut rows = stmt.query([])?;
+    /// First LapData packet of the current session where the player is on a later lap
+    fn find_next_lap(&self, playback: &Playback) -> Result<Option<i64>> {
+        let (Some(session_id), Some(lap)) = (&playback.session_id, playback.lap) else {
+            return Ok(None);
+        };
 
-        Ok(rows.next()?.unwrap().get(0)?)
+        let selection = Selection {
+            session_id: Some(session_id.clone()),
+            packet_types: vec![PacketType::LapData],
+            ..Default::default()
+        };
+        let mut cursor = None;
+        self.recording
+            .for_each_selected(&selection, playback.cursor, |p| {
+                if player_lap(&p.data).is_some_and(|l| l > lap) {
+                    cursor = Some(p.pkt_id);
+                    return Ok(false);
+                }
+                Ok(true)
+            })?;
+
+        Ok(cursor)
     }
 
-    fn delay_next_packet(
+    /// Wait for the deadline of a new frame, relative to the start of the playback so that
+    /// errors don't accumulate.
+    ///
+    /// In realtime, the deadline follows the recorded timestamps. Otherwise, frames are paced by
+    /// the size of the previous ones so that consumers can keep up.
+    fn wait_for_frame(
         &self,
-        playback_start: Instant,
+        playback: &mut Playback,
         first_packet_timestamp: f64,
         packet_timestamp: f64,
     ) {
-        let duration = if self.realtime {
-            let expected_delay =
-                (packet_timestamp - first_packet_timestamp) / self.realtime_factor as f64;
-            let real_delay = playback_start.elapsed().as_seconds_f64();
-
-            if real_delay < expected_delay {
-                let delta = expected_delay - real_delay;
-                Some(Duration::from_secs_f64(delta))
-            } else {
-                None
-            }
-        } else {
-            Some(Duration::from_millis(1))
+        let expected_delay = match self.realtime {
+            true => (packet_timestamp - first_packet_timestamp) / playback.speed as f64,
+            false => playback.bytes as f64 / FAST_DATA_RATE,
         };
+        let offset = Duration::from_secs_f64(expected_delay.max(0.0));
+        playback.frame_timestamp = packet_timestamp;
+
+        let deadline = playback.start + offset;
+        wait_until(deadline);
+
+        let lateness = Instant::now().saturating_duration_since(deadline);
+        if lateness > MAX_LATENESS {
+            warn!(
+                "Playback is {:.3}s late, continuing from here",
+                lateness.as_secs_f64()
+            );
+            // Keep the next frames on time rather than sending them all at once
+            playback.start += lateness;
+            playback.timing.record_clock_shift();
+        } else {
+            playback.timing.record(lateness);
+        }
+    }
+}
+
+/// Position and timing of an ongoing playback
+struct Playback {
+    /// Packet to read from next
+    cursor: i64,
+    /// Number of packets to skip from the cursor
+    offset: u64,
+
+    speed: f32,
+    paused: bool,
+    /// Frame being sent while stepping
+    stepping: Option<u32>,
+    start: Instant,
+    first_timestamp: Option<f64>,
+    /// Bytes sent since the clock started
+    bytes: u64,
+    /// Timestamp of the first packet of the current frame
+    frame_timestamp: f64,
+    timing: TimingStats,
+
+    session_id: Option<String>,
+    /// Session time of the last sent packet, in milliseconds
+    session_time: f64,
+    frame: u32,
+    lap: Option<u8>,
+}
 
-        if let Some(d) = duration {
-            sleep(d);
+impl Playback {
+    fn new(skip: u64, speed: f32) -> Self {
+        Self {
+            cursor: 0,
+            offset: skip,
+            speed,
+            paused: false,
+            stepping: None,
+            start: Instant::now(),
+            first_timestamp: None,
+            bytes: 0,
+            frame_timestamp: 0.0,
+            timing: TimingStats::default(),
+            session_id: None,
+            session_time: 0.0,
+            frame: 0,
+            lap: None,
         }
     }
+
+    /// Whether `packet` starts a frame, rather than following a packet of the same frame.
+    ///
+    /// The frame identifier doesn't change while the game is paused, so packets received long
+    /// after the start of the frame start a new one.
+    fn is_new_frame(&self, packet: &RecordedPacket) -> bool {
+        self.session_id.as_ref() != Some(&packet.session_id)
+            || self.frame != packet.frame
+            || packet.timestamp - self.frame_timestamp > FRAME_WINDOW
+    }
+
+    fn advance(&mut self, packet: &RecordedPacket) {
+        self.cursor = packet.pkt_id + 1;
+        self.bytes += packet.data.len() as u64;
+
+        if self.session_id.as_ref() != Some(&packet.session_id) {
+            self.session_id = Some(packet.session_id.clone());
+            self.lap = None;
+        }
+        self.session_time = packet.session_time;
+        self.frame = packet.frame;
+
+        if packet.packet_id == u8::from(PacketType::LapData) {
+            self.lap = player_lap(&packet.data).or(self.lap);
+        }
+    }
+
+    fn jump(&mut self, cursor: i64) {
+        self.cursor = cursor;
+        self.offset = 0;
+        self.reset_clock();
+    }
+
+    fn resume(&mut self) {
+        self.paused = false;
+        self.stepping = None;
+        self.reset_clock();
+    }
+
+    fn set_speed(&mut self, speed: f32) {
+        self.speed = speed;
+        self.reset_clock();
+    }
+
+    /// Restart the timing from the next packet, after a change of position or speed.
+    fn reset_clock(&mut self) {
+        self.start = Instant::now();
+        self.first_timestamp = None;
+        self.bytes = 0;
+    }
+
+    fn print_status(&self) {
+        let lap = match self.lap {
+            Some(l) => l.to_string(),
+            None => String::from("?"),
+        };
+
+        info!(
+            "{} session {}, time {:.3}s, lap {}, frame {}, speed {:.2}x",
+            if self.paused { "Paused in" } else { "Playing" },
+            self.session_id.as_deref().unwrap_or("?").trim(),
+            self.session_time / 1000.0,
+            lap,
+            self.frame,
+            self.speed
+        );
+    }
 }
@@ -0,0 +1,116 @@
+use std::io::stdin;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+
+use anyhow::{Error, Result};
+use log::warn;
+
+pub(super) const HELP: &str =
+    "Commands: p (pause/resume), s (step one frame), + / - (double/halve speed), \
+x <factor> (set speed), f [seconds] / b [seconds] (jump forward/back, default 10s), \
+n (next lap), i (status), h (help)";
+
+/// Seconds jumped by `f` and `b` without an argument
+const DEFAULT_JUMP: f64 = 10.0;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(super) enum Command {
+    TogglePause,
+    Step,
+    Faster,
+    Slower,
+    SetSpeed(f32),
+    /// Jump by a number of seconds of session time, backwards if negative
+    Jump(f64),
+    NextLap,
+    Status,
+    Help,
+}
+
+impl Command {
+    pub(super) fn parse(line: &str) -> Result<Self> {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        let argument = words.next();
+
+        let seconds = || -> Result<f64> {
+            match argument {
+                Some(a) => a
+                    .parse()
+                    .map_err(|_| Error::msg(format!("Invalid number of seconds: {}", a))),
+                None => Ok(DEFAULT_JUMP),
+            }
+        };
+
+        let command = match command {
+            "p" | "pause" => Command::TogglePause,
+            "s" | "step" => Command::Step,
+            "+" => Command::Faster,
+            "-" => Command::Slower,
+            "x" | "speed" => {
+                let factor: f32 = argument
+                    .and_then(|a| a.parse().ok())
+                    .filter(|f: &f32| *f > 0.0)
+                    .ok_or_else(|| Error::msg("Expected a positive speed factor"))?;
+                Command::SetSpeed(factor)
+            }
+            "f" | "forward" => Command::Jump(seconds()?),
+            "b" | "back" => Command::Jump(-seconds()?),
+            "n" | "next" => Command::NextLap,
+            "i" | "info" => Command::Status,
+            "h" | "help" | "?" => Command::Help,
+            c => return Err(Error::msg(format!("Unknown command: {}", c))),
+        };
+
+        Ok(command)
+    }
+}
+
+/// Read commands from stdin, one per line, in a background thread.
+pub(super) fn command_channel() -> Receiver<Command> {
+    let (sender, receiver) = channel();
+
+    thread::spawn(move || {
+        for line in stdin().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match Command::parse(&line) {
+                Ok(command) => {
+                    if sender.send(command).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => warn!("{}. {}", e, HELP),
+            }
+        }
+    });
+
+    receiver
+}
+
+#[cfg(test)]
+mod test_command {
+    use super::Command;
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!(Command::parse("p").unwrap(), Command::TogglePause);
+        assert_eq!(Command::parse(" s ").unwrap(), Command::Step);
+        assert_eq!(Command::parse("x 0.5").unwrap(), Command::SetSpeed(0.5));
+        assert_eq!(Command::parse("f").unwrap(), Command::Jump(10.0));
+        assert_eq!(Command::parse("b 30").unwrap(), Command::Jump(-30.0));
+    }
+
+    #[test]
+    fn test_parse_invalid_commands() {
+        assert!(Command::parse("x").is_err());
+        assert!(Command::parse("x -1").is_err());
+        assert!(Command::parse("f ten").is_err());
+        assert!(Command::parse("q").is_err());
+    }
+}
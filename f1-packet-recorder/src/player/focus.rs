@@ -0,0 +1,268 @@
+use std::fmt;
+
+use anyhow::{Error, Result};
+use log::info;
+
+use f1_telemetry::packet::participants::PacketParticipantsData;
+use f1_telemetry::packet::{parse_packet, Packet, PacketType};
+
+use crate::recording::Recording;
+use crate::selection::Selection;
+use crate::utils::HeaderLayout;
+
+/// Cars of the largest grids, from F1 2020 on
+const MAX_CARS: u8 = 22;
+
+/// Car to present as the player's car during the replay
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum FocusTarget {
+    Index(u8),
+    /// Name of a participant, resolved from the Participants packets
+    Name(String),
+}
+
+impl FocusTarget {
+    /// A car index, or the name of a participant
+    pub(crate) fn parse(value: &str) -> Result<Self> {
+        match value.trim().parse::<u64>() {
+            Ok(idx) if idx < MAX_CARS as u64 => Ok(FocusTarget::Index(idx as u8)),
+            Ok(idx) => Err(Error::msg(format!(
+                "Invalid car index: {} (0 to {})",
+                idx,
+                MAX_CARS - 1
+            ))),
+            Err(_) => Ok(FocusTarget::Name(value.trim().to_string())),
+        }
+    }
+
+    fn resolve(&self, participants: &PacketParticipantsData) -> Option<u8> {
+        match self {
+            FocusTarget::Index(idx) => Some(*idx),
+            FocusTarget::Name(name) => participants
+                .participants
+                .iter()
+                .position(|p| p.name.trim().eq_ignore_ascii_case(name))
+                .map(|idx| idx as u8),
+        }
+    }
+}
+
+impl fmt::Display for FocusTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FocusTarget::Index(idx) => write!(f, "car {}", idx),
+            FocusTarget::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Rewrites the headers of the replayed packets so that consumers follow another car.
+pub(super) struct Focus {
+    target: FocusTarget,
+    car_index: Option<u8>,
+}
+
+impl Focus {
+    /// Check that the target exists in the recording, using the first Participants packet of
+    /// the session (or of the recording without a session).
+    pub(super) fn new(
+        target: FocusTarget,
+        recording: &Recording,
+        session_id: Option<&str>,
+    ) -> Result<Self> {
+        let mut focus = Focus {
+            target,
+            car_index: None,
+        };
+
+        if let FocusTarget::Index(idx) = focus.target {
+            focus.car_index = Some(idx);
+            return Ok(focus);
+        }
+
+        let participants = Selection {
+            session_id: session_id.map(str::to_string),
+            packet_types: vec![PacketType::Participants],
+            ..Default::default()
+        };
+        recording.for_each_selected(&participants, 0, |p| {
+            focus.update(&p.data);
+            Ok(focus.car_index.is_none())
+        })?;
+
+        if let Some(car_index) = focus.car_index {
+            info!("Following {} (car {})", focus.target, car_index);
+            return Ok(focus);
+        }
+
+        Err(Error::msg(format!(
+            "{} not found in the participants",
+            focus.target
+        )))
+    }
+
+    /// Rewrite `player_car_index` in a raw packet, and clear the fields about the recording player.
+    ///
+    /// Returns false for packets only holding data about the recording player, which would be
+    /// attributed to the focused car otherwise.
+    pub(super) fn apply(&mut self, data: &mut [u8]) -> bool {
+        self.update(data);
+
+        let (Some(car_index), Some(layout)) = (self.car_index, HeaderLayout::of(data)) else {
+            return true;
+        };
+
+        if data[layout.player_car_index] == car_index {
+            return true;
+        }
+
+        if data[layout.packet_id] == u8::from(PacketType::MotionEx) {
+            return false;
+        }
+
+        data[layout.player_car_index] = car_index;
+        if let Some(offset) = layout.secondary_player_car_index {
+            data[offset] = NO_CAR;
+        }
+        clear_player_data(data, &layout);
+
+        true
+    }
+
+    /// Follow the target by name across sessions, where its index may change.
+    fn update(&mut self, data: &[u8]) {
+        if let FocusTarget::Index(_) = self.target {
+            return;
+        }
+        let is_participants = HeaderLayout::of(data)
+            .is_some_and(|l| data[l.packet_id] == u8::from(PacketType::Participants));
+        if !is_participants {
+            return;
+        }
+
+        if let Ok(Packet::Participants(p)) = parse_packet(data.len(), data) {
+            if let Some(idx) = self.target.resolve(&p) {
+                self.car_index = Some(idx);
+            }
+        }
+    }
+}
+
+/// Car index of a missing second player, and value of a closed MFD panel
+const NO_CAR: u8 = 255;
+
+/// Size of the motion data of a car, before 2023
+const CAR_MOTION_SIZE: usize = 60;
+
+/// Reset the player-only fields of a packet, which can't be rebuilt for another car.
+fn clear_player_data(data: &mut [u8], layout: &HeaderLayout) {
+    let packet_format = u16::from_le_bytes([data[0], data[1]]);
+    let packet_id = data[layout.packet_id];
+
+    if packet_id == u8::from(PacketType::Motion) && packet_format < 2023 {
+        // Suspension, wheels and local velocities of the player's car, after the data of every
+        // car. Moved to the Motion Ex packet in 2023.
+        let cars = if packet_format == 2019 { 20 } else { 22 };
+        let start = layout.size + cars * CAR_MOTION_SIZE;
+        if let Some(player_data) = data.get_mut(start..) {
+            player_data.fill(0);
+        }
+    } else if packet_id == u8::from(PacketType::CarTelemetry) {
+        // Buttons until 2020, then MFD panels of both players and the suggested gear
+        let player_data: &[u8] = match packet_format {
+            2019 => &[0, 0, 0, 0],
+            2020 => &[0, 0, 0, 0, NO_CAR, NO_CAR, 0],
+            _ => &[NO_CAR, NO_CAR, 0],
+        };
+        if let Some(start) = data.len().checked_sub(player_data.len()) {
+            data[start..].copy_from_slice(player_data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_focus {
+    use f1_telemetry::packet::{parse_packet, Packet};
+
+    use super::{Focus, FocusTarget};
+
+    fn focus_on_car_5() -> Focus {
+        Focus {
+            target: FocusTarget::Index(5),
+            car_index: Some(5),
+        }
+    }
+
+    #[test]
+    fn test_parse_target() {
+        assert_eq!(FocusTarget::parse("3").unwrap(), FocusTarget::Index(3));
+        assert_eq!(FocusTarget::parse("21").unwrap(), FocusTarget::Index(21));
+        assert_eq!(
+            FocusTarget::parse(" Hamilton").unwrap(),
+            FocusTarget::Name("Hamilton".to_string())
+        );
+
+        assert!(FocusTarget::parse("22").is_err());
+        assert!(FocusTarget::parse("300").is_err());
+    }
+
+    #[test]
+    fn test_rewrite_player_car_index() {
+        let mut focus = focus_on_car_5();
+
+        // 2021 Lap Data header
+        let mut packet = vec![0u8; 24];
+        packet[0..2].copy_from_slice(&2021u16.to_le_bytes());
+        packet[5] = 2;
+        packet[22] = 1;
+
+        assert!(focus.apply(&mut packet));
+        assert_eq!(packet[22], 5);
+
+        // 2023 Motion Ex header
+        let mut packet = vec![0u8; 29];
+        packet[0..2].copy_from_slice(&2023u16.to_le_bytes());
+        packet[6] = 13;
+        packet[27] = 1;
+
+        assert!(!focus.apply(&mut packet));
+    }
+
+    #[test]
+    fn test_clear_motion_player_data() {
+        for (packet_format, header_size, cars) in [(2019u16, 23, 20), (2021, 24, 22)] {
+            let player_data = header_size + cars * 60;
+            let mut packet = vec![0u8; player_data + 120];
+            packet[0..2].copy_from_slice(&packet_format.to_le_bytes());
+            packet[22] = 1;
+            // Position of the first car, and wheel speeds of the player
+            packet[header_size..header_size + 4].copy_from_slice(&10.0f32.to_le_bytes());
+            packet[player_data..].fill(0x41);
+
+            assert!(focus_on_car_5().apply(&mut packet));
+
+            let Ok(Packet::Motion(motion)) = parse_packet(packet.len(), &packet) else {
+                panic!("not a {} motion packet", packet_format);
+            };
+            assert_eq!(motion.header.player_car_index, 5);
+            assert_eq!(motion.motion_data[0].world_position_x, 10.0);
+            assert!(packet[player_data..].iter().all(|b| *b == 0));
+        }
+    }
+
+    #[test]
+    fn test_clear_car_telemetry_player_data() {
+        // 2021 Car Telemetry packet, with the second player's car and the player's MFD panels
+        let mut packet = vec![0u8; 1347];
+        packet[0..2].copy_from_slice(&2021u16.to_le_bytes());
+        packet[5] = 6;
+        packet[22] = 1;
+        packet[23] = 2;
+        packet[1344..].copy_from_slice(&[3, 1, 4]);
+
+        assert!(focus_on_car_5().apply(&mut packet));
+
+        assert_eq!(packet[22..24], [5, 255]);
+        assert_eq!(packet[1344..], [255, 255, 0]);
+    }
+}
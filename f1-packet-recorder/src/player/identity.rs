@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+
+use log::info;
+
+use crate::utils::HeaderLayout;
+
+/// Rewrites the headers of the replayed packets on every loop after the first one, so that
+/// consumers see a new session rather than the same one going back in time.
+pub(super) struct LoopIdentity {
+    /// Give every session a new UID
+    new_session: bool,
+    /// Keep the session time and frame identifiers increasing from one loop to the next
+    continue_time: bool,
+
+    iteration: u32,
+    /// New UIDs of the sessions of the current loop
+    session_uids: HashMap<u64, u64>,
+    /// Offsets of the current loop, in seconds and frames
+    time_offset: f32,
+    frame_offset: u32,
+    /// Largest values sent during the current loop, where the next loop continues from
+    max_session_time: f32,
+    max_frame: u32,
+}
+
+impl LoopIdentity {
+    /// `None` when the packets are replayed as recorded.
+    pub(super) fn new(new_session: bool, continue_time: bool) -> Option<Self> {
+        (new_session || continue_time).then(|| Self {
+            new_session,
+            continue_time,
+            iteration: 0,
+            session_uids: HashMap::new(),
+            time_offset: 0.0,
+            frame_offset: 0,
+            max_session_time: 0.0,
+            max_frame: 0,
+        })
+    }
+
+    /// Start the next loop.
+    pub(super) fn next_loop(&mut self) {
+        self.iteration += 1;
+        self.session_uids.clear();
+
+        if self.continue_time {
+            self.time_offset = self.max_session_time;
+            self.frame_offset = self.max_frame.saturating_add(1);
+        }
+
+        info!(
+            "Starting loop {}{}",
+            self.iteration + 1,
+            match self.continue_time {
+                true => format!(
+                    " (session time +{:.3}s, frames +{})",
+                    self.time_offset, self.frame_offset
+                ),
+                false => String::new(),
+            }
+        );
+    }
+
+    /// Rewrite the header of a raw packet for the current loop.
+    pub(super) fn apply(&mut self, data: &mut [u8]) {
+        let Some(layout) = HeaderLayout::of(data) else {
+            return;
+        };
+
+        if self.continue_time {
+            let session_time = read_f32(data, layout.session_time) + self.time_offset;
+            data[layout.session_time..layout.session_time + 4]
+                .copy_from_slice(&session_time.to_le_bytes());
+            self.max_session_time = self.max_session_time.max(session_time);
+
+            let mut frame_fields = vec![layout.frame_identifier];
+            frame_fields.extend(layout.overall_frame_identifier);
+            for offset in frame_fields {
+                let frame = read_u32(data, offset).saturating_add(self.frame_offset);
+                data[offset..offset + 4].copy_from_slice(&frame.to_le_bytes());
+                self.max_frame = self.max_frame.max(frame);
+            }
+        }
+
+        if self.new_session && self.iteration > 0 {
+            let session_uid = u64::from_le_bytes(
+                data[layout.session_uid..layout.session_uid + 8]
+                    .try_into()
+                    .unwrap(),
+            );
+            // Packets outside of sessions keep their null UID
+            if session_uid != 0 {
+                let new_uid = *self
+                    .session_uids
+                    .entry(session_uid)
+                    .or_insert_with(rand::random);
+                data[layout.session_uid..layout.session_uid + 8]
+                    .copy_from_slice(&new_uid.to_le_bytes());
+            }
+        }
+    }
+}
+
+fn read_f32(data: &[u8], offset: usize) -> f32 {
+    f32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod test_identity {
+    use super::{read_f32, read_u32, LoopIdentity};
+
+    /// 2021 header of session 0xaaaa
+    fn packet(session_time: f32, frame: u32) -> Vec<u8> {
+        let mut packet = vec![0u8; 24];
+        packet[0..2].copy_from_slice(&2021u16.to_le_bytes());
+        packet[6..14].copy_from_slice(&0xaaaau64.to_le_bytes());
+        packet[14..18].copy_from_slice(&session_time.to_le_bytes());
+        packet[18..22].copy_from_slice(&frame.to_le_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_first_loop_is_unchanged() {
+        let mut identity = LoopIdentity::new(true, true).unwrap();
+        let mut first = packet(12.5, 100);
+        identity.apply(&mut first);
+
+        assert_eq!(first, packet(12.5, 100));
+    }
+
+    #[test]
+    fn test_next_loop() {
+        let mut identity = LoopIdentity::new(true, true).unwrap();
+        identity.apply(&mut packet(0.0, 0));
+        identity.apply(&mut packet(60.0, 3600));
+        identity.next_loop();
+
+        let mut a = packet(0.5, 30);
+        let mut b = packet(1.0, 60);
+        identity.apply(&mut a);
+        identity.apply(&mut b);
+
+        assert_eq!(read_f32(&a, 14), 60.5);
+        assert_eq!(read_u32(&a, 18), 3631);
+        assert_eq!(read_u32(&b, 18), 3661);
+
+        // Same new UID for the whole session
+        assert_ne!(&a[6..14], &0xaaaau64.to_le_bytes());
+        assert_eq!(&a[6..14], &b[6..14]);
+    }
+}
@@ -0,0 +1,346 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::net::UdpSocket;
+use std::str::FromStr;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use anyhow::{Error, Result};
+use log::info;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use f1_telemetry::packet::PacketType;
+
+use crate::utils::parse_packet_type;
+use crate::ImpairmentArgs;
+
+/// Loss rate of a packet type, like `motion=50`
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct PacketLoss {
+    pub(crate) packet_type: PacketType,
+    /// Percentage of packets dropped
+    pub(crate) percent: f64,
+}
+
+impl FromStr for PacketLoss {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (packet_type, percent) = s
+            .split_once('=')
+            .ok_or_else(|| Error::msg(format!("Expected PACKET_TYPE=PERCENT, got {}", s)))?;
+
+        Ok(Self {
+            packet_type: parse_packet_type(packet_type.trim())?,
+            percent: parse_percentage(percent)?,
+        })
+    }
+}
+
+pub(crate) fn parse_percentage(value: &str) -> Result<f64> {
+    value
+        .trim()
+        .parse()
+        .ok()
+        .filter(|p| (0.0..=100.0).contains(p))
+        .ok_or_else(|| Error::msg(format!("Invalid percentage: {}", value)))
+}
+
+/// Maximum delay of the jitter, in milliseconds
+pub(crate) fn parse_jitter(value: &str) -> Result<f64> {
+    value
+        .trim()
+        .parse()
+        .ok()
+        .filter(|j: &f64| j.is_finite() && *j >= 0.0)
+        .ok_or_else(|| Error::msg(format!("Invalid jitter: {}", value)))
+}
+
+/// Counters of the simulated network issues
+#[derive(Debug, Default)]
+struct ImpairmentStats {
+    dropped: u64,
+    duplicated: u64,
+    /// Packets released from the reordering window ahead of older ones
+    reordered: u64,
+    max_delay: Duration,
+}
+
+/// Simulates a bad network between the player and its consumers: packets go through random
+/// loss, duplication, a reordering window and a random delay before being sent.
+///
+/// Every random draw comes from a single seeded generator, so a seed replays the same decisions.
+/// Delays are only as precise as the pace of the playback: delayed packets are sent when the next
+/// packet is played, or when the playback ends.
+pub(super) struct Impairment {
+    rng: StdRng,
+    /// Probability to drop a packet, by packet ID
+    loss: HashMap<u8, f64>,
+    default_loss: f64,
+    duplicate: f64,
+    jitter: Duration,
+    reorder_window: usize,
+
+    /// Packets waiting to be released in a random order
+    window: Vec<Vec<u8>>,
+    /// Packets waiting for their delay to pass, with their sequence number to keep the order of
+    /// packets due at the same time
+    delayed: BinaryHeap<Reverse<(Instant, u64, Vec<u8>)>>,
+    sequence: u64,
+    stats: ImpairmentStats,
+}
+
+impl Impairment {
+    /// `None` when no impairment is enabled, to send packets untouched.
+    pub(super) fn new(args: &ImpairmentArgs) -> Option<Self> {
+        let enabled = args.drop > 0.0
+            || args.drop_type.iter().any(|l| l.percent > 0.0)
+            || args.duplicate > 0.0
+            || args.jitter > 0.0
+            || args.reorder_window > 1;
+        if !enabled {
+            return None;
+        }
+
+        let seed = args.seed.unwrap_or_else(rand::random);
+        info!(
+            "Simulating network issues (seed {}): drop {}%{}, duplicate {}%, jitter {}ms, \
+            reorder window {}",
+            seed,
+            args.drop,
+            args.drop_type
+                .iter()
+                .map(|l| format!(", {} {}%", l.packet_type.name(), l.percent))
+                .collect::<String>(),
+            args.duplicate,
+            args.jitter,
+            args.reorder_window
+        );
+
+        Some(Self {
+            rng: StdRng::seed_from_u64(seed),
+            loss: args
+                .drop_type
+                .iter()
+                .map(|l| (l.packet_type as u8, l.percent / 100.0))
+                .collect(),
+            default_loss: args.drop / 100.0,
+            duplicate: args.duplicate / 100.0,
+            jitter: Duration::from_secs_f64(args.jitter / 1000.0),
+            reorder_window: args.reorder_window.max(1),
+            window: Vec::new(),
+            delayed: BinaryHeap::new(),
+            sequence: 0,
+            stats: ImpairmentStats::default(),
+        })
+    }
+
+    /// Send a packet through the simulated network, along with the delayed packets now due.
+    pub(super) fn send(&mut self, socket: &UdpSocket, data: &[u8], packet_id: u8) -> Result<()> {
+        self.push(data, packet_id, Instant::now());
+        self.send_due(socket)
+    }
+
+    /// Release every held packet, waiting for their delays.
+    pub(super) fn flush(&mut self, socket: &UdpSocket) -> Result<()> {
+        while !self.window.is_empty() {
+            self.release_one(Instant::now());
+        }
+
+        while let Some(Reverse((due, _, _))) = self.delayed.peek() {
+            if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                sleep(wait);
+            }
+            self.send_due(socket)?;
+        }
+
+        Ok(())
+    }
+
+    pub(super) fn log_stats(&self) {
+        info!(
+            "Simulated network: dropped {}, duplicated {}, reordered {} packets, max delay {:.1}ms",
+            self.stats.dropped,
+            self.stats.duplicated,
+            self.stats.reordered,
+            self.stats.max_delay.as_secs_f64() * 1000.0
+        );
+    }
+
+    /// Drop, duplicate or hold a packet received at `now`.
+    fn push(&mut self, data: &[u8], packet_id: u8, now: Instant) {
+        let loss = self.loss.get(&packet_id).copied();
+        if self.rng.gen_bool(loss.unwrap_or(self.default_loss)) {
+            self.stats.dropped += 1;
+        } else {
+            self.enter_window(data.to_vec(), now);
+            if self.rng.gen_bool(self.duplicate) {
+                self.stats.duplicated += 1;
+                self.enter_window(data.to_vec(), now);
+            }
+        }
+    }
+
+    fn enter_window(&mut self, data: Vec<u8>, now: Instant) {
+        self.window.push(data);
+        if self.window.len() >= self.reorder_window {
+            self.release_one(now);
+        }
+    }
+
+    /// Release a random packet of the reordering window, with a random delay.
+    fn release_one(&mut self, now: Instant) {
+        let idx = self.rng.gen_range(0..self.window.len());
+        if idx > 0 {
+            self.stats.reordered += 1;
+        }
+        let data = self.window.remove(idx);
+
+        let delay = match self.jitter.is_zero() {
+            true => Duration::ZERO,
+            false => self.rng.gen_range(Duration::ZERO..=self.jitter),
+        };
+        self.stats.max_delay = self.stats.max_delay.max(delay);
+
+        self.sequence += 1;
+        self.delayed
+            .push(Reverse((now + delay, self.sequence, data)));
+    }
+
+    fn send_due(&mut self, socket: &UdpSocket) -> Result<()> {
+        for data in self.take_due(Instant::now()) {
+            socket.send(&data)?;
+        }
+
+        Ok(())
+    }
+
+    /// Delayed packets due at `now`, in the order to send them
+    fn take_due(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let mut due = Vec::new();
+
+        while self
+            .delayed
+            .peek()
+            .is_some_and(|Reverse((d, _, _))| *d <= now)
+        {
+            let Some(Reverse((_, _, data))) = self.delayed.pop() else {
+                break;
+            };
+            due.push(data);
+        }
+
+        due
+    }
+}
+
+#[cfg(test)]
+mod test_impairment {
+    use std::time::{Duration, Instant};
+
+    use f1_telemetry::packet::PacketType;
+
+    use super::{parse_jitter, parse_percentage, Impairment, PacketLoss};
+    use crate::ImpairmentArgs;
+
+    fn args(drop: f64, duplicate: f64, jitter: f64, reorder_window: usize) -> ImpairmentArgs {
+        ImpairmentArgs {
+            drop,
+            drop_type: vec![],
+            duplicate,
+            jitter,
+            reorder_window,
+            seed: Some(42),
+        }
+    }
+
+    /// Packets coming out of the simulated network, for 200 packets sent every millisecond
+    fn run(args: &ImpairmentArgs) -> Vec<Vec<u8>> {
+        let mut impairment = Impairment::new(args).unwrap();
+        let start = Instant::now();
+        let mut sent = Vec::new();
+
+        for i in 0..200u32 {
+            let now = start + Duration::from_millis(i as u64);
+            impairment.push(&i.to_le_bytes(), (i % 12) as u8, now);
+            sent.extend(impairment.take_due(now));
+        }
+
+        let end = start + Duration::from_secs(1);
+        while !impairment.window.is_empty() {
+            impairment.release_one(end);
+        }
+        sent.extend(impairment.take_due(end + Duration::from_secs(1)));
+
+        sent
+    }
+
+    #[test]
+    fn test_seed_reproduces_the_run() {
+        let args = args(20.0, 20.0, 5.0, 4);
+        let sent = run(&args);
+
+        assert_eq!(run(&args), sent);
+        // Some packets went through each impairment
+        assert!(sent.len() != 200);
+        let mut ordered = sent.clone();
+        ordered.sort_by_key(|data| u32::from_le_bytes(data[..4].try_into().unwrap()));
+        assert_ne!(ordered, sent);
+
+        let other_seed = ImpairmentArgs {
+            seed: Some(7),
+            ..args
+        };
+        assert_ne!(run(&other_seed), sent);
+    }
+
+    #[test]
+    fn test_drop_and_duplicate_everything() {
+        assert!(run(&args(100.0, 0.0, 0.0, 1)).is_empty());
+
+        let sent = run(&args(0.0, 100.0, 0.0, 1));
+        assert_eq!(sent.len(), 400);
+        for pair in sent.chunks(2) {
+            assert_eq!(pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn test_parse_jitter() {
+        assert_eq!(parse_jitter("2.5").unwrap(), 2.5);
+        assert_eq!(parse_jitter("0").unwrap(), 0.0);
+        assert!(parse_jitter("-5").is_err());
+        assert!(parse_jitter("NaN").is_err());
+        assert!(parse_jitter("inf").is_err());
+    }
+
+    #[test]
+    fn test_parse_packet_loss() {
+        assert_eq!(
+            "motion=50".parse::<PacketLoss>().unwrap(),
+            PacketLoss {
+                packet_type: PacketType::Motion,
+                percent: 50.0
+            }
+        );
+        assert_eq!(
+            "lap_data = 2.5".parse::<PacketLoss>().unwrap(),
+            PacketLoss {
+                packet_type: PacketType::LapData,
+                percent: 2.5
+            }
+        );
+        assert!("motion".parse::<PacketLoss>().is_err());
+        assert!("motion=150".parse::<PacketLoss>().is_err());
+        assert!("engine=10".parse::<PacketLoss>().is_err());
+    }
+
+    #[test]
+    fn test_parse_percentage() {
+        assert_eq!(parse_percentage("0").unwrap(), 0.0);
+        assert_eq!(parse_percentage("100").unwrap(), 100.0);
+        assert!(parse_percentage("-1").is_err());
+        assert!(parse_percentage("abc").is_err());
+    }
+}
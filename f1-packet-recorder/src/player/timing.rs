@@ -0,0 +1,104 @@
+use std::thread::{sleep, yield_now};
+use std::time::{Duration, Instant};
+
+use log::info;
+
+/// Time left before a deadline under which the player spins instead of sleeping, as sleeps can
+/// overshoot by about a scheduler tick.
+const SPIN_THRESHOLD: Duration = Duration::from_micros(1500);
+
+/// Lateness above which the playback shifts its clock instead of sending a burst to catch up,
+/// after the process was suspended for example
+pub(super) const MAX_LATENESS: Duration = Duration::from_secs(1);
+
+/// Time over which the packets of a frame are received, in seconds
+pub(super) const FRAME_WINDOW: f64 = 0.005;
+
+/// Data rate when not playing in realtime, in bytes per second. About six times the rate of a game
+/// sending every packet at 60Hz, and slow enough for consumers to keep up.
+pub(super) const FAST_DATA_RATE: f64 = 2_000_000.0;
+
+/// Block until `deadline`: sleep while it is far away, then spin until it passes.
+///
+/// The spin yields to the other threads, which would otherwise starve on a single core.
+pub(super) fn wait_until(deadline: Instant) {
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return;
+        }
+
+        let left = deadline - now;
+        if left > SPIN_THRESHOLD {
+            sleep(left - SPIN_THRESHOLD);
+        } else {
+            yield_now();
+        }
+    }
+}
+
+/// How late each frame was sent compared to its deadline
+#[derive(Debug, Default)]
+pub(super) struct TimingStats {
+    lateness: Vec<Duration>,
+    /// Times the clock was shifted because the playback fell too far behind
+    clock_shifts: u64,
+}
+
+impl TimingStats {
+    pub(super) fn record(&mut self, lateness: Duration) {
+        self.lateness.push(lateness);
+    }
+
+    pub(super) fn record_clock_shift(&mut self) {
+        self.clock_shifts += 1;
+    }
+
+    pub(super) fn log(&mut self) {
+        if self.lateness.is_empty() {
+            return;
+        }
+
+        self.lateness.sort_unstable();
+        let ms = |p: f64| percentile(&self.lateness, p).as_secs_f64() * 1000.0;
+
+        info!(
+            "Timing error over {} frames: p50 {:.3}ms, p90 {:.3}ms, p99 {:.3}ms, max {:.3}ms \
+            ({} clock shifts)",
+            self.lateness.len(),
+            ms(50.0),
+            ms(90.0),
+            ms(99.0),
+            ms(100.0),
+            self.clock_shifts
+        );
+    }
+}
+
+/// Nearest-rank percentile of sorted values
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod test_timing {
+    use std::time::Duration;
+
+    use super::percentile;
+
+    #[test]
+    fn test_percentile() {
+        let values: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+
+        assert_eq!(percentile(&values, 50.0), Duration::from_millis(50));
+        assert_eq!(percentile(&values, 99.0), Duration::from_millis(99));
+        assert_eq!(percentile(&values, 100.0), Duration::from_millis(100));
+        assert_eq!(percentile(&values, 0.0), Duration::from_millis(1));
+        assert_eq!(percentile(&[], 50.0), Duration::ZERO);
+    }
+}
@@ -0,0 +1,285 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use log::{info, warn};
+use rusqlite::{params, Connection};
+
+use f1_telemetry::packet::parse_packet;
+
+use crate::db::PacketSource;
+use crate::recorder::{insert_packet, TimestampedPacket};
+use crate::sessions::Sessions;
+use crate::utils::get_database_connection;
+
+use super::ReparseArgs;
+
+/// A datagram that could not be parsed, kept as received so it can be parsed again by a later
+/// version of the library.
+pub(crate) struct QuarantinedPacket {
+    pub(crate) timestamp: f64,
+    pub(crate) source: PacketSource,
+    pub(crate) error: String,
+    pub(crate) data: Vec<u8>,
+}
+
+pub(crate) fn init_quarantine_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "
+        CREATE TABLE IF NOT EXISTS quarantine (
+            quarantine_id INTEGER PRIMARY KEY,
+            timestamp     REAL    NOT NULL,
+            error         TEXT    NOT NULL,
+            packet        BLOB    NOT NULL
+        );
+        ",
+        (),
+    )?;
+
+    Ok(())
+}
+
+pub(crate) fn insert_quarantined(conn: &Connection, packets: &[QuarantinedPacket]) -> Result<()> {
+    let mut stmt = conn.prepare_cached(
+        "
+        INSERT INTO quarantine(timestamp, source, sourceLabel, error, packet)
+        VALUES (?, ?, ?, ?, ?);
+        ",
+    )?;
+
+    conn.execute("BEGIN;", ())?;
+    for p in packets {
+        stmt.execute(params![
+            p.timestamp,
+            p.source.address,
+            p.source.label,
+            p.error,
+            p.data
+        ])?;
+    }
+    conn.execute("COMMIT;", ())?;
+
+    Ok(())
+}
+
+/// Parse the quarantined packets again, and move the ones that can now be parsed to the
+/// `packets` table.
+pub(crate) fn reparse(args: &ReparseArgs) -> Result<()> {
+    let conn = get_database_connection(&args.file)?;
+
+    let mut quarantined = Vec::new();
+    {
+        let mut stmt = conn.prepare(
+            "
+            SELECT quarantine_id, timestamp, source, sourceLabel, packet
+            FROM quarantine ORDER BY quarantine_id;
+            ",
+        )?;
+        let mut rows = stmt.query(())?;
+        while let Some(row) = rows.next()? {
+            quarantined.push((
+                row.get::<_, i64>(0)?,
+                row.get::<_, f64>(1)?,
+                PacketSource {
+                    address: row.get(2)?,
+                    label: row.get(3)?,
+                },
+                row.get::<_, Vec<u8>>(4)?,
+            ));
+        }
+    }
+
+    info!(
+        "Parsing {} quarantined packets from {}",
+        quarantined.len(),
+        args.file
+    );
+
+    let mut ids = Vec::new();
+    let mut packets = Vec::new();
+    for (quarantine_id, timestamp, source, data) in quarantined {
+        if let Ok(packet) = parse_packet(data.len(), &data) {
+            ids.push(quarantine_id);
+            packets.push(TimestampedPacket {
+                timestamp,
+                source,
+                packet,
+                data,
+            });
+        }
+    }
+
+    let remaining = count_quarantined(&conn)? - ids.len() as u64;
+    if packets.is_empty() {
+        warn!(
+            "No quarantined packet can be parsed, {} remaining",
+            remaining
+        );
+        return Ok(());
+    }
+
+    // Moved packets are only removed from the quarantine along with their insertion.
+    // Rolled back when dropped, on errors.
+    let tx = conn.unchecked_transaction()?;
+
+    // Packets are read in `pkt_id` order, put the moved ones in place
+    packets.sort_by(|a, b| a.timestamp.total_cmp(&b.timestamp));
+    let renumbering = Renumbering::new(
+        &stored_timestamps(&tx)?,
+        &packets.iter().map(|p| p.timestamp).collect::<Vec<_>>(),
+    );
+    renumbering.apply(&tx)?;
+    for (p, pkt_id) in packets.iter().zip(&renumbering.merged_ids) {
+        insert_packet(&tx, p, Some(*pkt_id))?;
+    }
+
+    let mut stmt = tx.prepare_cached("DELETE FROM quarantine WHERE quarantine_id = ?;")?;
+    for quarantine_id in &ids {
+        stmt.execute([quarantine_id])?;
+    }
+    drop(stmt);
+
+    // Add the moved packets to the summaries of their sessions
+    let mut sessions = Sessions::default();
+    for p in &packets {
+        sessions.update(p.timestamp, &p.packet, p.source.label.as_deref());
+    }
+    sessions.save_all(&tx)?;
+
+    tx.commit()?;
+
+    info!(
+        "Moved {} packets to the recording, {} remaining in quarantine",
+        ids.len(),
+        remaining
+    );
+
+    Ok(())
+}
+
+/// `pkt_id` and timestamp of the stored packets, in `pkt_id` order
+fn stored_timestamps(conn: &Connection) -> Result<Vec<(i64, f64)>> {
+    let mut stmt = conn.prepare("SELECT pkt_id, timestamp FROM packets ORDER BY pkt_id;")?;
+    let rows = stmt.query_map((), |r| Ok((r.get(0)?, r.get(1)?)))?;
+
+    Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+}
+
+/// New `pkt_id`s to merge packets into a recording, in timestamp order, without changing the order
+/// of the stored packets, whose timestamps may not be monotonic with several sources.
+///
+/// A merged packet goes before the first stored packet with a later timestamp.
+#[derive(Debug, Default, PartialEq)]
+struct Renumbering {
+    /// Ranges of stored packets to shift, as first and last `pkt_id` and offset
+    shifts: Vec<(i64, i64, i64)>,
+    /// IDs of the merged packets
+    merged_ids: Vec<i64>,
+}
+
+impl Renumbering {
+    /// `merged` holds the timestamps of the merged packets, sorted.
+    fn new(stored: &[(i64, f64)], merged: &[f64]) -> Self {
+        let mut renumbering = Self::default();
+        let mut next = 0;
+        let mut offset = 0;
+
+        for (pkt_id, timestamp) in stored {
+            // Take the IDs freed before this packet by shifting it
+            let first = next;
+            while next < merged.len() && merged[next] < *timestamp {
+                renumbering
+                    .merged_ids
+                    .push(pkt_id + offset + (next - first) as i64);
+                next += 1;
+            }
+            offset += (next - first) as i64;
+
+            if offset > 0 {
+                match renumbering.shifts.last_mut() {
+                    Some((_, last, shift)) if *shift == offset => *last = *pkt_id,
+                    _ => renumbering.shifts.push((*pkt_id, *pkt_id, offset)),
+                }
+            }
+        }
+
+        let last_id = stored.last().map_or(0, |(pkt_id, _)| pkt_id + offset);
+        for idx in next..merged.len() {
+            renumbering
+                .merged_ids
+                .push(last_id + 1 + (idx - next) as i64);
+        }
+
+        renumbering
+    }
+
+    /// Shift the stored packets, through negative IDs so that they never collide.
+    fn apply(&self, conn: &Connection) -> Result<()> {
+        for (first, last, shift) in &self.shifts {
+            conn.execute(
+                "UPDATE packets SET pkt_id = -(pkt_id + ?) WHERE pkt_id BETWEEN ? AND ?;",
+                params![shift, first, last],
+            )?;
+        }
+        conn.execute("UPDATE packets SET pkt_id = -pkt_id WHERE pkt_id < 0;", ())?;
+
+        Ok(())
+    }
+}
+
+pub(crate) fn count_quarantined(conn: &Connection) -> Result<u64> {
+    let count = conn.query_row("SELECT COUNT(*) FROM quarantine;", (), |r| r.get(0))?;
+
+    Ok(count)
+}
+
+/// Number of quarantined packets by parse error, from every source or the given one
+pub(crate) fn count_quarantined_by_error(
+    conn: &Connection,
+    source: Option<&str>,
+) -> Result<BTreeMap<String, u64>> {
+    let mut stmt = conn.prepare(
+        "
+        SELECT error, COUNT(*) FROM quarantine
+        WHERE ?1 IS NULL OR sourceLabel = ?1
+        GROUP BY error;
+        ",
+    )?;
+    let rows = stmt.query_map([source], |r| Ok((r.get(0)?, r.get(1)?)))?;
+
+    Ok(rows.collect::<rusqlite::Result<_>>()?)
+}
+
+#[cfg(test)]
+mod test_quarantine {
+    use super::Renumbering;
+
+    #[test]
+    fn test_renumbering() {
+        // Stored packets from two sources, with timestamps out of order
+        let stored = [(1, 1.0), (2, 3.0), (3, 2.0), (4, 4.0), (6, 5.0)];
+
+        let renumbering = Renumbering::new(&stored, &[0.5, 2.5, 2.6, 6.0]);
+        assert_eq!(
+            renumbering,
+            Renumbering {
+                shifts: vec![(1, 1, 1), (2, 6, 3)],
+                merged_ids: vec![1, 3, 4, 10],
+            }
+        );
+    }
+
+    #[test]
+    fn test_renumbering_after_the_last_packet() {
+        let renumbering = Renumbering::new(&[(1, 1.0), (2, 2.0)], &[3.0]);
+        assert_eq!(
+            renumbering,
+            Renumbering {
+                shifts: vec![],
+                merged_ids: vec![3],
+            }
+        );
+
+        let renumbering = Renumbering::new(&[], &[1.0, 2.0]);
+        assert_eq!(renumbering.merged_ids, vec![1, 2]);
+    }
+}
@@ -1,188 +1,624 @@
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::create_dir_all;
 use std::net::UdpSocket;
-use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
 use std::thread::{sleep, spawn};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use anyhow::Result;
-use log::{info, warn};
+use anyhow::{Error, Result};
+use log::{debug, info, warn};
 use rusqlite::Connection;
-use time::Instant;
 
-use crate::utils::{ctrl_c_channel, get_database_connection};
+use crate::binlog::{open_for_recording, BinlogFile, PacketSummary};
+use crate::compression::PacketCompressor;
+use crate::db::PacketSource;
+use crate::quarantine::{insert_quarantined, QuarantinedPacket};
+use crate::relay::Relay;
+use crate::sessions::Sessions;
+use crate::utils::{
+    ctrl_c_channel, format_session_uid, get_database_connection, session_file_name,
+};
 use f1_telemetry::packet::{parse_packet, Packet};
 
-use super::RecordArgs;
+use super::{RecordArgs, RecordFormat};
+
+use auto::AutoSessions;
+
+mod auto;
+
+/// Maximum number of packets written per transaction
+const BATCH_SIZE: usize = 256;
+
+/// Interval between two reports of the recording counters
+const REPORT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A packet sent by a socket thread to the writer thread
+type Received = Result<TimestampedPacket, QuarantinedPacket>;
 
 pub(crate) fn record(args: &RecordArgs) -> Result<()> {
-    info!("Recording {}:{} to {}", args.host, args.port, args.file);
+    let listeners = match args.listen.is_empty() {
+        true => format!("{}:{}", args.host, args.port),
+        false => args
+            .listen
+            .iter()
+            .map(|l| format!("{}:{} ({})", args.host, l.port, l.label))
+            .collect::<Vec<_>>()
+            .join(", "),
+    };
+    match &args.file {
+        Some(file) if !args.auto => info!(
+            "Recording {} to {} (split sessions: {})",
+            listeners, file, args.split_sessions
+        ),
+        _ => info!(
+            "Recording {} to {}, one file per session",
+            listeners, args.output_dir
+        ),
+    }
 
-    let recorder = Recorder::new(args)?;
+    let mut recorder = Recorder::new(args)?;
     let ctrl_receiver = ctrl_c_channel()?;
+    let stats = Arc::new(RecorderStats::default());
+    let stop = Arc::new(AtomicBool::new(false));
+    let relay = recorder.relay.clone();
+
+    let rx = recorder.start_receiver_sockets(args.queue_size, &stats)?;
+    let writer = {
+        let stats = stats.clone();
+        let stop = stop.clone();
+        spawn(move || -> Result<Recorder> {
+            recorder.write(&rx, &stats, &stop)?;
+            Ok(recorder)
+        })
+    };
+
+    // The writer only stops by itself on errors
+    let mut last_report = Instant::now();
+    while !writer.is_finished() {
+        if ctrl_receiver.try_recv().is_ok() {
+            info!("Stopping recording");
+            stop.store(true, Ordering::Relaxed);
+            break;
+        }
+
+        if last_report.elapsed() >= REPORT_INTERVAL {
+            stats.log();
+            if let Some(relay) = &relay {
+                relay.log_stats();
+            }
+            last_report = Instant::now();
+        }
+
+        sleep(Duration::from_millis(100));
+    }
+
+    let recorder = match writer.join() {
+        Ok(recorder) => recorder?,
+        Err(_) => return Err(Error::msg("The writer thread panicked")),
+    };
 
-    recorder.record(&ctrl_receiver)?;
+    stats.log();
+    if let Some(relay) = &relay {
+        relay.log_stats();
+    }
+
+    if let Some(compressor) = &recorder.compressor {
+        let stats = compressor.stats;
+        info!(
+            "Compressed {} bytes of packets to {} bytes (ratio {:.2})",
+            stats.raw,
+            stats.stored,
+            stats.ratio()
+        );
+    }
 
     Ok(())
 }
 
-struct TimestampedPacket {
-    timestamp: f64,
-    packet: Packet,
-    raw_packet: Vec<u8>,
+/// A labelled UDP listener, to record several games at once. Given as `LABEL=PORT`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Listener {
+    label: String,
+    port: u16,
+}
+
+impl FromStr for Listener {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (label, port) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Expected LABEL=PORT, got {}", s))?;
+
+        if label.is_empty() {
+            return Err(format!("Missing label in {}", s));
+        }
+
+        Ok(Self {
+            label: label.to_string(),
+            port: port
+                .parse()
+                .map_err(|e| format!("Invalid port {}: {}", port, e))?,
+        })
+    }
+}
+
+pub(crate) struct TimestampedPacket {
+    pub(crate) timestamp: f64,
+    pub(crate) source: PacketSource,
+    pub(crate) packet: Packet,
+    /// Packet as stored in the database
+    pub(crate) data: Vec<u8>,
+}
+
+/// Counters shared by the socket threads and the writer thread
+#[derive(Debug, Default)]
+struct RecorderStats {
+    received: AtomicU64,
+    written: AtomicU64,
+    /// Packets dropped because the writer was falling behind
+    dropped: AtomicU64,
+    /// Packets waiting for the writer
+    queue_depth: AtomicUsize,
+    max_queue_depth: AtomicUsize,
+}
+
+impl RecorderStats {
+    fn log(&self) {
+        info!(
+            "Received {} packets, wrote {}, dropped {} (queue depth: {}, max: {})",
+            self.received.load(Ordering::Relaxed),
+            self.written.load(Ordering::Relaxed),
+            self.dropped.load(Ordering::Relaxed),
+            self.queue_depth.load(Ordering::Relaxed),
+            self.max_queue_depth.load(Ordering::Relaxed),
+        );
+    }
 }
 
 struct Recorder {
     host: String,
-    port: u16,
-    conn: Connection,
+    /// Ports to listen on, with the label of their packets
+    listeners: Vec<(Option<String>, u16)>,
+    file: String,
+    split_sessions: bool,
+    /// Open databases, keyed by session UID when splitting sessions
+    databases: HashMap<Option<u64>, Connection>,
+    /// Starts and stops recording sessions in automatic mode
+    auto: Option<AutoSessions>,
+    sessions: Sessions,
+    compressor: Option<PacketCompressor>,
+    /// Packets that could not be parsed, saved to the main database even when splitting sessions
+    quarantined: Vec<QuarantinedPacket>,
+    /// Forwards the received packets, shared with the socket thread
+    relay: Option<Arc<Relay>>,
+    /// Maximum time a packet waits before being written
+    flush_interval: Duration,
+    /// Binary log written instead of databases
+    binlog: Option<BinlogFile>,
 }
 
 impl Recorder {
     fn new(args: &RecordArgs) -> Result<Self> {
-        let conn = get_database_connection(&args.file)?;
+        let binlog = args.format == RecordFormat::Binlog;
+        if binlog && (args.auto || args.split_sessions || args.compress || !args.listen.is_empty())
+        {
+            return Err(Error::msg(
+                "Binary logs can't be used with --auto, --split-sessions, --compress or --listen",
+            ));
+        }
 
-        Ok(Self {
+        let auto = match args.auto {
+            true => {
+                create_dir_all(&args.output_dir)?;
+                let max_disk_usage = args.max_disk_usage.map(|mb| mb * 1024 * 1024);
+                Some(AutoSessions::new(
+                    &args.output_dir,
+                    args.idle_timeout,
+                    max_disk_usage,
+                ))
+            }
+            false => None,
+        };
+
+        let mut recorder = Self {
             host: args.host.clone(),
-            port: args.port,
-            conn,
-        })
-    }
+            listeners: match args.listen.is_empty() {
+                true => vec![(None, args.port)],
+                false => args
+                    .listen
+                    .iter()
+                    .map(|l| (Some(l.label.clone()), l.port))
+                    .collect(),
+            },
+            file: match &auto {
+                Some(auto) => auto.quarantine_file(),
+                None => args.file.clone().unwrap_or_default(),
+            },
+            split_sessions: args.split_sessions,
+            databases: HashMap::new(),
+            auto,
+            sessions: Sessions::default(),
+            compressor: match args.compress {
+                true => Some(PacketCompressor::new(args.compression_level)?),
+                false => None,
+            },
+            quarantined: Vec::new(),
+            relay: match args.relay.is_empty() {
+                true => None,
+                false => Some(Arc::new(Relay::new(&args.relay)?)),
+            },
+            flush_interval: Duration::from_secs_f64(args.flush_interval),
+            binlog: None,
+        };
+
+        if let Some(relay) = &recorder.relay {
+            info!("Relaying packets to {}", relay.destination_list());
+        }
 
-    fn record(&self, ctrl_receiver: &Receiver<()>) -> Result<()> {
-        self.init_db()?;
+        if binlog {
+            info!("Opening binary log {}", recorder.file);
+            recorder.binlog = Some(open_for_recording(&recorder.file)?);
+        } else if let Some(auto) = &recorder.auto {
+            auto.apply_retention()?;
+        } else if !recorder.split_sessions {
+            // Open the database right away to fail early on an invalid file
+            recorder.get_database(None)?;
+        }
 
-        let rx = self.start_receiver_socket()?;
-        let mut last_recv = Instant::now();
-        let mut packets: Vec<TimestampedPacket> = Vec::with_capacity(256);
+        Ok(recorder)
+    }
 
-        loop {
-            if ctrl_receiver.try_recv().is_ok() {
-                info!("Stopping recording");
-                break;
-            }
+    /// Write the received packets in batches, until asked to stop.
+    ///
+    /// A batch is written when it is full or when its oldest packet has waited for the flush
+    /// interval, so a crash loses at most that much data.
+    fn write(
+        &mut self,
+        rx: &Receiver<Received>,
+        stats: &RecorderStats,
+        stop: &AtomicBool,
+    ) -> Result<()> {
+        let mut packets: Vec<TimestampedPacket> = Vec::with_capacity(BATCH_SIZE);
+        // Reception of the oldest packet not written yet
+        let mut oldest: Option<Instant> = None;
+        let mut last_check = Instant::now();
 
-            let packet = match rx.try_recv() {
-                Ok(p) => Some(p),
-                Err(TryRecvError::Empty) => None,
-                Err(e) => {
-                    warn!("Error receiving packet: {:?}", e);
-                    continue;
-                }
+        loop {
+            let stopping = stop.load(Ordering::Relaxed);
+            let received = match stopping {
+                // Empty the queue before stopping
+                true => rx.try_recv().ok(),
+                false => rx.recv_timeout(Duration::from_millis(100)).ok(),
             };
+            let done = stopping && received.is_none();
 
-            if packet.is_none() {
-                if !packets.is_empty() && last_recv.elapsed() >= Duration::from_secs(5) {
-                    info!("No packets received in the last 5 sec, saving packets to database.");
+            if let Some(received) = received {
+                stats.queue_depth.fetch_sub(1, Ordering::Relaxed);
+                oldest.get_or_insert_with(Instant::now);
 
-                    self.save_packets(&mut packets)?;
+                match received {
+                    Ok(p) => packets.push(p),
+                    Err(p) => self.quarantined.push(p),
                 }
-                sleep(Duration::from_millis(1));
-                continue;
             }
 
-            packets.push(packet.unwrap());
-            if packets.len() >= 256 {
-                info!("Saving packets to database.");
+            let pending = packets.len() + self.quarantined.len();
+            let flush = pending >= BATCH_SIZE
+                || oldest.is_some_and(|t| t.elapsed() >= self.flush_interval)
+                || done;
+            if flush && pending > 0 {
+                debug!("Saving {} packets to database.", pending);
                 self.save_packets(&mut packets)?;
+                stats.written.fetch_add(pending as u64, Ordering::Relaxed);
+                oldest = None;
             }
 
-            last_recv = Instant::now();
-        }
-
-        info!("Saving packets to database.");
-        self.save_packets(&mut packets)?;
+            if last_check.elapsed() >= Duration::from_secs(1) {
+                self.close_finished_sessions()?;
+                last_check = Instant::now();
+            }
 
-        Ok(())
+            if done {
+                if let Some(binlog) = &mut self.binlog {
+                    binlog.finish()?;
+                }
+                return Ok(());
+            }
+        }
     }
 
-    fn init_db(&self) -> Result<()> {
-        let create_table_stmt = "
-            CREATE TABLE IF NOT EXISTS packets (
-                pkt_id           INTEGER  PRIMARY KEY,
-                timestamp        REAL     NOT NULL,
-                packetFormat     INTEGER  NOT NULL,
-                gameMajorVersion INTEGER  NOT NULL,
-                gameMinorVersion INTEGER  NOT NULL,
-                packetVersion    INTEGER  NOT NULL,
-                packetID         INTEGER  NOT NULL,
-                sessionID        CHAR(16) NOT NULL,
-                sessionTime      REAL     NOT NULL,
-                frameIdentifier  INTEGER  NOT NULL,
-                playerCarIndex   INTEGER  NOT NULL,
-                packet           BLOB     NOT NULL
-            );
-        ";
-
-        self.conn.execute(create_table_stmt, ())?;
-
-        Ok(())
+    fn get_database(&mut self, session_uid: Option<u64>) -> Result<&Connection> {
+        match self.databases.entry(session_uid) {
+            Entry::Occupied(e) => Ok(e.into_mut()),
+            Entry::Vacant(e) => {
+                let file = match (session_uid, &self.auto) {
+                    (Some(uid), Some(auto)) => match auto.file(uid) {
+                        Some(file) => file.to_string(),
+                        None => unreachable!("only recorded sessions are saved"),
+                    },
+                    (Some(uid), None) => session_file_name(&self.file, uid),
+                    (None, _) => self.file.clone(),
+                };
+                info!("Opening database {}", file);
+
+                let conn = get_database_connection(&file)?;
+                // Readers can open the file while recording, and commits don't wait for the disk
+                // anymore. A crash still keeps every committed batch.
+                conn.pragma_update_and_check(None, "journal_mode", "WAL", |r| {
+                    r.get::<_, String>(0)
+                })?;
+                conn.pragma_update(None, "synchronous", "NORMAL")?;
+
+                Ok(e.insert(conn))
+            }
+        }
     }
 
-    fn start_receiver_socket(&self) -> Result<Receiver<TimestampedPacket>> {
-        let socket = UdpSocket::bind(format!("{}:{}", self.host, self.port))?;
-        let (tx, rx) = channel();
-
-        spawn(move || {
-            loop {
-                let mut buf = [0; 2048]; // All packets fit in 2048 bytes
-                match socket.recv(&mut buf) {
-                    Ok(len) => match process_incoming_packet(len, &buf) {
-                        Ok(p) => {
-                            let _ = tx.send(p);
+    /// Start a thread per listener, all sending their packets to the writer through a queue of
+    /// `queue_size` packets.
+    ///
+    /// Packets are dropped when the queue is full rather than blocking the sockets, which would
+    /// drop them anyway once their buffers are full.
+    fn start_receiver_sockets(
+        &self,
+        queue_size: usize,
+        stats: &Arc<RecorderStats>,
+    ) -> Result<Receiver<Received>> {
+        let (tx, rx) = sync_channel(queue_size);
+
+        for (label, port) in &self.listeners {
+            let socket = UdpSocket::bind(format!("{}:{}", self.host, port))?;
+            let tx = tx.clone();
+            let relay = self.relay.clone();
+            let label = label.clone();
+            let stats = stats.clone();
+
+            spawn(move || {
+                loop {
+                    let mut buf = [0; 2048]; // All packets fit in 2048 bytes
+                    match socket.recv_from(&mut buf) {
+                        Ok((len, addr)) => {
+                            if let Some(relay) = &relay {
+                                relay.forward(&buf[..len]);
+                            }
+
+                            let source = PacketSource {
+                                address: Some(addr.to_string()),
+                                label: label.clone(),
+                            };
+                            let received = process_incoming_packet(len, &buf, source);
+                            if let Err(p) = &received {
+                                warn!("Error processing packet, quarantining it: {}", p.error);
+                            }
+
+                            stats.received.fetch_add(1, Ordering::Relaxed);
+                            if !enqueue(&tx, received, &stats) {
+                                // The writer stopped
+                                break;
+                            }
+                        }
+                        Err(e) => {
+                            warn!("Invalid packet received: {:?}", e);
                         }
-                        Err(e) => warn!("Error processing packet: {:?}", e),
-                    },
-                    Err(e) => {
-                        warn!("Invalid packet received: {:?}", e);
                     }
                 }
-            }
-        });
+            });
+        }
 
         Ok(rx)
     }
 
-    fn save_packets(&self, packets: &mut Vec<TimestampedPacket>) -> Result<()> {
-        let mut stmt = self.conn.prepare_cached("
-            INSERT INTO packets(
-                timestamp, packetFormat, gameMajorVersion, gameMinorVersion, packetVersion, packetID,
-                sessionID, sessionTime, frameIdentifier, playerCarIndex, packet
-            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);
-        ")?;
+    fn save_packets(&mut self, packets: &mut Vec<TimestampedPacket>) -> Result<()> {
+        if let Some(binlog) = &mut self.binlog {
+            return save_to_binlog(binlog, packets, &mut self.quarantined);
+        }
+
+        if let Some(auto) = &mut self.auto {
+            *packets = auto.route(std::mem::take(packets));
+        }
+
+        let mut batches: BTreeMap<Option<u64>, Vec<&TimestampedPacket>> = BTreeMap::new();
+
+        for p in packets.iter_mut() {
+            self.sessions
+                .update(p.timestamp, &p.packet, p.source.label.as_deref());
+
+            if let Some(compressor) = &mut self.compressor {
+                p.data = compressor.compress(&p.data)?;
+            }
+        }
 
-        self.conn.execute("BEGIN;", ())?;
         for p in packets.iter() {
-            let header = p.packet.header();
-            stmt.execute((
-                p.timestamp,
-                header.packet_format,
-                header.game_major_version,
-                header.game_minor_version,
-                header.packet_version,
-                header.packet_type as u8,
-                format!("{:16x}", header.session_uid),
-                header.session_time,
-                header.frame_identifier,
-                header.player_car_index,
-                &p.raw_packet,
-            ))?;
-        }
-        self.conn.execute("COMMIT;", ())?;
+            let session_uid = p.packet.header().session_uid;
+            let key = (self.split_sessions || self.auto.is_some()).then_some(session_uid);
+            batches.entry(key).or_default().push(p);
+        }
+
+        for (key, batch) in batches {
+            self.get_database(key)?;
+            let conn = &self.databases[&key];
+
+            if let Some(compressor) = &self.compressor {
+                compressor.save_dictionary(conn)?;
+            }
+            insert_packets(conn, &batch)?;
+
+            let session_uids: BTreeSet<u64> = batch
+                .iter()
+                .map(|p| p.packet.header().session_uid)
+                .collect();
+            for session_uid in session_uids {
+                self.sessions.save(&self.databases[&key], session_uid)?;
+            }
+        }
+
         packets.clear();
 
+        if !self.quarantined.is_empty() {
+            self.get_database(None)?;
+            insert_quarantined(&self.databases[&None], &self.quarantined)?;
+            self.quarantined.clear();
+        }
+
+        self.close_finished_sessions()
+    }
+
+    /// Close the files of the sessions that ended, in automatic mode.
+    fn close_finished_sessions(&mut self) -> Result<()> {
+        let Some(auto) = &mut self.auto else {
+            return Ok(());
+        };
+
+        for session_uid in auto.finished() {
+            self.databases.remove(&Some(session_uid));
+            auto.close(session_uid)?;
+        }
+
         Ok(())
     }
 }
 
-fn process_incoming_packet(len: usize, buf: &[u8]) -> Result<TimestampedPacket> {
+/// Append a batch to a binary log, in reception order.
+fn save_to_binlog(
+    binlog: &mut BinlogFile,
+    packets: &mut Vec<TimestampedPacket>,
+    quarantined: &mut Vec<QuarantinedPacket>,
+) -> Result<()> {
+    let mut packets = packets.drain(..).peekable();
+    let mut quarantined = quarantined.drain(..).peekable();
+
+    loop {
+        let next_quarantined = match (packets.peek(), quarantined.peek()) {
+            (Some(p), Some(q)) => q.timestamp < p.timestamp,
+            (None, Some(_)) => true,
+            (_, None) => false,
+        };
+
+        if next_quarantined {
+            let q = quarantined.next().expect("peeked");
+            binlog.write_unparsed(q.timestamp, &q.data)?;
+        } else if let Some(p) = packets.next() {
+            binlog.write_packet(p.timestamp, &PacketSummary::new(p.packet.header()), &p.data)?;
+        } else {
+            break;
+        }
+    }
+
+    binlog.flush()
+}
+
+/// Queue a packet for the writer, unless the queue is full. Returns false once the writer is gone.
+fn enqueue(tx: &SyncSender<Received>, received: Received, stats: &RecorderStats) -> bool {
+    let depth = stats.queue_depth.fetch_add(1, Ordering::Relaxed) + 1;
+    stats.max_queue_depth.fetch_max(depth, Ordering::Relaxed);
+
+    match tx.try_send(received) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+            stats.queue_depth.fetch_sub(1, Ordering::Relaxed);
+            if stats.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                warn!("The writer is falling behind, dropping packets");
+            }
+            true
+        }
+        Err(TrySendError::Disconnected(_)) => false,
+    }
+}
+
+pub(crate) fn insert_packets(conn: &Connection, packets: &[&TimestampedPacket]) -> Result<()> {
+    conn.execute("BEGIN;", ())?;
+    for p in packets.iter() {
+        insert_packet(conn, p, None)?;
+    }
+    conn.execute("COMMIT;", ())?;
+
+    Ok(())
+}
+
+/// Insert a packet with the given `pkt_id`, or after the last one, outside of a transaction.
+pub(crate) fn insert_packet(
+    conn: &Connection,
+    p: &TimestampedPacket,
+    pkt_id: Option<i64>,
+) -> Result<()> {
+    let mut stmt = conn.prepare_cached(
+        "
+        INSERT INTO packets(
+            pkt_id, timestamp, packetFormat, gameMajorVersion, gameMinorVersion, packetVersion,
+            packetID, sessionID, sessionTime, frameIdentifier, playerCarIndex, source, sourceLabel,
+            packet
+        ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);
+    ",
+    )?;
+
+    let header = p.packet.header();
+    stmt.execute((
+        pkt_id,
+        p.timestamp,
+        header.packet_format,
+        header.game_major_version,
+        header.game_minor_version,
+        header.packet_version,
+        header.packet_type as u8,
+        format_session_uid(header.session_uid),
+        header.session_time,
+        header.frame_identifier,
+        header.player_car_index,
+        &p.source.address,
+        &p.source.label,
+        &p.data,
+    ))?;
+
+    Ok(())
+}
+
+fn process_incoming_packet(
+    len: usize,
+    buf: &[u8],
+    source: PacketSource,
+) -> Result<TimestampedPacket, QuarantinedPacket> {
     let timestamp = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .expect("Somehow we went back in time")
         .as_secs_f64();
-    let packet = parse_packet(len, buf)?;
+    let data = Vec::from(&buf[..len]);
+
+    match parse_packet(len, buf) {
+        Ok(packet) => Ok(TimestampedPacket {
+            timestamp,
+            source,
+            packet,
+            data,
+        }),
+        Err(e) => Err(QuarantinedPacket {
+            timestamp,
+            source,
+            error: e.to_string(),
+            data,
+        }),
+    }
+}
 
-    Ok(TimestampedPacket {
-        timestamp,
-        packet,
-        raw_packet: Vec::from(&buf[..len]),
-    })
+#[cfg(test)]
+mod test_listener {
+    use super::Listener;
+
+    #[test]
+    fn test_parse_listener() {
+        assert_eq!(
+            "alice=20778".parse::<Listener>(),
+            Ok(Listener {
+                label: String::from("alice"),
+                port: 20778
+            })
+        );
+        assert!("20778".parse::<Listener>().is_err());
+        assert!("=20778".parse::<Listener>().is_err());
+        assert!("alice=port".parse::<Listener>().is_err());
+    }
 }
@@ -0,0 +1,391 @@
+use std::collections::HashMap;
+use std::fs::{read_dir, remove_file};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use log::{info, warn};
+use time::macros::format_description;
+use time::OffsetDateTime;
+
+use f1_telemetry::packet::event::Event;
+use f1_telemetry::packet::Packet;
+
+use super::TimestampedPacket;
+
+/// Database of the packets that could not be parsed, which belong to no session
+pub(super) const QUARANTINE_FILE: &str = "quarantine.db";
+
+/// A session being recorded to its own file
+struct ActiveSession {
+    file: String,
+    /// Timestamp of the last packet of the session
+    last_packet: f64,
+    /// The game sent the end of the session
+    ended: bool,
+}
+
+/// Sessions started since the recorder was launched
+struct PendingSession {
+    started_at: f64,
+    /// Packets received before the Session packet, needed to name the file
+    packets: Vec<TimestampedPacket>,
+}
+
+/// Starts and stops recording from the events of the game: every session goes to its own file,
+/// named from its track, session type and start time.
+pub(super) struct AutoSessions {
+    output_dir: PathBuf,
+    /// Inactivity after which a session is closed, in seconds
+    idle_timeout: f64,
+    /// Disk usage of the recordings above which the oldest ones are deleted, in bytes
+    max_disk_usage: Option<u64>,
+    pending: HashMap<u64, PendingSession>,
+    active: HashMap<u64, ActiveSession>,
+    /// Packets received outside of a session
+    ignored: u64,
+}
+
+impl AutoSessions {
+    pub(super) fn new(output_dir: &str, idle_timeout: f64, max_disk_usage: Option<u64>) -> Self {
+        Self {
+            output_dir: PathBuf::from(output_dir),
+            idle_timeout,
+            max_disk_usage,
+            pending: HashMap::new(),
+            active: HashMap::new(),
+            ignored: 0,
+        }
+    }
+
+    pub(super) fn quarantine_file(&self) -> String {
+        self.output_dir
+            .join(QUARANTINE_FILE)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// File of a session being recorded
+    pub(super) fn file(&self, session_uid: u64) -> Option<&str> {
+        self.active.get(&session_uid).map(|s| s.file.as_str())
+    }
+
+    /// Keep the packets of the sessions being recorded, in order, and hold the ones of sessions
+    /// that can't be named yet.
+    pub(super) fn route(&mut self, packets: Vec<TimestampedPacket>) -> Vec<TimestampedPacket> {
+        let mut recorded = Vec::with_capacity(packets.len());
+
+        for p in packets {
+            let session_uid = p.packet.header().session_uid;
+
+            if let Packet::Event(e) = &p.packet {
+                if matches!(e.event, Event::SessionStarted)
+                    && !self.active.contains_key(&session_uid)
+                {
+                    info!("Session {:016x} started", session_uid);
+                    self.pending.entry(session_uid).or_insert(PendingSession {
+                        started_at: p.timestamp,
+                        packets: Vec::new(),
+                    });
+                }
+            }
+
+            if let Packet::Session(s) = &p.packet {
+                if let Some(pending) = self.pending.remove(&session_uid) {
+                    let file = self.unique_file_name(
+                        &snake_case(&format!("{:?}", s.track)),
+                        &snake_case(&format!("{:?}", s.session_type)),
+                        pending.started_at,
+                    );
+                    info!("Recording session {:016x} to {}", session_uid, file);
+
+                    self.active.insert(
+                        session_uid,
+                        ActiveSession {
+                            file,
+                            last_packet: p.timestamp,
+                            ended: false,
+                        },
+                    );
+                    recorded.extend(pending.packets);
+                }
+            }
+
+            if let Some(session) = self.active.get_mut(&session_uid) {
+                session.last_packet = p.timestamp;
+                session.ended |= match &p.packet {
+                    Packet::Event(e) => matches!(e.event, Event::SessionEnded),
+                    Packet::FinalClassification(_) => true,
+                    _ => false,
+                };
+                recorded.push(p);
+            } else if let Some(pending) = self.pending.get_mut(&session_uid) {
+                pending.packets.push(p);
+            } else {
+                self.ignored += 1;
+            }
+        }
+
+        recorded
+    }
+
+    /// Sessions that ended or have been idle for too long, to be closed.
+    pub(super) fn finished(&mut self) -> Vec<u64> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Somehow we went back in time")
+            .as_secs_f64();
+
+        self.pending.retain(|session_uid, pending| {
+            let last_packet = pending
+                .packets
+                .last()
+                .map_or(pending.started_at, |p| p.timestamp);
+            let idle = now - last_packet > self.idle_timeout;
+            if idle {
+                warn!(
+                    "Session {:016x} went idle before it could be named, dropping {} packets",
+                    session_uid,
+                    pending.packets.len()
+                );
+            }
+            !idle
+        });
+
+        self.active
+            .iter()
+            .filter(|(_, s)| s.ended || now - s.last_packet > self.idle_timeout)
+            .map(|(session_uid, _)| *session_uid)
+            .collect()
+    }
+
+    /// Stop recording a session, once its file is closed.
+    pub(super) fn close(&mut self, session_uid: u64) -> Result<()> {
+        if let Some(session) = self.active.remove(&session_uid) {
+            info!(
+                "Session {:016x} {}, closed {}",
+                session_uid,
+                if session.ended { "ended" } else { "went idle" },
+                session.file
+            );
+        }
+
+        if self.ignored > 0 {
+            info!("Ignored {} packets outside of sessions", self.ignored);
+            self.ignored = 0;
+        }
+
+        self.apply_retention()
+    }
+
+    /// Delete the oldest recordings until they fit in the maximum disk usage.
+    pub(super) fn apply_retention(&self) -> Result<()> {
+        let Some(max_disk_usage) = self.max_disk_usage else {
+            return Ok(());
+        };
+
+        let mut recordings = Vec::new();
+        let mut in_use = 0;
+
+        for entry in read_dir(&self.output_dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let metadata = entry.metadata()?;
+
+            // Other files in the directory are left alone
+            let name = entry.file_name();
+            if !metadata.is_file() || !name.to_str().is_some_and(is_auto_recording) {
+                continue;
+            }
+
+            // The write-ahead log can be as large as the database itself
+            let size = metadata.len()
+                + sidecar_files(&path)
+                    .iter()
+                    .filter_map(|p| p.metadata().ok())
+                    .map(|m| m.len())
+                    .sum::<u64>();
+
+            let active = self
+                .active
+                .values()
+                .any(|s| Path::new(&s.file) == path.as_path());
+            if active {
+                in_use += size;
+            } else {
+                recordings.push((path, size, metadata.modified()?));
+            }
+        }
+
+        for path in expired_recordings(recordings, in_use, max_disk_usage) {
+            info!(
+                "Deleting {} to stay under the disk usage limit",
+                path.display()
+            );
+            if let Err(e) = remove_file(&path) {
+                warn!("Unable to delete {}: {}", path.display(), e);
+                continue;
+            }
+            for sidecar in sidecar_files(&path) {
+                match remove_file(&sidecar) {
+                    Err(e) if e.kind() != ErrorKind::NotFound => {
+                        warn!("Unable to delete {}: {}", sidecar.display(), e)
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Name of the file of a new session, like `spa_race_2026-10-17T20-15.db`.
+    ///
+    /// The start time is in UTC: the local offset can't be read safely once the recorder threads
+    /// are running.
+    fn unique_file_name(&self, track: &str, session_type: &str, started_at: f64) -> String {
+        let date = OffsetDateTime::from_unix_timestamp(started_at as i64)
+            .ok()
+            .and_then(|d| {
+                d.format(format_description!("[year]-[month]-[day]T[hour]-[minute]"))
+                    .ok()
+            })
+            .unwrap_or_default();
+        let stem = format!("{}_{}_{}", track, session_type, date);
+
+        let mut path = self.output_dir.join(format!("{}.db", stem));
+        let mut n = 2;
+        while path.exists() || self.active.values().any(|s| Path::new(&s.file) == path) {
+            path = self.output_dir.join(format!("{}_{}.db", stem, n));
+            n += 1;
+        }
+
+        path.to_string_lossy().into_owned()
+    }
+}
+
+/// Whether a file was named by [`AutoSessions::unique_file_name`], and can be deleted to enforce
+/// the maximum disk usage.
+fn is_auto_recording(name: &str) -> bool {
+    let Some(stem) = name.strip_suffix(".db") else {
+        return false;
+    };
+
+    // Start time, like 2026-10-17T20-15
+    stem.split('_').any(|part| {
+        part.len() == 16
+            && part.bytes().enumerate().all(|(idx, c)| match idx {
+                4 | 7 | 13 => c == b'-',
+                10 => c == b'T',
+                _ => c.is_ascii_digit(),
+            })
+    })
+}
+
+/// Write-ahead log and shared memory files SQLite keeps next to a database
+fn sidecar_files(path: &Path) -> [PathBuf; 2] {
+    ["-wal", "-shm"].map(|suffix| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(suffix);
+        PathBuf::from(name)
+    })
+}
+
+/// Convert a `CamelCase` name to `snake_case`
+fn snake_case(name: &str) -> String {
+    let mut snake = String::with_capacity(name.len() + 4);
+
+    for (idx, c) in name.chars().enumerate() {
+        if c.is_uppercase() && idx > 0 {
+            snake.push('_');
+        }
+        snake.extend(c.to_lowercase());
+    }
+
+    snake
+}
+
+/// Oldest recordings to delete so that the total size fits in `max_disk_usage`, on top of the
+/// files still in use.
+fn expired_recordings(
+    mut recordings: Vec<(PathBuf, u64, SystemTime)>,
+    in_use: u64,
+    max_disk_usage: u64,
+) -> Vec<PathBuf> {
+    recordings.sort_by_key(|(_, _, modified)| *modified);
+
+    let mut total: u64 = in_use + recordings.iter().map(|(_, size, _)| size).sum::<u64>();
+    let mut expired = Vec::new();
+
+    for (path, size, _) in recordings {
+        if total <= max_disk_usage {
+            break;
+        }
+        total -= size;
+        expired.push(path);
+    }
+
+    expired
+}
+
+#[cfg(test)]
+mod test_auto {
+    use std::path::PathBuf;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{expired_recordings, is_auto_recording, sidecar_files, snake_case};
+
+    #[test]
+    fn test_snake_case() {
+        assert_eq!(snake_case("Spa"), "spa");
+        assert_eq!(snake_case("PaulRicard"), "paul_ricard");
+        assert_eq!(snake_case("Practice1"), "practice1");
+    }
+
+    #[test]
+    fn test_is_auto_recording() {
+        assert!(is_auto_recording("spa_race_2026-10-17T20-15.db"));
+        assert!(is_auto_recording("spa_race_2026-10-17T20-15_2.db"));
+        assert!(!is_auto_recording("spa_race_2026-10-17T20-15.csv"));
+        assert!(!is_auto_recording(super::QUARANTINE_FILE));
+        assert!(!is_auto_recording("race.db"));
+    }
+
+    #[test]
+    fn test_sidecar_files() {
+        assert_eq!(
+            sidecar_files(&PathBuf::from("out/spa_race_2026-10-17T20-15.db")),
+            [
+                PathBuf::from("out/spa_race_2026-10-17T20-15.db-wal"),
+                PathBuf::from("out/spa_race_2026-10-17T20-15.db-shm"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expired_recordings() {
+        let recording = |name: &str, size: u64, age: u64| {
+            (
+                PathBuf::from(name),
+                size,
+                UNIX_EPOCH + Duration::from_secs(1000 - age),
+            )
+        };
+        let recordings = vec![
+            recording("recent.db", 100, 10),
+            recording("oldest.db", 100, 300),
+            recording("old.db", 100, 200),
+        ];
+
+        assert!(expired_recordings(recordings.clone(), 50, 400).is_empty());
+        assert_eq!(
+            expired_recordings(recordings.clone(), 50, 300),
+            vec![PathBuf::from("oldest.db")]
+        );
+        assert_eq!(
+            expired_recordings(recordings, 50, 150),
+            vec![PathBuf::from("oldest.db"), PathBuf::from("old.db")]
+        );
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use rusqlite::types::Value;
+use rusqlite::{params_from_iter, Connection};
+
+use crate::binlog::{count_unparsed_by_error, for_each_packet_from, is_binlog};
+use crate::compression::PacketDecompressor;
+use crate::db::PacketSource;
+use crate::quarantine::count_quarantined_by_error;
+use crate::selection::Selection;
+use crate::utils::{format_session_uid, open_database_read_only};
+
+/// A stored packet, with the header fields used to select and replay it
+pub(crate) struct RecordedPacket {
+    pub(crate) pkt_id: i64,
+    pub(crate) timestamp: f64,
+    pub(crate) session_id: String,
+    /// Session time, in milliseconds
+    pub(crate) session_time: f64,
+    pub(crate) frame: u32,
+    pub(crate) packet_id: u8,
+    pub(crate) source: PacketSource,
+    pub(crate) data: Vec<u8>,
+}
+
+/// A recording opened for reading, whether it is a database or a binary log.
+pub(crate) enum Recording {
+    Database(Connection),
+    /// Binary logs are streamed from the file on every read, rather than loaded in memory
+    Binlog(String),
+}
+
+impl Recording {
+    pub(crate) fn open(file: &str) -> Result<Self> {
+        match is_binlog(file)? {
+            true => Ok(Self::Binlog(file.to_string())),
+            false => Ok(Self::Database(open_database_read_only(file)?)),
+        }
+    }
+
+    /// Call `f` with the selected packets from `pkt_id` `from` onwards, in recording order,
+    /// until it returns false.
+    pub(crate) fn for_each_selected<F>(
+        &self,
+        selection: &Selection,
+        from: i64,
+        mut f: F,
+    ) -> Result<()>
+    where
+        F: FnMut(RecordedPacket) -> Result<bool>,
+    {
+        match self {
+            Self::Database(conn) => {
+                let (conditions, mut params) = selection.conditions();
+                params.insert(0, Value::from(from));
+
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT pkt_id, timestamp, sessionID, sessionTime, frameIdentifier, packetID, \
+                    source, sourceLabel, packet FROM packets WHERE pkt_id >= ?{} ORDER BY pkt_id;",
+                    conditions
+                ))?;
+                let mut rows = stmt.query(params_from_iter(params))?;
+                let mut decompressor = PacketDecompressor::new(conn)?;
+
+                while let Some(row) = rows.next()? {
+                    let packet = RecordedPacket {
+                        pkt_id: row.get(0)?,
+                        timestamp: row.get(1)?,
+                        session_id: row.get(2)?,
+                        session_time: row.get(3)?,
+                        frame: row.get(4)?,
+                        packet_id: row.get(5)?,
+                        source: PacketSource {
+                            address: row.get(6)?,
+                            label: row.get(7)?,
+                        },
+                        data: decompressor.decompress(row.get(8)?)?,
+                    };
+                    if !f(packet)? {
+                        break;
+                    }
+                }
+
+                Ok(())
+            }
+            Self::Binlog(file) => {
+                for_each_packet_from(file, from, |pkt_id, timestamp, summary, data| {
+                    let packet = RecordedPacket {
+                        pkt_id,
+                        timestamp,
+                        session_id: format_session_uid(summary.session_uid),
+                        session_time: summary.session_time as f64,
+                        frame: summary.frame_identifier,
+                        packet_id: summary.packet_id,
+                        source: PacketSource::default(),
+                        data,
+                    };
+
+                    match selection.matches(&packet) {
+                        true => f(packet),
+                        false => Ok(true),
+                    }
+                })
+            }
+        }
+    }
+
+    /// Number of packets that could not be parsed when recorded, by parse error, from every
+    /// source or the given one
+    pub(crate) fn count_unparseable_by_error(
+        &self,
+        source: Option<&str>,
+    ) -> Result<BTreeMap<String, u64>> {
+        match self {
+            Self::Database(conn) => count_quarantined_by_error(conn, source),
+            // Sources are not part of the binary log format
+            Self::Binlog(_) if source.is_some() => Ok(BTreeMap::new()),
+            Self::Binlog(file) => count_unparsed_by_error(file),
+        }
+    }
+}
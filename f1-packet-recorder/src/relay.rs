@@ -0,0 +1,182 @@
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use log::{info, warn};
+
+use crate::utils::ctrl_c_channel;
+
+use super::RelayArgs;
+
+/// Interval between two reports of the relay counters
+const REPORT_INTERVAL: Duration = Duration::from_secs(30);
+
+pub(crate) fn relay(args: &RelayArgs) -> Result<()> {
+    let relay = Relay::new(&args.destinations)?;
+    info!(
+        "Relaying {}:{} to {}",
+        args.host,
+        args.port,
+        relay.destination_list()
+    );
+
+    let socket = UdpSocket::bind(format!("{}:{}", args.host, args.port))?;
+    // Wake up regularly to check for Ctrl-C
+    socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+
+    let ctrl_receiver = ctrl_c_channel()?;
+    let mut last_report = Instant::now();
+    let mut buf = [0; 2048]; // All packets fit in 2048 bytes
+
+    loop {
+        if ctrl_receiver.try_recv().is_ok() {
+            info!("Stopping relay");
+            break;
+        }
+
+        match socket.recv(&mut buf) {
+            Ok(len) => relay.forward(&buf[..len]),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+                ) => {}
+            Err(e) => warn!("Invalid packet received: {:?}", e),
+        }
+
+        if last_report.elapsed() >= REPORT_INTERVAL {
+            relay.log_stats();
+            last_report = Instant::now();
+        }
+    }
+
+    relay.log_stats();
+
+    Ok(())
+}
+
+struct Destination {
+    addr: SocketAddr,
+    sent: AtomicU64,
+    errors: AtomicU64,
+}
+
+/// Forwards datagrams unchanged to a list of destinations, which can be unicast, broadcast or
+/// multicast addresses.
+///
+/// Counters are atomic so that a relay can forward from the receiving thread and be reported on
+/// from another one.
+pub(crate) struct Relay {
+    /// Sending sockets, for the IPv4 and IPv6 destinations
+    socket_v4: Option<UdpSocket>,
+    socket_v6: Option<UdpSocket>,
+    destinations: Vec<Destination>,
+    received: AtomicU64,
+}
+
+impl Relay {
+    pub(crate) fn new(destinations: &[SocketAddr]) -> Result<Self> {
+        let socket_v4 = match destinations.iter().any(SocketAddr::is_ipv4) {
+            true => {
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.set_broadcast(true)?;
+                Some(socket)
+            }
+            false => None,
+        };
+        let socket_v6 = match destinations.iter().any(SocketAddr::is_ipv6) {
+            true => Some(UdpSocket::bind("[::]:0")?),
+            false => None,
+        };
+
+        Ok(Self {
+            socket_v4,
+            socket_v6,
+            destinations: destinations
+                .iter()
+                .map(|addr| Destination {
+                    addr: *addr,
+                    sent: AtomicU64::new(0),
+                    errors: AtomicU64::new(0),
+                })
+                .collect(),
+            received: AtomicU64::new(0),
+        })
+    }
+
+    pub(crate) fn forward(&self, data: &[u8]) {
+        self.received.fetch_add(1, Ordering::Relaxed);
+
+        for destination in &self.destinations {
+            let socket = match destination.addr {
+                SocketAddr::V4(_) => &self.socket_v4,
+                SocketAddr::V6(_) => &self.socket_v6,
+            };
+            // Bound for the family of every destination
+            let socket = socket.as_ref().unwrap();
+
+            match socket.send_to(data, destination.addr) {
+                Ok(_) => {
+                    destination.sent.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) => {
+                    // Only warn once, a missing destination would flood the logs
+                    if destination.errors.fetch_add(1, Ordering::Relaxed) == 0 {
+                        warn!("Unable to relay packet to {}: {}", destination.addr, e);
+                    }
+                }
+            }
+        }
+    }
+
+    pub(crate) fn destination_list(&self) -> String {
+        self.destinations
+            .iter()
+            .map(|d| d.addr.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    pub(crate) fn log_stats(&self) {
+        info!("Relayed {} packets", self.received.load(Ordering::Relaxed));
+        for destination in &self.destinations {
+            info!(
+                "    {:<21} {:>10} sent {:>10} errors",
+                destination.addr,
+                destination.sent.load(Ordering::Relaxed),
+                destination.errors.load(Ordering::Relaxed)
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_relay {
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    use super::Relay;
+
+    #[test]
+    fn test_forward_to_both_families() {
+        let receivers = ["127.0.0.1:0", "[::1]:0"].map(|addr| {
+            let socket = UdpSocket::bind(addr).unwrap();
+            socket
+                .set_read_timeout(Some(Duration::from_secs(1)))
+                .unwrap();
+            socket
+        });
+        let destinations: Vec<_> = receivers.iter().map(|r| r.local_addr().unwrap()).collect();
+
+        let relay = Relay::new(&destinations).unwrap();
+        relay.forward(b"packet");
+
+        let mut buf = [0; 16];
+        for receiver in &receivers {
+            let len = receiver.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..len], b"packet");
+        }
+    }
+}
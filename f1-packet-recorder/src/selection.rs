@@ -0,0 +1,283 @@
+use anyhow::{Error, Result};
+use log::info;
+use rusqlite::types::Value;
+
+use f1_telemetry::packet::{parse_packet, Packet, PacketType};
+
+use super::SelectionArgs;
+use crate::recording::{RecordedPacket, Recording};
+use crate::utils::{format_session_uid, parse_session_uid};
+
+/// Range of packets to replay or copy
+#[derive(Debug, Default)]
+pub(crate) struct Selection {
+    pub(crate) session_id: Option<String>,
+    /// Session time of the first packet, in milliseconds
+    pub(crate) from_session_time: Option<u32>,
+    /// Session time after the last packet, in milliseconds
+    pub(crate) to_session_time: Option<u32>,
+    pub(crate) packet_types: Vec<PacketType>,
+    /// Label of the source the packets were recorded from
+    pub(crate) source: Option<String>,
+}
+
+impl Selection {
+    pub(crate) fn new(args: &SelectionArgs, recording: &Recording) -> Result<Self> {
+        let mut selection = Selection {
+            session_id: match &args.session {
+                Some(s) => Some(format_session_uid(parse_session_uid(s)?)),
+                None => None,
+            },
+            from_session_time: args.from_session_time.map(|t| (t * 1000.0) as u32),
+            to_session_time: args.to_session_time.map(|t| (t * 1000.0) as u32),
+            packet_types: args.packet_types.clone(),
+            source: args.source.clone(),
+        };
+
+        if args.from_lap.is_some() || args.to_lap.is_some() {
+            selection.find_laps(recording, args.from_lap, args.to_lap)?;
+        }
+
+        Ok(selection)
+    }
+
+    /// Restrict the selection to the given laps of the player.
+    ///
+    /// Without a session, the first one where the player reaches `from_lap` is used.
+    fn find_laps(
+        &mut self,
+        recording: &Recording,
+        from_lap: Option<u8>,
+        to_lap: Option<u8>,
+    ) -> Result<()> {
+        let lap_data = Selection {
+            session_id: self.session_id.clone(),
+            packet_types: vec![PacketType::LapData],
+            source: self.source.clone(),
+            ..Default::default()
+        };
+
+        let mut lap_start: Option<u32> = None;
+        let mut lap_end: Option<u32> = None;
+
+        recording.for_each_selected(&lap_data, 0, |p| {
+            let Some(lap_num) = player_lap(&p.data) else {
+                return Ok(true);
+            };
+
+            if lap_start.is_none() {
+                if from_lap.is_none_or(|from| lap_num >= from) {
+                    lap_start = Some(p.session_time as u32);
+                    self.session_id = Some(p.session_id.clone());
+                } else {
+                    return Ok(true);
+                }
+            }
+
+            if self.session_id.as_ref() != Some(&p.session_id) {
+                return Ok(true);
+            }
+
+            if to_lap.is_some_and(|to| lap_num > to) {
+                lap_end = Some(p.session_time as u32);
+                return Ok(false);
+            }
+
+            Ok(true)
+        })?;
+
+        if lap_start.is_none() {
+            return Err(match from_lap {
+                Some(lap) => Error::msg(format!("Lap {} not found", lap)),
+                None => Error::msg("No lap data found"),
+            });
+        }
+
+        if from_lap.is_some() {
+            self.from_session_time = self.from_session_time.max(lap_start);
+        }
+        if lap_end.is_some() {
+            self.to_session_time = self.to_session_time.min(lap_end).or(lap_end);
+        }
+
+        info!(
+            "Selected session {} from {:?}ms to {:?}ms",
+            self.session_id.as_deref().unwrap_or_default().trim(),
+            self.from_session_time,
+            self.to_session_time
+        );
+
+        Ok(())
+    }
+
+    /// Conditions on the `packets` table matching the selection, to append to a `WHERE` clause.
+    pub(crate) fn conditions(&self) -> (String, Vec<Value>) {
+        let mut query = String::new();
+        let mut params = Vec::new();
+
+        if let Some(session_id) = &self.session_id {
+            query.push_str(" AND sessionID = ?");
+            params.push(Value::from(session_id.clone()));
+        }
+        if let Some(from) = self.from_session_time {
+            query.push_str(" AND sessionTime >= ?");
+            params.push(Value::from(from));
+        }
+        if let Some(to) = self.to_session_time {
+            query.push_str(" AND sessionTime < ?");
+            params.push(Value::from(to));
+        }
+        if !self.packet_types.is_empty() {
+            let placeholders = vec!["?"; self.packet_types.len()].join(", ");
+            query.push_str(&format!(" AND packetID IN ({})", placeholders));
+            params.extend(
+                self.packet_types
+                    .iter()
+                    .map(|t| Value::from(u8::from(*t) as i64)),
+            );
+        }
+        if let Some(source) = &self.source {
+            query.push_str(" AND sourceLabel = ?");
+            params.push(Value::from(source.clone()));
+        }
+
+        (query, params)
+    }
+
+    /// Whether a packet matches the selection, for recordings that can't be queried
+    pub(crate) fn matches(&self, packet: &RecordedPacket) -> bool {
+        self.session_id
+            .as_ref()
+            .is_none_or(|id| *id == packet.session_id)
+            && self
+                .from_session_time
+                .is_none_or(|from| packet.session_time >= from as f64)
+            && self
+                .to_session_time
+                .is_none_or(|to| packet.session_time < to as f64)
+            && (self.packet_types.is_empty()
+                || self
+                    .packet_types
+                    .iter()
+                    .any(|t| u8::from(*t) == packet.packet_id))
+            && self
+                .source
+                .as_ref()
+                .is_none_or(|source| packet.source.label.as_ref() == Some(source))
+    }
+}
+
+/// Current lap of the player in a LapData packet
+pub(crate) fn player_lap(data: &[u8]) -> Option<u8> {
+    match parse_packet(data.len(), data) {
+        Ok(Packet::LapData(p)) => p
+            .lap_data
+            .get(p.header.player_car_index as usize)
+            .map(|l| l.current_lap_num),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test_selection {
+    use super::Selection;
+    use crate::fixtures::{lap_data_2021, laps, memory_recording};
+    use crate::recording::Recording;
+    use crate::utils::format_session_uid;
+
+    /// Laps 1 to 3 of session 0xaaaa, then a packet of session 0xbbbb
+    fn recording() -> Recording {
+        let mut packets = laps(1..=3);
+        packets.push((2000.0, lap_data_2021(0xbbbb, 0.0, 0, 1)));
+
+        Recording::Database(memory_recording(&packets))
+    }
+
+    /// Session times of the selected packets, in seconds
+    fn selected(recording: &Recording, selection: &Selection) -> Vec<u32> {
+        let mut times = vec![];
+        recording
+            .for_each_selected(selection, 0, |p| {
+                times.push(p.session_time as u32 / 1000);
+                Ok(true)
+            })
+            .unwrap();
+        times
+    }
+
+    #[test]
+    fn test_session_and_time_range() {
+        let recording = recording();
+        assert_eq!(selected(&recording, &Selection::default()).len(), 31);
+
+        let session = Selection {
+            session_id: Some(format_session_uid(0xbbbb)),
+            ..Default::default()
+        };
+        assert_eq!(selected(&recording, &session), vec![0]);
+
+        // From included, to excluded
+        let range = Selection {
+            session_id: Some(format_session_uid(0xaaaa)),
+            from_session_time: Some(5000),
+            to_session_time: Some(8000),
+            ..Default::default()
+        };
+        assert_eq!(selected(&recording, &range), vec![5, 6, 7]);
+    }
+
+    #[test]
+    fn test_lap_range() {
+        let recording = recording();
+
+        let mut selection = Selection::default();
+        selection.find_laps(&recording, Some(2), Some(2)).unwrap();
+        assert_eq!(selection.session_id, Some(format_session_uid(0xaaaa)));
+        assert_eq!(
+            selected(&recording, &selection),
+            (10..20).collect::<Vec<_>>()
+        );
+
+        // A time range within the laps narrows them
+        let mut selection = Selection {
+            from_session_time: Some(12000),
+            ..Default::default()
+        };
+        selection.find_laps(&recording, Some(2), None).unwrap();
+        assert_eq!(
+            selected(&recording, &selection),
+            (12..30).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_first_and_last_laps() {
+        let recording = recording();
+
+        let mut first = Selection::default();
+        first.find_laps(&recording, Some(1), Some(1)).unwrap();
+        assert_eq!(selected(&recording, &first), (0..10).collect::<Vec<_>>());
+
+        // Until the end of the session, without a next lap
+        let mut last = Selection::default();
+        last.find_laps(&recording, Some(3), Some(3)).unwrap();
+        assert_eq!(last.to_session_time, None);
+        assert_eq!(selected(&recording, &last), (20..30).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_lap_not_found() {
+        let recording = recording();
+        let error = Selection::default()
+            .find_laps(&recording, Some(4), None)
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Lap 4 not found");
+
+        // Without any LapData packet
+        let recording = Recording::Database(memory_recording(&[]));
+        let error = Selection::default()
+            .find_laps(&recording, None, Some(2))
+            .unwrap_err();
+        assert_eq!(error.to_string(), "No lap data found");
+    }
+}
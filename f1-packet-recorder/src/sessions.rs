@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use rusqlite::Connection;
+
+use f1_telemetry::packet::Packet;
+
+use crate::utils::format_session_uid;
+
+/// Summary of a recorded session, built from the Session and Participants packets.
+#[derive(Debug, Default)]
+pub(crate) struct SessionSummary {
+    session_uid: u64,
+    /// Label of the source the packets were recorded from, each source of a session has its own
+    /// summary
+    source_label: Option<String>,
+    packet_format: u16,
+    track: Option<String>,
+    session_type: Option<String>,
+    formula: Option<String>,
+    start_time: f64,
+    end_time: f64,
+    player_car_index: u8,
+    player_driver: Option<String>,
+    /// Packets received since the summary was last saved, added to the saved count
+    unsaved_packets: u64,
+}
+
+impl SessionSummary {
+    fn new(session_uid: u64, source_label: Option<&str>, timestamp: f64) -> Self {
+        Self {
+            session_uid,
+            source_label: source_label.map(str::to_string),
+            start_time: timestamp,
+            end_time: timestamp,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn update(&mut self, timestamp: f64, packet: &Packet) {
+        let header = packet.header();
+
+        self.packet_format = header.packet_format;
+        self.player_car_index = header.player_car_index;
+        self.start_time = self.start_time.min(timestamp);
+        self.end_time = self.end_time.max(timestamp);
+        self.unsaved_packets += 1;
+
+        match packet {
+            Packet::Session(s) => {
+                self.track = Some(format!("{:?}", s.track));
+                self.session_type = Some(format!("{:?}", s.session_type));
+                self.formula = Some(format!("{:?}", s.formula));
+            }
+            Packet::Participants(p) => {
+                if let Some(participant) = p.participants.get(header.player_car_index as usize) {
+                    self.player_driver = Some(participant.name.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Merge the summary into the saved one, which may come from an earlier recording into the
+    /// same file.
+    fn save(&mut self, conn: &Connection) -> Result<()> {
+        let mut stmt = conn.prepare_cached(
+            "
+            INSERT INTO sessions(
+                sessionID, sourceLabel, packetFormat, track, sessionType, formula, startTime,
+                endTime, playerCarIndex, playerDriver, packetCount
+            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT(sessionID, sourceLabel) DO UPDATE SET
+                packetFormat = excluded.packetFormat,
+                track = COALESCE(excluded.track, track),
+                sessionType = COALESCE(excluded.sessionType, sessionType),
+                formula = COALESCE(excluded.formula, formula),
+                startTime = MIN(startTime, excluded.startTime),
+                endTime = MAX(endTime, excluded.endTime),
+                playerCarIndex = excluded.playerCarIndex,
+                playerDriver = COALESCE(excluded.playerDriver, playerDriver),
+                packetCount = packetCount + excluded.packetCount;
+        ",
+        )?;
+
+        stmt.execute((
+            format_session_uid(self.session_uid),
+            self.source_label.as_deref().unwrap_or_default(),
+            self.packet_format,
+            &self.track,
+            &self.session_type,
+            &self.formula,
+            self.start_time,
+            self.end_time,
+            self.player_car_index,
+            &self.player_driver,
+            self.unsaved_packets,
+        ))?;
+        self.unsaved_packets = 0;
+
+        Ok(())
+    }
+}
+
+/// Sessions seen during a recording, indexed by session UID and source label.
+#[derive(Debug, Default)]
+pub(crate) struct Sessions {
+    sessions: HashMap<(u64, Option<String>), SessionSummary>,
+}
+
+impl Sessions {
+    pub(crate) fn update(&mut self, timestamp: f64, packet: &Packet, source_label: Option<&str>) {
+        let session_uid = packet.header().session_uid;
+
+        self.sessions
+            .entry((session_uid, source_label.map(str::to_string)))
+            .or_insert_with(|| SessionSummary::new(session_uid, source_label, timestamp))
+            .update(timestamp, packet);
+    }
+
+    /// Save the summaries of a single session, from every source, to the given database.
+    pub(crate) fn save(&mut self, conn: &Connection, session_uid: u64) -> Result<()> {
+        for ((uid, _), summary) in self.sessions.iter_mut() {
+            if *uid == session_uid {
+                summary.save(conn)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Save the summaries of every session to the given database.
+    pub(crate) fn save_all(&mut self, conn: &Connection) -> Result<()> {
+        for summary in self.sessions.values_mut() {
+            summary.save(conn)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) fn init_sessions_table(conn: &Connection) -> Result<()> {
+    let create_table_stmt = "
+        CREATE TABLE IF NOT EXISTS sessions (
+            sessionID        CHAR(16) PRIMARY KEY,
+            packetFormat     INTEGER  NOT NULL,
+            track            TEXT,
+            sessionType      TEXT,
+            formula          TEXT,
+            startTime        REAL     NOT NULL,
+            endTime          REAL     NOT NULL,
+            playerCarIndex   INTEGER  NOT NULL,
+            playerDriver     TEXT,
+            packetCount      INTEGER  NOT NULL
+        );
+    ";
+
+    conn.execute(create_table_stmt, ())?;
+
+    Ok(())
+}
+
+/// Keep a summary per source of a session, as sources see different players and times.
+///
+/// Packets without a label are summarized under an empty label, so that they still share a row.
+/// Saved summaries are assigned to the label of their packets when they all came from the same
+/// source.
+pub(crate) fn add_session_sources(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "
+        ALTER TABLE sessions RENAME TO sessions_old;
+
+        CREATE TABLE sessions (
+            sessionID        CHAR(16) NOT NULL,
+            sourceLabel      TEXT     NOT NULL DEFAULT '',
+            packetFormat     INTEGER  NOT NULL,
+            track            TEXT,
+            sessionType      TEXT,
+            formula          TEXT,
+            startTime        REAL     NOT NULL,
+            endTime          REAL     NOT NULL,
+            playerCarIndex   INTEGER  NOT NULL,
+            playerDriver     TEXT,
+            packetCount      INTEGER  NOT NULL,
+            PRIMARY KEY (sessionID, sourceLabel)
+        );
+
+        INSERT INTO sessions
+        SELECT
+            sessionID,
+            COALESCE((
+                SELECT MAX(sourceLabel) FROM packets
+                WHERE packets.sessionID = sessions_old.sessionID
+                HAVING COUNT(DISTINCT COALESCE(sourceLabel, '')) = 1
+            ), ''),
+            packetFormat, track, sessionType, formula, startTime, endTime, playerCarIndex,
+            playerDriver, packetCount
+        FROM sessions_old;
+
+        DROP TABLE sessions_old;
+        ",
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_sessions {
+    use rusqlite::Connection;
+
+    use super::SessionSummary;
+    use crate::db::init_db;
+
+    fn saved(conn: &Connection) -> (f64, f64, u64, Option<String>) {
+        conn.query_row(
+            "SELECT startTime, endTime, packetCount, track FROM sessions;",
+            (),
+            |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_save_merges_recordings() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+
+        let mut first = SessionSummary::new(0xaaaa, None, 10.0);
+        first.end_time = 20.0;
+        first.track = Some("Monza".to_string());
+        first.unsaved_packets = 100;
+        first.save(&conn).unwrap();
+
+        // Saving again only adds the packets received since
+        first.unsaved_packets = 5;
+        first.end_time = 21.0;
+        first.save(&conn).unwrap();
+        assert_eq!(saved(&conn), (10.0, 21.0, 105, Some("Monza".to_string())));
+
+        // A later recording of the same session, into the same file
+        let mut second = SessionSummary::new(0xaaaa, None, 5.0);
+        second.end_time = 15.0;
+        second.unsaved_packets = 50;
+        second.save(&conn).unwrap();
+        assert_eq!(saved(&conn), (5.0, 21.0, 155, Some("Monza".to_string())));
+    }
+
+    #[test]
+    fn test_save_sources_separately() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+
+        for (label, packets) in [(Some("alice"), 10), (Some("bob"), 20), (None, 30)] {
+            let mut summary = SessionSummary::new(0xaaaa, label, 10.0);
+            summary.unsaved_packets = packets;
+            summary.save(&conn).unwrap();
+        }
+
+        let mut stmt = conn
+            .prepare("SELECT sourceLabel, packetCount FROM sessions ORDER BY sourceLabel;")
+            .unwrap();
+        let rows: Vec<(String, u64)> = stmt
+            .query_map((), |r| Ok((r.get(0)?, r.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                ("".to_string(), 30),
+                ("alice".to_string(), 10),
+                ("bob".to_string(), 20)
+            ]
+        );
+    }
+}
@@ -1,4 +1,9 @@
-use rusqlite::Connection;
+use f1_telemetry::packet::PacketType;
+use rusqlite::{Connection, OpenFlags};
+
+use crate::binlog::{is_binlog, open_in_memory};
+use crate::db::{check_schema, init_db};
+use std::path::Path;
 use std::sync::mpsc::{channel, Receiver};
 
 pub(crate) fn ctrl_c_channel() -> anyhow::Result<Receiver<()>, ctrlc::Error> {
@@ -9,8 +14,137 @@ pub(crate) fn ctrl_c_channel() -> anyhow::Result<Receiver<()>, ctrlc::Error> {
 
     Ok(receiver)
 }
+
+/// Open a database, creating or migrating its tables if needed.
 pub(crate) fn get_database_connection(file: &str) -> anyhow::Result<Connection> {
     let conn = Connection::open(file)?;
+    init_db(&conn)?;
+
+    Ok(conn)
+}
+
+/// Open an existing database to read it, without changing it.
+///
+/// Fails if the database has an older schema, which only writing commands upgrade.
+pub(crate) fn open_database_read_only(file: &str) -> anyhow::Result<Connection> {
+    if !Path::new(file).exists() {
+        return Err(anyhow::Error::msg(format!("{} not found", file)));
+    }
+
+    let conn = Connection::open_with_flags(
+        file,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
+    check_schema(&conn, file)?;
 
     Ok(conn)
 }
+
+/// Open a recording to read it, whether it is a database or a binary log.
+pub(crate) fn open_recording(file: &str) -> anyhow::Result<Connection> {
+    match is_binlog(file)? {
+        true => open_in_memory(file),
+        false => open_database_read_only(file),
+    }
+}
+
+pub(crate) fn format_session_uid(session_uid: u64) -> String {
+    format!("{:16x}", session_uid)
+}
+
+pub(crate) fn parse_session_uid(session_uid: &str) -> anyhow::Result<u64> {
+    u64::from_str_radix(session_uid.trim(), 16)
+        .map_err(|e| anyhow::Error::msg(format!("Invalid session UID {}: {}", session_uid, e)))
+}
+
+/// Name of the database file for a given session, when splitting sessions into multiple files.
+///
+/// For example, `race.db` becomes `race_<session uid>.db`.
+pub(crate) fn session_file_name(file: &str, session_uid: u64) -> String {
+    let path = Path::new(file);
+    let stem = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("session");
+    let name = match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => format!("{}_{:016x}.{}", stem, session_uid, ext),
+        None => format!("{}_{:016x}", stem, session_uid),
+    };
+
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+/// Offsets of the header fields in a raw packet, which depend on the packet format.
+pub(crate) struct HeaderLayout {
+    pub(crate) packet_id: usize,
+    pub(crate) session_uid: usize,
+    /// Session time, in seconds as a `f32`
+    pub(crate) session_time: usize,
+    pub(crate) frame_identifier: usize,
+    /// Frame identifier that doesn't go back after flashbacks, since 2023
+    pub(crate) overall_frame_identifier: Option<usize>,
+    pub(crate) player_car_index: usize,
+    /// Index of the car of a second player in split screen, since 2020
+    pub(crate) secondary_player_car_index: Option<usize>,
+    /// Size of the header
+    pub(crate) size: usize,
+}
+
+impl HeaderLayout {
+    pub(crate) fn of(packet: &[u8]) -> Option<Self> {
+        let packet_format = u16::from_le_bytes(packet.get(0..2)?.try_into().ok()?);
+
+        let layout = match packet_format {
+            2019 => HeaderLayout {
+                packet_id: 5,
+                session_uid: 6,
+                session_time: 14,
+                frame_identifier: 18,
+                overall_frame_identifier: None,
+                player_car_index: 22,
+                secondary_player_car_index: None,
+                size: 23,
+            },
+            2020..=2022 => HeaderLayout {
+                packet_id: 5,
+                session_uid: 6,
+                session_time: 14,
+                frame_identifier: 18,
+                overall_frame_identifier: None,
+                player_car_index: 22,
+                secondary_player_car_index: Some(23),
+                size: 24,
+            },
+            2023 => HeaderLayout {
+                packet_id: 6,
+                session_uid: 7,
+                session_time: 15,
+                frame_identifier: 19,
+                overall_frame_identifier: Some(23),
+                player_car_index: 27,
+                secondary_player_car_index: Some(28),
+                size: 29,
+            },
+            _ => return None,
+        };
+
+        (packet.len() >= layout.size).then_some(layout)
+    }
+}
+
+/// A positive duration, in seconds
+pub(crate) fn parse_seconds(value: &str) -> anyhow::Result<f64> {
+    value
+        .trim()
+        .parse()
+        .ok()
+        .filter(|s: &f64| s.is_finite() && *s > 0.0)
+        .ok_or_else(|| anyhow::Error::msg(format!("Invalid duration: {}", value)))
+}
+
+pub(crate) fn parse_packet_type(name: &str) -> anyhow::Result<PacketType> {
+    PacketType::ALL
+        .into_iter()
+        .find(|t| t.name() == name)
+        .ok_or_else(|| anyhow::Error::msg(format!("Invalid packet type: {}", name)))
+}
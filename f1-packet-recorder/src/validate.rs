@@ -0,0 +1,215 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Error, Result};
+use rusqlite::Row;
+
+use f1_telemetry::packet::header::PacketHeader;
+use f1_telemetry::packet::{parse_packet, PacketType};
+
+use crate::compression::PacketDecompressor;
+use crate::db::{schema_version, SCHEMA_VERSION};
+use crate::quarantine::count_quarantined;
+use crate::utils::{format_session_uid, open_database_read_only};
+
+use super::ValidateArgs;
+
+pub(crate) fn validate(args: &ValidateArgs) -> Result<()> {
+    let conn = open_database_read_only(&args.file)?;
+    let mut decompressor = PacketDecompressor::new(&conn)?;
+
+    let mut packets = 0;
+    // Failures by packet format and ID, with the first error
+    let mut failures: BTreeMap<(u16, u8), (u64, String)> = BTreeMap::new();
+    // Mismatches by column, with the first packet
+    let mut mismatches: BTreeMap<&str, (u64, i64)> = BTreeMap::new();
+
+    let mut stmt = conn.prepare(
+        "
+        SELECT pkt_id, packetFormat, gameMajorVersion, gameMinorVersion, packetVersion, packetID,
+            sessionID, sessionTime, frameIdentifier, playerCarIndex, packet
+        FROM packets ORDER BY pkt_id;
+        ",
+    )?;
+    let mut rows = stmt.query(())?;
+
+    while let Some(row) = rows.next()? {
+        packets += 1;
+        let columns = HeaderColumns::from_row(row)?;
+
+        let parsed = decompressor
+            .decompress(row.get(10)?)
+            .and_then(|data| Ok(parse_packet(data.len(), &data)?));
+
+        match parsed {
+            Ok(packet) => {
+                for column in columns.mismatches(packet.header()) {
+                    mismatches.entry(column).or_insert((0, columns.pkt_id)).0 += 1;
+                }
+            }
+            Err(e) => {
+                let key = (columns.packet_format, columns.packet_id);
+                failures.entry(key).or_insert((0, e.to_string())).0 += 1;
+            }
+        }
+    }
+
+    let failure_count: u64 = failures.values().map(|(count, _)| count).sum();
+    let mismatch_count: u64 = mismatches.values().map(|(count, _)| count).sum();
+
+    println!("File:              {}", args.file);
+    println!(
+        "Schema version:    {} (supported: {})",
+        schema_version(&conn)?,
+        SCHEMA_VERSION
+    );
+    println!("Packets:           {}", packets);
+    println!("Quarantined:       {}", count_quarantined(&conn)?);
+    println!("Parse failures:    {}", failure_count);
+    for ((packet_format, packet_id), (count, error)) in &failures {
+        let packet_type = PacketType::ALL
+            .into_iter()
+            .find(|t| u8::from(*t) == *packet_id)
+            .map_or("unknown", PacketType::name);
+
+        println!(
+            "    {} {:<20} {:>8}  {}",
+            packet_format, packet_type, count, error
+        );
+    }
+    println!("Header mismatches: {}", mismatch_count);
+    for (column, (count, pkt_id)) in &mismatches {
+        println!(
+            "    {:<20} {:>8}  first in packet {}",
+            column, count, pkt_id
+        );
+    }
+
+    if failure_count > 0 || mismatch_count > 0 {
+        return Err(Error::msg("Validation failed"));
+    }
+
+    Ok(())
+}
+
+/// Header fields stored in their own columns of the `packets` table
+#[derive(Debug, Default)]
+struct HeaderColumns {
+    pkt_id: i64,
+    packet_format: u16,
+    game_major_version: u8,
+    game_minor_version: u8,
+    packet_version: u8,
+    packet_id: u8,
+    session_id: String,
+    session_time: f64,
+    frame_identifier: u32,
+    player_car_index: u8,
+}
+
+impl HeaderColumns {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            pkt_id: row.get(0)?,
+            packet_format: row.get(1)?,
+            game_major_version: row.get(2)?,
+            game_minor_version: row.get(3)?,
+            packet_version: row.get(4)?,
+            packet_id: row.get(5)?,
+            session_id: row.get(6)?,
+            session_time: row.get(7)?,
+            frame_identifier: row.get(8)?,
+            player_car_index: row.get(9)?,
+        })
+    }
+
+    /// Columns that don't match the header of the stored packet
+    fn mismatches(&self, header: &PacketHeader) -> Vec<&'static str> {
+        let checks = [
+            ("packetFormat", self.packet_format == header.packet_format),
+            (
+                "gameMajorVersion",
+                self.game_major_version == header.game_major_version,
+            ),
+            (
+                "gameMinorVersion",
+                self.game_minor_version == header.game_minor_version,
+            ),
+            (
+                "packetVersion",
+                self.packet_version == header.packet_version,
+            ),
+            ("packetID", self.packet_id == u8::from(header.packet_type)),
+            (
+                "sessionID",
+                self.session_id == format_session_uid(header.session_uid),
+            ),
+            (
+                "sessionTime",
+                self.session_time == header.session_time as f64,
+            ),
+            (
+                "frameIdentifier",
+                self.frame_identifier == header.frame_identifier,
+            ),
+            (
+                "playerCarIndex",
+                self.player_car_index == header.player_car_index,
+            ),
+        ];
+
+        checks
+            .into_iter()
+            .filter(|(_, matches)| !matches)
+            .map(|(column, _)| column)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test_header_columns {
+    use f1_telemetry::packet::header::PacketHeader;
+    use f1_telemetry::packet::PacketType;
+
+    use super::HeaderColumns;
+    use crate::utils::format_session_uid;
+
+    #[test]
+    fn test_mismatches() {
+        let header = PacketHeader {
+            packet_format: 2021,
+            game_year: 21,
+            game_major_version: 1,
+            game_minor_version: 18,
+            packet_version: 1,
+            packet_type: PacketType::LapData,
+            session_uid: 0xaaaa,
+            session_time: 1500,
+            frame_identifier: 90,
+            overall_frame_identifier: None,
+            player_car_index: 19,
+            secondary_player_car_index: None,
+        };
+        let mut columns = HeaderColumns {
+            pkt_id: 1,
+            packet_format: 2021,
+            game_major_version: 1,
+            game_minor_version: 18,
+            packet_version: 1,
+            packet_id: 2,
+            session_id: format_session_uid(0xaaaa),
+            session_time: 1500.0,
+            frame_identifier: 90,
+            player_car_index: 19,
+        };
+
+        assert!(columns.mismatches(&header).is_empty());
+
+        columns.session_time = 1.5;
+        columns.player_car_index = 0;
+
+        assert_eq!(
+            columns.mismatches(&header),
+            vec!["sessionTime", "playerCarIndex"]
+        );
+    }
+}
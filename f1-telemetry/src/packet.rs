@@ -112,6 +112,46 @@ pub enum PacketType {
     MotionEx,
 }
 
+impl PacketType {
+    /// Every packet type, by ID
+    pub const ALL: [PacketType; 14] = [
+        PacketType::Motion,
+        PacketType::Session,
+        PacketType::LapData,
+        PacketType::Event,
+        PacketType::Participants,
+        PacketType::CarSetups,
+        PacketType::CarTelemetry,
+        PacketType::CarStatus,
+        PacketType::FinalClassification,
+        PacketType::LobbyInfo,
+        PacketType::CarDamage,
+        PacketType::SessionHistory,
+        PacketType::TyreSets,
+        PacketType::MotionEx,
+    ];
+
+    /// Name in snake case, as used in file names and command line arguments
+    pub fn name<'a>(self) -> &'a str {
+        match self {
+            PacketType::Motion => "motion",
+            PacketType::Session => "session",
+            PacketType::LapData => "lap_data",
+            PacketType::Event => "event",
+            PacketType::Participants => "participants",
+            PacketType::CarSetups => "car_setups",
+            PacketType::CarTelemetry => "car_telemetry",
+            PacketType::CarStatus => "car_status",
+            PacketType::FinalClassification => "final_classification",
+            PacketType::LobbyInfo => "lobby_info",
+            PacketType::CarDamage => "car_damage",
+            PacketType::SessionHistory => "session_history",
+            PacketType::TyreSets => "tyre_sets",
+            PacketType::MotionEx => "motion_ex",
+        }
+    }
+}
+
 impl From<PacketType> for u8 {
     fn from(val: PacketType) -> u8 {
         match val {
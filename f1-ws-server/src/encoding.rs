@@ -0,0 +1,171 @@
+//! Encodings of the packets sent to the WebSocket clients.
+//!
+//! Clients get JSON text messages by default, and can ask for binary MessagePack or CBOR messages
+//! with the `msgpack` or `cbor` subprotocol, or with the `encoding` query parameter:
+//! `ws://host:20888/?encoding=cbor`. Control messages and their replies stay in JSON.
+
+use serde::Serialize;
+use tokio_tungstenite::tungstenite::Message;
+
+/// Subprotocols of the encodings, in order of preference
+const SUBPROTOCOLS: [(&str, Encoding); 3] = [
+    ("msgpack", Encoding::MessagePack),
+    ("cbor", Encoding::Cbor),
+    ("json", Encoding::Json),
+];
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Encoding {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Encoding {
+    pub(crate) const COUNT: usize = 3;
+
+    /// Encoding requested by a client, and the subprotocol to accept if it used one
+    pub(crate) fn negotiate(
+        query: Option<&str>,
+        subprotocols: Option<&str>,
+    ) -> Result<(Self, Option<&'static str>), String> {
+        let offered: Vec<&str> = subprotocols
+            .map(|s| s.split(',').map(str::trim).collect())
+            .unwrap_or_default();
+        if let Some((name, encoding)) = SUBPROTOCOLS.iter().find(|(n, _)| offered.contains(n)) {
+            return Ok((*encoding, Some(name)));
+        }
+
+        let requested = query
+            .unwrap_or_default()
+            .split('&')
+            .find_map(|param| param.strip_prefix("encoding="));
+        match requested {
+            Some(name) => SUBPROTOCOLS
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, encoding)| (*encoding, None))
+                .ok_or_else(|| format!("Unknown encoding: {}", name)),
+            None => Ok((Self::Json, None)),
+        }
+    }
+
+    pub(crate) fn encode<T: Serialize>(self, value: &T) -> Message {
+        match self {
+            Self::Json => Message::Text(serde_json::to_string(value).unwrap()),
+            // With the field names, like the JSON objects
+            Self::MessagePack => Message::Binary(rmp_serde::to_vec_named(value).unwrap()),
+            Self::Cbor => {
+                let mut data = Vec::new();
+                ciborium::into_writer(value, &mut data).unwrap();
+                Message::Binary(data)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_encoding {
+    use std::time::Instant;
+
+    use f1_telemetry::packet::{parse_packet, Packet};
+    use serde_json::Value;
+    use tokio_tungstenite::tungstenite::Message;
+
+    use super::Encoding;
+
+    /// 2021 Motion packet, with varied values in every field
+    fn motion_packet() -> Packet {
+        let mut data = vec![0u8; 1464];
+        data[0..2].copy_from_slice(&2021u16.to_le_bytes());
+        for (idx, chunk) in data[24..].chunks_exact_mut(4).enumerate() {
+            let value = (idx as f32 * 0.37).sin() * 100.0;
+            chunk.copy_from_slice(&value.to_le_bytes());
+        }
+
+        parse_packet(data.len(), &data).unwrap()
+    }
+
+    fn decode(encoding: Encoding, message: &Message) -> Value {
+        match (encoding, message) {
+            (Encoding::Json, Message::Text(text)) => serde_json::from_str(text).unwrap(),
+            (Encoding::MessagePack, Message::Binary(data)) => rmp_serde::from_slice(data).unwrap(),
+            (Encoding::Cbor, Message::Binary(data)) => ciborium::from_reader(&data[..]).unwrap(),
+            _ => panic!("unexpected message for {:?}", encoding),
+        }
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(Encoding::negotiate(None, None), Ok((Encoding::Json, None)));
+        assert_eq!(
+            Encoding::negotiate(None, Some("foo, cbor")),
+            Ok((Encoding::Cbor, Some("cbor")))
+        );
+        assert_eq!(
+            Encoding::negotiate(Some("encoding=cbor"), Some("msgpack")),
+            Ok((Encoding::MessagePack, Some("msgpack")))
+        );
+        assert_eq!(
+            Encoding::negotiate(Some("token=abc&encoding=msgpack"), Some("foo")),
+            Ok((Encoding::MessagePack, None))
+        );
+        assert!(Encoding::negotiate(Some("encoding=xml"), None).is_err());
+    }
+
+    #[test]
+    fn test_encodings() {
+        let packet = motion_packet();
+        let json = serde_json::to_value(&packet).unwrap();
+
+        for encoding in [Encoding::Json, Encoding::MessagePack, Encoding::Cbor] {
+            let decoded = decode(encoding, &encoding.encode(&packet));
+            assert_eq!(decoded["packet_type"], json["packet_type"]);
+            assert_eq!(decoded["header"], json["header"]);
+            assert_eq!(
+                decoded["motion_data"].as_array().unwrap().len(),
+                json["motion_data"].as_array().unwrap().len()
+            );
+        }
+    }
+
+    #[test]
+    fn test_size() {
+        let packet = motion_packet();
+        let size = |encoding: Encoding| encoding.encode(&packet).len();
+
+        assert!(
+            size(Encoding::MessagePack) < size(Encoding::Json),
+            "MessagePack larger than JSON"
+        );
+        assert!(
+            size(Encoding::Cbor) < size(Encoding::Json),
+            "CBOR larger than JSON"
+        );
+    }
+
+    /// Compare the encoding speeds, with `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn test_throughput() {
+        const ITERATIONS: u32 = 2000;
+        let packet = motion_packet();
+
+        for encoding in [Encoding::Json, Encoding::MessagePack, Encoding::Cbor] {
+            let start = Instant::now();
+            let mut size = 0;
+            for _ in 0..ITERATIONS {
+                size = encoding.encode(&packet).len();
+            }
+            let elapsed = start.elapsed();
+
+            println!(
+                "{:?}: {} bytes, {:.1}µs per packet, {:.1}MB/s",
+                encoding,
+                size,
+                elapsed.as_secs_f64() * 1e6 / ITERATIONS as f64,
+                (size as f64 * ITERATIONS as f64) / elapsed.as_secs_f64() / 1e6
+            );
+        }
+    }
+}
@@ -1,19 +1,37 @@
+use std::collections::VecDeque;
+use std::future::poll_fn;
 use std::io::ErrorKind::BrokenPipe;
 use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
 
 use clap::Parser;
-use futures_util::SinkExt;
+use futures_util::{SinkExt, StreamExt};
 use log::{error, info, warn, LevelFilter};
 use simplelog::{ColorChoice, TerminalMode};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::broadcast::Receiver;
-use tokio_tungstenite::accept_async;
+use tokio::time::sleep_until;
+use tokio_tungstenite::accept_hdr_async;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::header::SEC_WEBSOCKET_PROTOCOL;
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
 use tokio_tungstenite::tungstenite::{Error, Message, Result};
 
+use f1_telemetry::packet::Packet;
 use f1_telemetry::Stream;
 use f1_telemetry_common::logging::LogBuilder;
 
+use crate::encoding::Encoding;
+use crate::rate_limit::RateLimiter;
+use crate::subscription::Subscription;
+
+mod encoding;
+mod rate_limit;
+mod subscription;
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None, propagate_version = true)]
 struct AppArgs {
@@ -34,6 +52,27 @@ struct AppArgs {
     server_port: u16,
 }
 
+/// Packets kept for the clients between two reads of the broadcast channel
+const CHANNEL_CAPACITY: usize = 256;
+
+/// Packets kept for a client while its socket is busy, a few frames' worth
+const MAX_QUEUED_PACKETS: usize = 256;
+
+/// Packet broadcast to every client
+struct SharedPacket {
+    packet: Packet,
+    /// Serialized once per encoding, for the clients receiving the whole packet
+    encoded: [OnceLock<Message>; Encoding::COUNT],
+}
+
+impl SharedPacket {
+    fn message(&self, encoding: Encoding) -> Message {
+        self.encoded[encoding as usize]
+            .get_or_init(|| encoding.encode(&self.packet))
+            .clone()
+    }
+}
+
 #[tokio::main]
 async fn main() {
     let args = AppArgs::parse();
@@ -49,15 +88,17 @@ async fn main() {
         .expect("Unable to bind packet socket");
     info!("Listening for telemetry packets on: {}", addr);
 
-    let (tx, _) = broadcast::channel(32);
+    let (tx, _) = broadcast::channel(CHANNEL_CAPACITY);
 
     let packet_tx = tx.clone();
     tokio::spawn(async move {
         loop {
             match packet_stream.next().await {
-                Ok(p) => {
-                    let value = serde_json::to_string(&p).unwrap();
-                    let _ = packet_tx.send(value);
+                Ok(packet) => {
+                    let _ = packet_tx.send(Arc::new(SharedPacket {
+                        packet,
+                        encoded: Default::default(),
+                    }));
                 }
                 Err(err) => {
                     error!("{:?}", err);
@@ -84,7 +125,7 @@ async fn main() {
     }
 }
 
-async fn accept_connection(peer: SocketAddr, stream: TcpStream, rx: Receiver<String>) {
+async fn accept_connection(peer: SocketAddr, stream: TcpStream, rx: Receiver<Arc<SharedPacket>>) {
     if let Err(e) = handle_connection(peer, stream, rx).await {
         match e {
             Error::ConnectionClosed | Error::Utf8 => (),
@@ -103,22 +144,184 @@ async fn accept_connection(peer: SocketAddr, stream: TcpStream, rx: Receiver<Str
     }
 }
 
+// The handshake callback has to return the error response of tungstenite
+#[allow(clippy::result_large_err)]
 async fn handle_connection(
     peer: SocketAddr,
     stream: TcpStream,
-    mut rx: Receiver<String>,
+    mut rx: Receiver<Arc<SharedPacket>>,
 ) -> Result<()> {
-    let mut ws_stream = accept_async(stream).await.expect("Failed to accept");
-    info!("New WebSocket connection: {}", peer);
-
-    loop {
-        match rx.recv().await {
-            Ok(p) => {
-                ws_stream.send(Message::Text(p)).await?;
+    let mut encoding = Encoding::Json;
+    let ws_stream = accept_hdr_async(stream, |request: &Request, mut response: Response| {
+        match negotiate_encoding(request, &mut response) {
+            Ok(requested) => {
+                encoding = requested;
+                Ok(response)
             }
-            Err(_e) => {
-                error!("{:?}", _e);
+            Err(message) => {
+                let mut error = ErrorResponse::new(Some(message));
+                *error.status_mut() = StatusCode::BAD_REQUEST;
+                Err(error)
             }
         }
+    })
+    .await?;
+    info!("New WebSocket connection: {} ({:?})", peer, encoding);
+
+    // The socket only takes a packet when it is ready, so that the broadcast channel keeps being
+    // drained while a slow client catches up, and rate limited packets coalesced meanwhile.
+    let (mut sink, mut source) = ws_stream.split();
+    let mut replies = VecDeque::new();
+    let mut queued = PacketQueue::default();
+    let mut unflushed = false;
+
+    let mut subscription = Subscription::default();
+    let mut limiter = RateLimiter::default();
+
+    loop {
+        let next_due = limiter.next_due();
+        let has_output = !replies.is_empty() || !queued.is_empty();
+
+        tokio::select! {
+            packet = rx.recv() => match packet {
+                Ok(p) if !subscription.wants(&p.packet) => (),
+                Ok(p) => {
+                    let p = match subscription.rate_limit(&p.packet) {
+                        // Only the latest one is sent once the client catches up
+                        Some((slot, interval)) if has_output => {
+                            limiter.keep(slot, interval, p, Instant::now());
+                            None
+                        }
+                        Some((slot, interval)) => limiter.offer(slot, interval, p, Instant::now()),
+                        None => Some(p),
+                    };
+
+                    if let Some(p) = p {
+                        queued.push(p);
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Client too slow, skipped {} packets: {}", skipped, peer);
+                }
+                Err(RecvError::Closed) => break,
+            },
+            _ = sleep_until(next_due.unwrap_or_else(Instant::now).into()), if next_due.is_some() => {
+                for p in limiter.take_due(Instant::now()) {
+                    queued.push(p);
+                }
+            },
+            result = poll_fn(|cx| match has_output {
+                true => sink.poll_ready_unpin(cx),
+                false => sink.poll_flush_unpin(cx),
+            }), if has_output || unflushed => {
+                result?;
+
+                if queued.dropped > 0 {
+                    warn!("Client too slow, dropped {} queued packets: {}", queued.dropped, peer);
+                    queued.dropped = 0;
+                }
+                if !has_output {
+                    unflushed = false;
+                    continue;
+                }
+
+                // Replies to the client first, then the packets in the order they were queued
+                let message = match replies.pop_front() {
+                    Some(reply) => Some(reply),
+                    None => queued
+                        .pop()
+                        .and_then(|p| packet_message(&subscription, &p, encoding)),
+                };
+                if let Some(message) = message {
+                    sink.start_send_unpin(message)?;
+                    unflushed = true;
+                }
+            },
+            message = source.next() => match message {
+                Some(Ok(Message::Text(control))) => {
+                    let reply = subscription.handle(&control);
+                    let reply = serde_json::to_string(&reply).unwrap();
+                    replies.push_back(Message::Text(reply));
+                }
+                Some(Ok(Message::Close(_))) | None => break,
+                Some(Ok(_)) => (),
+                Some(Err(e)) => return Err(e),
+            },
+        }
+    }
+
+    if limiter.coalesced() > 0 {
+        info!(
+            "Coalesced {} rate limited packets: {}",
+            limiter.coalesced(),
+            peer
+        );
+    }
+
+    Ok(())
+}
+
+/// Packets waiting for the socket of a client, of which the oldest are dropped past
+/// `MAX_QUEUED_PACKETS`. Rate limited packets stay in the limiter instead.
+#[derive(Default)]
+struct PacketQueue {
+    packets: VecDeque<Arc<SharedPacket>>,
+    dropped: u64,
+}
+
+impl PacketQueue {
+    fn push(&mut self, packet: Arc<SharedPacket>) {
+        if self.packets.len() == MAX_QUEUED_PACKETS {
+            self.packets.pop_front();
+            self.dropped += 1;
+        }
+        self.packets.push_back(packet);
+    }
+
+    fn pop(&mut self) -> Option<Arc<SharedPacket>> {
+        self.packets.pop_front()
     }
+
+    fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+}
+
+/// Pick the encoding from the subprotocols or the query string of the handshake.
+fn negotiate_encoding(request: &Request, response: &mut Response) -> Result<Encoding, String> {
+    let subprotocols = request
+        .headers()
+        .get(SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|h| h.to_str().ok());
+
+    let (encoding, subprotocol) = Encoding::negotiate(request.uri().query(), subprotocols)?;
+    if let Some(subprotocol) = subprotocol {
+        response.headers_mut().insert(
+            SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static(subprotocol),
+        );
+    }
+
+    Ok(encoding)
+}
+
+/// Message of a packet for a client, if subscribed to it
+fn packet_message(
+    subscription: &Subscription,
+    shared: &SharedPacket,
+    encoding: Encoding,
+) -> Option<Message> {
+    if !subscription.wants(&shared.packet) {
+        return None;
+    }
+
+    let message = match subscription.filters_cars(&shared.packet) {
+        true => {
+            let value = serde_json::to_value(&shared.packet).unwrap();
+            encoding.encode(&subscription.filter_cars(&shared.packet, value))
+        }
+        false => shared.message(encoding),
+    };
+
+    Some(message)
 }
@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Packet type, and car of the packets about a single car, which are limited separately so that
+/// the history of every car still gets through
+pub(crate) type Slot = (u8, Option<u8>);
+
+/// Caps the rate of the packets sent to a client, keeping only the latest packet of each slot
+/// while it waits for its next turn.
+#[derive(Debug)]
+pub(crate) struct RateLimiter<T> {
+    last_sent: HashMap<Slot, Instant>,
+    /// Latest packet of each slot, with the time it can be sent
+    pending: HashMap<Slot, (Instant, T)>,
+    /// Packets replaced by a newer one before being sent
+    coalesced: u64,
+}
+
+impl<T> Default for RateLimiter<T> {
+    fn default() -> Self {
+        Self {
+            last_sent: HashMap::new(),
+            pending: HashMap::new(),
+            coalesced: 0,
+        }
+    }
+}
+
+impl<T> RateLimiter<T> {
+    /// Return the packet if it can be sent right away, or keep it until its slot is due.
+    pub(crate) fn offer(
+        &mut self,
+        slot: Slot,
+        interval: Duration,
+        item: T,
+        now: Instant,
+    ) -> Option<T> {
+        match self.last_sent.get(&slot) {
+            Some(last) if now < *last + interval => {
+                self.keep(slot, interval, item, now);
+                None
+            }
+            _ => {
+                self.last_sent.insert(slot, now);
+                if self.pending.remove(&slot).is_some() {
+                    self.coalesced += 1;
+                }
+                Some(item)
+            }
+        }
+    }
+
+    /// Keep the packet until its slot is due, even if it could be sent right away, while the
+    /// client is busy.
+    pub(crate) fn keep(&mut self, slot: Slot, interval: Duration, item: T, now: Instant) {
+        let due = self
+            .last_sent
+            .get(&slot)
+            .map_or(now, |last| (*last + interval).max(now));
+
+        if self.pending.insert(slot, (due, item)).is_some() {
+            self.coalesced += 1;
+        }
+    }
+
+    /// When the next kept packet can be sent
+    pub(crate) fn next_due(&self) -> Option<Instant> {
+        self.pending.values().map(|(due, _)| *due).min()
+    }
+
+    /// Kept packets that can be sent now, oldest deadline first
+    pub(crate) fn take_due(&mut self, now: Instant) -> Vec<T> {
+        let mut due: Vec<Slot> = self
+            .pending
+            .iter()
+            .filter(|(_, (due, _))| *due <= now)
+            .map(|(slot, _)| *slot)
+            .collect();
+        due.sort_by_key(|slot| self.pending[slot].0);
+
+        due.into_iter()
+            .filter_map(|slot| {
+                self.last_sent.insert(slot, now);
+                self.pending.remove(&slot).map(|(_, item)| item)
+            })
+            .collect()
+    }
+
+    pub(crate) fn coalesced(&self) -> u64 {
+        self.coalesced
+    }
+}
+
+#[cfg(test)]
+mod test_rate_limit {
+    use std::time::{Duration, Instant};
+
+    use super::RateLimiter;
+
+    const INTERVAL: Duration = Duration::from_millis(200);
+
+    #[test]
+    fn test_latest_value_wins() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut limiter = RateLimiter::default();
+
+        assert_eq!(limiter.offer((2, None), INTERVAL, 1, at(0)), Some(1));
+        assert_eq!(limiter.offer((2, None), INTERVAL, 2, at(50)), None);
+        assert_eq!(limiter.offer((2, None), INTERVAL, 3, at(100)), None);
+        assert_eq!(limiter.next_due(), Some(at(200)));
+
+        assert!(limiter.take_due(at(150)).is_empty());
+        assert_eq!(limiter.take_due(at(200)), vec![3]);
+        assert_eq!(limiter.next_due(), None);
+        assert_eq!(limiter.coalesced(), 1);
+
+        // The slot starts over from the last packet sent
+        assert_eq!(limiter.offer((2, None), INTERVAL, 4, at(300)), None);
+        assert_eq!(limiter.offer((2, None), INTERVAL, 5, at(400)), Some(5));
+        assert_eq!(limiter.coalesced(), 2);
+    }
+
+    #[test]
+    fn test_slots_are_independent() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::default();
+
+        assert_eq!(
+            limiter.offer((11, Some(0)), INTERVAL, 'a', start),
+            Some('a')
+        );
+        assert_eq!(
+            limiter.offer((11, Some(1)), INTERVAL, 'b', start),
+            Some('b')
+        );
+        assert_eq!(limiter.offer((2, None), INTERVAL, 'c', start), Some('c'));
+        assert_eq!(limiter.offer((11, Some(0)), INTERVAL, 'd', start), None);
+    }
+
+    #[test]
+    fn test_keep_while_busy() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut limiter = RateLimiter::default();
+
+        limiter.keep((2, None), INTERVAL, 1, at(0));
+        limiter.keep((2, None), INTERVAL, 2, at(10));
+        assert_eq!(limiter.next_due(), Some(at(10)));
+        assert_eq!(limiter.take_due(at(20)), vec![2]);
+
+        // Still spaced by the interval once sent
+        limiter.keep((2, None), INTERVAL, 3, at(30));
+        assert_eq!(limiter.next_due(), Some(at(220)));
+        assert_eq!(limiter.coalesced(), 1);
+    }
+}
@@ -0,0 +1,322 @@
+//! Control protocol of the WebSocket clients.
+//!
+//! Clients receive every packet until they send JSON control messages:
+//!
+//! - `{"action": "subscribe", "packet_types": ["LapData", "Session"]}` restricts the packets to
+//!   the given types the first time, and adds to them afterwards.
+//! - `{"action": "unsubscribe", "packet_types": ["Motion"]}` stops sending these types.
+//! - `{"action": "cars", "cars": [0, 5]}` only sends the data of these cars, or of every car with
+//!   `null`.
+//! - `{"action": "max_rate", "rates": {"LapData": 5, "Motion": 10}}` caps the rate of these types,
+//!   in Hz, sending the latest packet when their turn comes. `null` or `0` removes the cap.
+//!
+//! Every message is acknowledged with the resulting subscription, or answered with an error.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use f1_telemetry::packet::{Packet, PacketType};
+
+use crate::rate_limit::Slot;
+
+/// Message sent by a client to change its subscription
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub(crate) enum ControlMessage {
+    Subscribe {
+        packet_types: Vec<String>,
+    },
+    Unsubscribe {
+        packet_types: Vec<String>,
+    },
+    Cars {
+        cars: Option<Vec<u8>>,
+    },
+    MaxRate {
+        rates: BTreeMap<String, Option<f64>>,
+    },
+}
+
+/// Answer to a control message
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum Reply {
+    Ack {
+        packet_types: Vec<String>,
+        cars: Option<BTreeSet<u8>>,
+        max_rates: BTreeMap<String, f64>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// Car data of a packet
+enum CarData {
+    /// Field with an entry per car
+    PerCar(&'static str),
+    /// The whole packet is about one car
+    Single(u8),
+    /// Not about specific cars
+    None,
+}
+
+/// Packets and cars a client receives
+#[derive(Debug, Default)]
+pub(crate) struct Subscription {
+    /// IDs of the packet types, every type if not set
+    packet_types: Option<BTreeSet<u8>>,
+    /// Indices of the cars, every car if not set
+    cars: Option<BTreeSet<u8>>,
+    /// Maximum rates in Hz, by packet type ID
+    max_rates: BTreeMap<u8, f64>,
+}
+
+impl Subscription {
+    /// Apply a control message, and describe the result to the client.
+    pub(crate) fn handle(&mut self, message: &str) -> Reply {
+        match serde_json::from_str(message) {
+            Ok(m) => match self.apply(m) {
+                Ok(()) => self.ack(),
+                Err(message) => Reply::Error { message },
+            },
+            Err(e) => Reply::Error {
+                message: format!("Invalid control message: {}", e),
+            },
+        }
+    }
+
+    fn apply(&mut self, message: ControlMessage) -> Result<(), String> {
+        match message {
+            ControlMessage::Subscribe { packet_types } => {
+                let ids = parse_packet_types(&packet_types)?;
+                self.packet_types
+                    .get_or_insert_with(BTreeSet::new)
+                    .extend(ids);
+            }
+            ControlMessage::Unsubscribe { packet_types } => {
+                let ids = parse_packet_types(&packet_types)?;
+                self.packet_types
+                    .get_or_insert_with(|| PacketType::ALL.into_iter().map(u8::from).collect())
+                    .retain(|id| !ids.contains(id));
+            }
+            ControlMessage::Cars { cars } => self.cars = cars.map(|c| c.into_iter().collect()),
+            ControlMessage::MaxRate { rates } => {
+                let mut max_rates = self.max_rates.clone();
+                for (name, rate) in rates {
+                    let id = parse_packet_type(&name)?;
+                    if id == u8::from(PacketType::Event) {
+                        return Err("Event packets can't be rate limited".to_string());
+                    }
+
+                    match rate {
+                        Some(rate) if rate.is_finite() && rate > 0.0 => {
+                            max_rates.insert(id, rate);
+                        }
+                        None | Some(0.0) => {
+                            max_rates.remove(&id);
+                        }
+                        Some(rate) => return Err(format!("Invalid rate for {}: {}", name, rate)),
+                    }
+                }
+                self.max_rates = max_rates;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn ack(&self) -> Reply {
+        Reply::Ack {
+            packet_types: PacketType::ALL
+                .into_iter()
+                .filter(|t| self.wants_type(*t))
+                .map(|t| format!("{:?}", t))
+                .collect(),
+            cars: self.cars.clone(),
+            max_rates: PacketType::ALL
+                .into_iter()
+                .filter_map(|t| {
+                    let rate = self.max_rates.get(&u8::from(t))?;
+                    Some((format!("{:?}", t), *rate))
+                })
+                .collect(),
+        }
+    }
+
+    fn wants_type(&self, packet_type: PacketType) -> bool {
+        self.packet_types
+            .as_ref()
+            .is_none_or(|ids| ids.contains(&u8::from(packet_type)))
+    }
+
+    /// Whether the client receives a packet at all
+    pub(crate) fn wants(&self, packet: &Packet) -> bool {
+        if !self.wants_type(packet.header().packet_type) {
+            return false;
+        }
+
+        match (car_data(packet), &self.cars) {
+            (CarData::Single(car), Some(cars)) => cars.contains(&car),
+            _ => true,
+        }
+    }
+
+    /// Slot and minimum interval of a packet, if its type is rate limited
+    pub(crate) fn rate_limit(&self, packet: &Packet) -> Option<(Slot, Duration)> {
+        let id = u8::from(packet.header().packet_type);
+        let rate = self.max_rates.get(&id)?;
+        let car = match car_data(packet) {
+            CarData::Single(car) => Some(car),
+            _ => None,
+        };
+
+        Some(((id, car), Duration::from_secs_f64(1.0 / rate)))
+    }
+
+    /// Whether the packet has to be serialized for this client, rather than shared as is
+    pub(crate) fn filters_cars(&self, packet: &Packet) -> bool {
+        self.cars.is_some() && matches!(car_data(packet), CarData::PerCar(_))
+    }
+
+    /// Replace the data of the cars the client didn't select by `null`, keeping the indices of
+    /// the other cars.
+    pub(crate) fn filter_cars(&self, packet: &Packet, mut value: Value) -> Value {
+        let (CarData::PerCar(field), Some(cars)) = (car_data(packet), &self.cars) else {
+            return value;
+        };
+
+        if let Some(Value::Array(entries)) = value.get_mut(field) {
+            for (idx, entry) in entries.iter_mut().enumerate() {
+                if !cars.contains(&(idx as u8)) {
+                    *entry = Value::Null;
+                }
+            }
+        }
+
+        value
+    }
+}
+
+fn car_data(packet: &Packet) -> CarData {
+    match packet {
+        Packet::Motion(_) => CarData::PerCar("motion_data"),
+        Packet::LapData(_) => CarData::PerCar("lap_data"),
+        Packet::Participants(_) => CarData::PerCar("participants"),
+        Packet::CarSetups(_) => CarData::PerCar("car_setups"),
+        Packet::CarTelemetry(_) => CarData::PerCar("car_telemetry_data"),
+        Packet::CarStatus(_) => CarData::PerCar("car_status_data"),
+        Packet::FinalClassification(_) => CarData::PerCar("final_classifications"),
+        Packet::LobbyInfo(_) => CarData::PerCar("players"),
+        Packet::CarDamage(_) => CarData::PerCar("car_damage_data"),
+        Packet::SessionHistory(p) => CarData::Single(p.car_index),
+        Packet::TyreSets(p) => CarData::Single(p.car_index),
+        Packet::MotionEx(p) => CarData::Single(p.header.player_car_index),
+        Packet::Session(_) | Packet::Event(_) => CarData::None,
+    }
+}
+
+/// ID of a packet type, by the name used in the `packet_type` field of the packets
+fn parse_packet_type(name: &str) -> Result<u8, String> {
+    PacketType::ALL
+        .into_iter()
+        .find(|t| format!("{:?}", t) == name)
+        .map(u8::from)
+        .ok_or_else(|| format!("Unknown packet type: {}", name))
+}
+
+fn parse_packet_types(names: &[String]) -> Result<BTreeSet<u8>, String> {
+    names.iter().map(|name| parse_packet_type(name)).collect()
+}
+
+#[cfg(test)]
+mod test_subscription {
+    use serde_json::json;
+
+    use super::{Reply, Subscription};
+
+    fn packet_types(reply: Reply) -> Vec<String> {
+        match reply {
+            Reply::Ack { packet_types, .. } => packet_types,
+            Reply::Error { message } => panic!("{}", message),
+        }
+    }
+
+    #[test]
+    fn test_subscribe_and_unsubscribe() {
+        let mut subscription = Subscription::default();
+
+        let reply = subscription
+            .handle(r#"{"action": "subscribe", "packet_types": ["LapData", "Session"]}"#);
+        assert_eq!(packet_types(reply), vec!["Session", "LapData"]);
+
+        let reply =
+            subscription.handle(r#"{"action": "unsubscribe", "packet_types": ["Session"]}"#);
+        assert_eq!(packet_types(reply), vec!["LapData"]);
+    }
+
+    #[test]
+    fn test_unsubscribe_from_everything() {
+        let mut subscription = Subscription::default();
+
+        let reply = subscription.handle(r#"{"action": "unsubscribe", "packet_types": ["Motion"]}"#);
+        let types = packet_types(reply);
+        assert_eq!(types.len(), 13);
+        assert!(!types.contains(&"Motion".to_string()));
+    }
+
+    #[test]
+    fn test_invalid_messages() {
+        let mut subscription = Subscription::default();
+
+        for message in [
+            "not json",
+            r#"{"action": "subscribe", "packet_types": ["Engine"]}"#,
+            r#"{"action": "dance"}"#,
+        ] {
+            assert!(matches!(subscription.handle(message), Reply::Error { .. }));
+        }
+
+        // Failed messages don't change the subscription
+        assert_eq!(packet_types(subscription.ack()).len(), 14);
+    }
+
+    #[test]
+    fn test_reply_format() {
+        let mut subscription = Subscription::default();
+        let reply = subscription.handle(r#"{"action": "cars", "cars": [3, 1]}"#);
+
+        let value = serde_json::to_value(reply).unwrap();
+        assert_eq!(value["type"], json!("ack"));
+        assert_eq!(value["cars"], json!([1, 3]));
+        assert_eq!(value["max_rates"], json!({}));
+    }
+
+    #[test]
+    fn test_max_rate() {
+        let mut subscription = Subscription::default();
+
+        let reply = subscription
+            .handle(r#"{"action": "max_rate", "rates": {"LapData": 5, "Motion": 10.5}}"#);
+        let value = serde_json::to_value(reply).unwrap();
+        assert_eq!(value["max_rates"], json!({"LapData": 5.0, "Motion": 10.5}));
+
+        let reply = subscription
+            .handle(r#"{"action": "max_rate", "rates": {"LapData": null, "Motion": 0}}"#);
+        let value = serde_json::to_value(reply).unwrap();
+        assert_eq!(value["max_rates"], json!({}));
+
+        for message in [
+            r#"{"action": "max_rate", "rates": {"Event": 1}}"#,
+            r#"{"action": "max_rate", "rates": {"Motion": 10, "LapData": -1}}"#,
+        ] {
+            assert!(matches!(subscription.handle(message), Reply::Error { .. }));
+        }
+
+        // Failed messages don't change the rates
+        assert!(subscription.max_rates.is_empty());
+    }
+}